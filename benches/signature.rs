@@ -0,0 +1,77 @@
+// signature.rs
+//
+// 比较Schnorr签名逐个验证与批量验证的耗时
+
+use blockchain::{test_utils, Amount, BatchVerifier, Blockchain, SignatureScheme, Transaction, TxOutput, Wallet};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const SIZES: [usize; 3] = [16, 64, 256];
+
+fn bench_signatures(c: &mut Criterion) {
+    let mut group = c.benchmark_group("schnorr_signatures");
+    for n in SIZES {
+        let signed: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)> = (0..n)
+            .map(|i| {
+                let (secret_key, pub_key) = SignatureScheme::Schnorr.new_key_pair();
+                let message = i.to_le_bytes().to_vec();
+                let signature = SignatureScheme::Schnorr.sign(secret_key.as_slice(), message.as_slice());
+                (pub_key, signature, message)
+            })
+            .collect();
+        group.bench_with_input(BenchmarkId::new("per_signature", n), &signed, |b, signed| {
+            b.iter(|| signed.iter().all(|(pub_key, signature, message)| SignatureScheme::verify(pub_key, signature, message)))
+        });
+        group.bench_with_input(BenchmarkId::new("batch", n), &signed, |b, signed| {
+            b.iter(|| {
+                let mut batch = BatchVerifier::new();
+                for (pub_key, signature, message) in signed {
+                    batch.add(pub_key, signature, message);
+                }
+                batch.verify()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// 新建一条链, 返回链和一笔花费n个Schnorr P2PKH输出的交易
+fn consolidation(n: usize) -> (Blockchain, Transaction) {
+    let wallet = Wallet::new_with_scheme(SignatureScheme::Schnorr);
+    let address = wallet.get_address();
+    let blockchain = test_utils::new_blockchain(address.as_str());
+    let coinbase = Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO);
+    blockchain.mine_block(std::slice::from_ref(&coinbase));
+    test_utils::mine_to_maturity(&blockchain, address.as_str());
+
+    let outputs = (0..n).map(|_| TxOutput::new(Amount::from_sat(1), address.as_str())).collect();
+    let mut fund = Transaction::new_with_outputs(outputs);
+    fund.add_input(coinbase.get_id(), 0);
+    fund.sign(&blockchain, &wallet);
+    blockchain.mine_block(std::slice::from_ref(&fund));
+
+    let mut tx = Transaction::new_with_outputs(vec![TxOutput::new(Amount::from_sat(n as u64), address.as_str())]);
+    for outid in 0..n {
+        tx.add_input(fund.get_id(), outid);
+    }
+    tx.sign(&blockchain, &wallet);
+    (blockchain, tx)
+}
+
+fn bench_transaction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("verify_transaction");
+    group.sample_size(10);
+    for n in SIZES {
+        let (blockchain, tx) = consolidation(n);
+        group.bench_function(BenchmarkId::new("per_input", n), |b| b.iter(|| tx.verify(&blockchain)));
+        group.bench_function(BenchmarkId::new("batch", n), |b| {
+            b.iter(|| {
+                let mut batch = BatchVerifier::new();
+                tx.verify_with_batch(&blockchain, &mut batch) && batch.verify()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_signatures, bench_transaction);
+criterion_main!(benches);
@@ -0,0 +1,256 @@
+// addrindex.rs
+//
+
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree, Transactional, UnabortableTransactionError};
+
+use crate::{
+    Amount,
+    Blockchain,
+    UTXOSet,
+    block::Block,
+    transaction::TxOutput,
+    utxo_set::UNDO_TREE
+};
+
+pub(crate) const ADDRINDEX_TREE: &str = "addrindex";
+/// 索引已同步到的区块hash
+const BEST_BLOCK_HASH_KEY: &str = "best_block_hash";
+
+/// 与地址相关的一笔交易
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressTx {
+    txid: Vec<u8>,      // 交易id
+    height: usize,      // 交易所在区块的高度
+    received: Amount,   // 交易支付给该地址的金额
+    sent: Amount,       // 交易花费的该地址的金额
+}
+
+impl AddressTx {
+    pub fn get_txid(&self) -> &[u8] {
+        self.txid.as_slice()
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn get_received(&self) -> Amount {
+        self.received
+    }
+
+    pub fn get_sent(&self) -> Amount {
+        self.sent
+    }
+}
+
+/// 索引中保存的金额
+#[derive(Default, Serialize, Deserialize)]
+struct AddressAmounts {
+    received: Amount,
+    sent: Amount,
+}
+
+/// 地址索引: 地址hash(P2PKH的公钥hash或P2SH的脚本hash) + 高度 + txid -> 收到和花费的金额
+/// 区块链开启地址索引时随主链的连接和断开维护, 花费的输出从utxo set的undo记录中读取
+pub struct AddressIndex {
+    blockchain: Blockchain,
+}
+
+impl AddressIndex {
+
+    /// 新建一个地址索引
+    pub fn new(blockchain: Blockchain) -> Self {
+        AddressIndex{blockchain}
+    }
+
+    fn get_addrindex_tree(&self) -> sled::Tree {
+        self.blockchain.get_db().open_tree(ADDRINDEX_TREE).unwrap()
+    }
+
+    fn get_undo_tree(&self) -> sled::Tree {
+        self.blockchain.get_db().open_tree(UNDO_TREE).unwrap()
+    }
+
+    /// 获取索引已同步到的区块hash
+    fn get_best_block_hash(&self) -> Option<String> {
+        self.get_addrindex_tree()
+            .get(BEST_BLOCK_HASH_KEY)
+            .unwrap()
+            .map(|bytes| String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    /// 索引是否已同步到主链最新区块
+    pub fn is_synced(&self) -> bool {
+        self.get_best_block_hash() == Some(self.blockchain.get_tip_hash())
+    }
+
+    /// 地址hash相关的交易, 按高度从低到高排列
+    pub fn get_history(&self, hash: &[u8]) -> Vec<AddressTx> {
+        self.get_addrindex_tree()
+            .scan_prefix(hash)
+            .map(|item| {
+                let (key, value) = item.unwrap();
+                let height = u64::from_be_bytes(key[hash.len()..hash.len() + 8].try_into().unwrap());
+                let amounts: AddressAmounts = bincode::deserialize(value.as_ref()).expect("unable to deserialize AddressAmounts");
+                AddressTx {
+                    txid: key[hash.len() + 8..].to_vec(),
+                    height: height as usize,
+                    received: amounts.received,
+                    sent: amounts.sent,
+                }
+            })
+            .collect()
+    }
+
+    /// 连接区块: 须在utxo set连接该区块之后调用, 索引不在该区块的前一个区块时不再维护
+    pub fn connect_block(&self, block: &Block) {
+        if !self.blockchain.is_addrindex_enabled() {
+            return;
+        }
+        (&self.get_addrindex_tree(), &self.get_undo_tree())
+            .transaction(|(addrindex_tree, undo_tree)| -> ConflictableTransactionResult<()> {
+                Ok(Self::connect(addrindex_tree, undo_tree, block)?)
+            })
+            .expect("unable to connect the block to the address index");
+    }
+
+    /// 断开区块: 须在utxo set断开该区块之前调用, 索引不在该区块时不再维护
+    pub fn disconnect_block(&self, block: &Block) {
+        if !self.blockchain.is_addrindex_enabled() {
+            return;
+        }
+        (&self.get_addrindex_tree(), &self.get_undo_tree())
+            .transaction(|(addrindex_tree, undo_tree)| -> ConflictableTransactionResult<()> {
+                Ok(Self::disconnect(addrindex_tree, undo_tree, block)?)
+            })
+            .expect("unable to disconnect the block from the address index");
+    }
+
+    /// 在事务中连接区块, 花费的输出从undo_tree中读取
+    pub(crate) fn connect(addrindex_tree: &TransactionalTree, undo_tree: &TransactionalTree, block: &Block) -> Result<(), UnabortableTransactionError> {
+        let synced = match addrindex_tree.get(BEST_BLOCK_HASH_KEY)? {
+            Some(best) => best == block.get_pre_block_hash().as_bytes(),
+            None => block.get_height() == 0,
+        };
+        if !synced {
+            return Ok(());
+        }
+        for (key, amounts) in Self::block_entries(block, Self::read_spent_outputs(undo_tree, block)?) {
+            addrindex_tree.insert(key, bincode::serialize(&amounts).unwrap())?;
+        }
+        addrindex_tree.insert(BEST_BLOCK_HASH_KEY, block.get_hash())?;
+        Ok(())
+    }
+
+    /// 在事务中断开区块, 花费的输出从undo_tree中读取
+    pub(crate) fn disconnect(addrindex_tree: &TransactionalTree, undo_tree: &TransactionalTree, block: &Block) -> Result<(), UnabortableTransactionError> {
+        if addrindex_tree.get(BEST_BLOCK_HASH_KEY)?.as_deref() != Some(block.get_hash().as_bytes()) {
+            return Ok(());
+        }
+        for (key, _) in Self::block_entries(block, Self::read_spent_outputs(undo_tree, block)?) {
+            addrindex_tree.remove(key)?;
+        }
+        addrindex_tree.insert(BEST_BLOCK_HASH_KEY, block.get_pre_block_hash().as_str())?;
+        Ok(())
+    }
+
+    fn read_spent_outputs(undo_tree: &TransactionalTree, block: &Block) -> Result<Vec<Vec<TxOutput>>, UnabortableTransactionError> {
+        let undo_bytes = undo_tree.get(block.get_hash())?
+            .expect("the undo data of block is not found");
+        Ok(UTXOSet::decode_spent_outputs(undo_bytes.as_ref()))
+    }
+
+    /// 重建索引: 从创世区块开始依次索引主链上的区块.
+    /// 先删除索引已同步到的区块hash, 重建中断时索引与tip不一致, 下次启动重新重建
+    pub fn reindex(&self) -> sled::Result<()> {
+        let addrindex_tree = self.get_addrindex_tree();
+        addrindex_tree.remove(BEST_BLOCK_HASH_KEY)?;
+        addrindex_tree.clear()?;
+        let height = self.blockchain.get_best_height();
+        for block in self.blockchain.forward_iterator(0, height + 1) {
+            self.index_block(&block)?;
+        }
+        Ok(())
+    }
+
+    fn index_block(&self, block: &Block) -> sled::Result<()> {
+        let addrindex_tree = self.get_addrindex_tree();
+        let spent = UTXOSet::new(self.blockchain.clone())
+            .get_spent_outputs(block)
+            .expect("the undo data of block is not found");
+        for (key, amounts) in Self::block_entries(block, spent) {
+            addrindex_tree.insert(key, bincode::serialize(&amounts).unwrap())?;
+        }
+        addrindex_tree.insert(BEST_BLOCK_HASH_KEY, block.get_hash())?;
+        Ok(())
+    }
+
+    /// 计算区块中各交易对每个地址hash收到和花费的金额, spent为各交易花费的输出
+    fn block_entries(block: &Block, spent: Vec<Vec<TxOutput>>) -> BTreeMap<Vec<u8>, AddressAmounts> {
+        let mut entries: BTreeMap<Vec<u8>, AddressAmounts> = BTreeMap::new();
+        for (tx, spent_outs) in block.get_transactions().iter().zip(spent) {
+            let received = tx.get_vout().iter().map(|out| (out, true));
+            let sent = spent_outs.iter().map(|out| (out, false));
+            for (out, is_received) in received.chain(sent) {
+                let hash = match out.get_script_pubkey().get_address_hash() {
+                    Some(hash) => hash,
+                    None => continue,
+                };
+                let key = [hash, &(block.get_height() as u64).to_be_bytes(), tx.get_id()].concat();
+                let amounts = entries.entry(key).or_default();
+                let amount = if is_received { &mut amounts.received } else { &mut amounts.sent };
+                *amount = amount.checked_add(out.get_cost()).expect("amount overflow");
+            }
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        GLOBAL_CONFIG,
+        Script,
+        Transaction,
+        test_utils::{mine_blocks, temporary_db},
+        wallet::Wallet
+    };
+
+    #[test]
+    fn test_address_history() {
+        let (wallet, to) = (Wallet::new(), Wallet::new().get_address());
+        let address = wallet.get_address();
+        let blockchain = Blockchain::create_blockchain_with_indexes(temporary_db(), address.as_str(), false, true);
+        let addrindex = AddressIndex::new(blockchain.clone());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let maturity = GLOBAL_CONFIG.get_coinbase_maturity();
+        mine_blocks(&blockchain, Wallet::new().get_address().as_str(), maturity);
+        let tx = Transaction::new_utxo_transaction_from_wallet(&wallet, to.as_str(), Amount::from_sat(3), Amount::ZERO, &utxo_set);
+        blockchain.mine_block(std::slice::from_ref(&tx));
+        assert!(addrindex.is_synced());
+
+        let hash = |address: &str| Script::from_address(address).unwrap().get_address_hash().unwrap().to_vec();
+        let history = addrindex.get_history(hash(address.as_str()).as_slice());
+        let subsidy = crate::transaction::get_block_subsidy(0);
+        let change = subsidy.checked_sub(Amount::from_sat(3)).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].get_height(), history[0].get_received(), history[0].get_sent()), (0, subsidy, Amount::ZERO));
+        assert_eq!((history[1].get_height(), history[1].get_received(), history[1].get_sent()), (maturity + 1, change, subsidy));
+        assert_eq!(history[1].get_txid(), tx.get_id());
+        let history = addrindex.get_history(hash(to.as_str()).as_slice());
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].get_received(), Amount::from_sat(3));
+
+        // 断开区块后删除其中交易的记录, 重建后与维护的结果一致
+        let tip = blockchain.get_block(blockchain.get_tip_hash().as_bytes()).unwrap();
+        addrindex.disconnect_block(&tip);
+        assert_eq!(addrindex.get_history(hash(to.as_str()).as_slice()), vec![]);
+        addrindex.reindex().unwrap();
+        assert!(addrindex.is_synced());
+        assert_eq!(addrindex.get_history(hash(to.as_str()).as_slice()).len(), 1);
+        assert_eq!(addrindex.get_history(hash(address.as_str()).as_slice()).len(), 2);
+    }
+}
@@ -0,0 +1,160 @@
+// amount.rs
+//
+
+use std::{
+    error::Error,
+    fmt,
+    str::FromStr
+};
+use serde::{Deserialize, Serialize};
+
+/// 1个币对应的最小单位数
+pub const COIN: u64 = 100_000_000;
+/// 小数部分的位数
+const DECIMALS: usize = 8;
+/// 币的总量上限
+pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+
+/// 金额, 以最小单位(1/COIN个币)计数, 所有运算都检查溢出
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    /// 由最小单位数新建金额
+    pub const fn from_sat(sat: u64) -> Self {
+        Amount(sat)
+    }
+
+    /// 由整币数新建金额, 溢出时返回None
+    pub fn from_coins(coins: u64) -> Option<Self> {
+        coins.checked_mul(COIN).map(Amount)
+    }
+
+    /// 获取最小单位数
+    pub fn as_sat(&self) -> u64 {
+        self.0
+    }
+
+    /// 金额是否不超过币的总量上限
+    pub fn is_valid(&self) -> bool {
+        *self <= MAX_MONEY
+    }
+
+    /// 加法, 溢出时返回None
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    /// 减法, 结果为负时返回None
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// 右移, 用于挖矿奖励减半
+    pub fn checked_shr(self, bits: u32) -> Option<Amount> {
+        self.0.checked_shr(bits).map(Amount)
+    }
+
+    /// 求和, 溢出时返回None
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, Amount::checked_add)
+    }
+}
+
+/// 以币为单位显示, 省略小数部分末尾的0, 如 `1.5`
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let coins = self.0 / COIN;
+        let fraction = self.0 % COIN;
+        if fraction == 0 {
+            return write!(f, "{}", coins);
+        }
+        let fraction = format!("{:0width$}", fraction, width = DECIMALS);
+        write!(f, "{}.{}", coins, fraction.trim_end_matches('0'))
+    }
+}
+
+/// 解析金额失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseAmountError {
+    /// 不是合法的非负十进制数
+    InvalidFormat,
+    /// 小数位数超过8位
+    TooPrecise,
+    /// 超过币的总量上限
+    TooLarge,
+}
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseAmountError::InvalidFormat => write!(f, "invalid amount"),
+            ParseAmountError::TooPrecise => write!(f, "amount has more than {} decimals", DECIMALS),
+            ParseAmountError::TooLarge => write!(f, "amount exceeds {}", MAX_MONEY),
+        }
+    }
+}
+
+impl Error for ParseAmountError {}
+
+/// 以币为单位解析, 如 `10`, `0.001`
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (coins, fraction) = s.split_once('.').unwrap_or((s, ""));
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if coins.is_empty() || !is_digits(coins) || !is_digits(fraction) || s.ends_with('.') {
+            return Err(ParseAmountError::InvalidFormat);
+        }
+        if fraction.len() > DECIMALS {
+            return Err(ParseAmountError::TooPrecise);
+        }
+
+        let coins = coins.parse().map_err(|_| ParseAmountError::TooLarge)?;
+        let fraction = format!("{:0<width$}", fraction, width = DECIMALS)
+            .parse()
+            .map_err(|_| ParseAmountError::InvalidFormat)?;
+        let amount = Amount::from_coins(coins)
+            .and_then(|amount| amount.checked_add(Amount(fraction)))
+            .filter(Amount::is_valid)
+            .ok_or(ParseAmountError::TooLarge)?;
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_display_and_parse() {
+        assert_eq!(Amount::from_sat(10 * COIN).to_string(), "10");
+        assert_eq!(Amount::from_sat(COIN + COIN / 2).to_string(), "1.5");
+        assert_eq!(Amount::from_sat(1).to_string(), "0.00000001");
+
+        assert_eq!("10".parse(), Ok(Amount::from_sat(10 * COIN)));
+        assert_eq!("1.5".parse(), Ok(Amount::from_sat(COIN + COIN / 2)));
+        assert_eq!("0.00000001".parse(), Ok(Amount::from_sat(1)));
+        assert_eq!("21000000".parse(), Ok(MAX_MONEY));
+        assert_eq!("-1".parse::<Amount>(), Err(ParseAmountError::InvalidFormat));
+        assert_eq!("1.".parse::<Amount>(), Err(ParseAmountError::InvalidFormat));
+        assert_eq!("0.000000001".parse::<Amount>(), Err(ParseAmountError::TooPrecise));
+        assert_eq!("21000000.00000001".parse::<Amount>(), Err(ParseAmountError::TooLarge));
+    }
+
+    #[test]
+    fn test_amount_checked_arithmetic() {
+        let max = Amount::from_sat(u64::MAX);
+        assert_eq!(max.checked_add(Amount::from_sat(1)), None);
+        assert_eq!(Amount::ZERO.checked_sub(Amount::from_sat(1)), None);
+        assert_eq!(Amount::checked_sum([max, Amount::from_sat(1)]), None);
+        assert_eq!(
+            Amount::checked_sum([Amount::from_sat(1), Amount::from_sat(2)]),
+            Some(Amount::from_sat(3))
+        );
+        assert!(!max.is_valid());
+    }
+}
@@ -1,55 +1,167 @@
 // block.rs
 //
-use crate::{ProofOfWork, Transaction};
+use crate::{
+    ProofOfWork,
+    Transaction,
+    merkle::{MerkleProof, MerkleTree}
+};
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 
+/// 区块版本
+const BLOCK_VERSION: u32 = 1;
+
+/// 区块序列化后的最大字节数
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// 区块头, 区块hash即区块头的hash
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    version: u32,           // 区块版本
+    pre_block_hash: String, // 上一个区块hash
+    merkle_root: Vec<u8>,   // 本区块所有tx的Merkle根
+    witness_root: Vec<u8>,  // 本区块所有tx的wtxid的Merkle根, 承诺交易的解锁脚本
+    timestamp: u64,         // 生成区块时间戳
+    bits: u32,              // 紧凑格式的pow目标值
+    nonce: i64,             // 随机数, pow挖矿时用于产生微扰
+}
+
+impl BlockHeader {
+    /// 获取区块版本
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    /// 获取前一个区块的hash
+    pub fn get_pre_block_hash(&self) -> String {
+        self.pre_block_hash.clone()
+    }
+
+    /// 获取Merkle根
+    pub fn get_merkle_root(&self) -> &[u8] {
+        self.merkle_root.as_slice()
+    }
+
+    /// 获取见证Merkle根
+    pub fn get_witness_root(&self) -> &[u8] {
+        self.witness_root.as_slice()
+    }
+
+    /// 获取时间戳
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// 获取pow目标值
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// 获取nonce
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
+    }
+
+    /// 计算区块头hash
+    pub fn hash(&self) -> String {
+        ProofOfWork::new(self.clone()).get_hash()
+    }
+
+    /// 反序列化, 字节数组 -> BlockHeader, 字节数组不合法时返回None
+    pub fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+
+    /// 序列化, BlockHeader -> 字节数组
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap().to_vec()
+    }
+}
+
 /// 区块
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Block {
-    timestamp: u64,         // 生成区块时间戳
-    nonce: i64,             // 随机数, pow挖矿时用于产生微扰
+    header: BlockHeader,    // 区块头
     height: usize,          // 区块高度(该区块相对于创世区块的个数)
-    hash: String,           // 区块hash, hash由: 上一个区块hash+hash(本区块所有tx)+timestamp+nonce
-    pre_block_hash: String, // 上一个区块hash
+    hash: String,           // 区块hash, 即区块头的hash
     transactions: Vec<Transaction>, //交易
 }
 
 impl Block {
     /// 新建一个区块
-    pub fn new(pre_block_hash: String, transactions: &[Transaction], height: usize) -> Self {
+    pub fn new(pre_block_hash: String, transactions: &[Transaction], height: usize, bits: u32) -> Self {
+        Self::new_with_timestamp(pre_block_hash, transactions, height, bits, crate::current_timestamp())
+    }
+
+    /// 以指定时间戳新建一个区块, 相同的输入总是生成相同的区块
+    pub fn new_with_timestamp(pre_block_hash: String, transactions: &[Transaction], height: usize, bits: u32, timestamp: u64) -> Self {
         let mut block = Block {
-            timestamp: crate::current_timestamp(),
-            nonce: 0,
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                pre_block_hash,
+                merkle_root: vec![],
+                witness_root: vec![],
+                timestamp,
+                bits,
+                nonce: 0,
+            },
             height,
-            pre_block_hash,
             hash: String::new(),
             transactions: transactions.to_vec(),
         };
+        block.header.merkle_root = block.merkle_tree().root();
+        block.header.witness_root = block.witness_tree().root();
 
-        let pow = ProofOfWork::new(block.clone());
+        let pow = ProofOfWork::new(block.header.clone());
         let (nonce, hash) = pow.run();
-        block.nonce = nonce;
+        block.header.nonce = nonce;
         block.hash = hash;
 
-        return block;
+        block
     }
 
     /// 生成创世区块
     /// 创世区块的前一个区块为none, 高度为0
     pub fn generate_genesis_block(transaction: &Transaction) -> Self {
         let transactions = vec![transaction.clone()];
-        return Block::new(String::from("None"), &transactions, 0);
+        Block::new(String::from("None"), &transactions, 0, ProofOfWork::initial_bits())
+    }
+
+    /// 由区块所有交易id构建Merkle树
+    fn merkle_tree(&self) -> MerkleTree {
+        let txids: Vec<Vec<u8>> = self.transactions.iter()
+            .map(|tx| tx.get_id_bytes())
+            .collect();
+        MerkleTree::new(txids.as_slice())
+    }
+
+    /// 由区块所有交易的wtxid构建Merkle树
+    fn witness_tree(&self) -> MerkleTree {
+        let wtxids: Vec<Vec<u8>> = self.transactions.iter()
+            .map(|tx| tx.get_wtxid())
+            .collect();
+        MerkleTree::new(wtxids.as_slice())
+    }
+
+    /// 区块头中的Merkle根是否与区块中的交易一致
+    pub fn check_merkle_root(&self) -> bool {
+        self.merkle_tree().root().eq(&self.header.merkle_root)
+    }
+
+    /// 区块头中的见证Merkle根是否与区块中交易的解锁脚本一致
+    pub fn check_witness_root(&self) -> bool {
+        self.witness_tree().root().eq(&self.header.witness_root)
     }
 
-    /// 计算区块所有交易hash
-    pub fn hash_transactions(&self) -> Vec<u8> {
-        let mut tx_hashes = vec![];
-        for transaction in &self.transactions {
-            tx_hashes.extend(transaction.get_id());
-        }
+    /// 生成交易包含在该区块中的Merkle路径
+    pub fn get_merkle_proof(&self, txid: &[u8]) -> Option<MerkleProof> {
+        let index = self.transactions.iter().position(|tx| tx.get_id().eq(txid))?;
+        self.merkle_tree().proof(index)
+    }
 
-        crate::sha256_digest(tx_hashes.as_slice())
+    /// 获取区块头
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
     }
 
     /// 获取区块中的交易
@@ -57,9 +169,9 @@ impl Block {
         self.transactions.as_slice()
     }
 
-    /// 反序列化, 字节数组 -> Block
-    pub fn deserialize(bytes: &[u8]) -> Self {
-        bincode::deserialize(bytes).unwrap()
+    /// 反序列化, 字节数组 -> Block, 字节数组不合法时返回None
+    pub fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
     }
 
     /// 序列化, Block -> 字节数组
@@ -67,6 +179,11 @@ impl Block {
         bincode::serialize(self).unwrap().to_vec()
     }
 
+    /// 区块序列化后的字节数
+    pub fn get_size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
     /// 获取block高度
     /// block的高度为当前 block到初始区块间区块的个数
     pub fn get_height(&self) -> usize {
@@ -75,7 +192,7 @@ impl Block {
 
     /// 获取前一个区块的hash
     pub fn get_pre_block_hash(&self) -> String {
-        self.pre_block_hash.clone()
+        self.header.get_pre_block_hash()
     }
 
     /// 获取当前区块hash
@@ -88,9 +205,14 @@ impl Block {
         self.hash.as_bytes().to_vec()
     }
 
+    /// 获取当前区块的pow目标值
+    pub fn get_bits(&self) -> u32 {
+        self.header.get_bits()
+    }
+
     /// 获取当前区块时间戳
     pub fn get_timestamp(&self) -> u64 {
-        self.timestamp
+        self.header.get_timestamp()
     }
 }
 
@@ -100,3 +222,46 @@ impl From<Block> for IVec {
         Self::from(bytes)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Amount;
+
+    #[test]
+    fn test_deterministic_block() {
+        let address = crate::wallet::Wallet::new().get_address();
+        let bits = ProofOfWork::initial_bits();
+        let new_block = |height| {
+            let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), height, Amount::ZERO);
+            Block::new_with_timestamp(String::from("None"), &[coinbase_tx], height, bits, 1_000)
+        };
+        assert_eq!(new_block(1).get_hash(), new_block(1).get_hash());
+        assert_ne!(new_block(1).get_hash(), new_block(2).get_hash());
+
+        let header = new_block(1).get_header().clone();
+        assert_eq!(BlockHeader::deserialize(header.serialize().as_slice()).map(|header| header.hash()), Some(header.hash()));
+        assert!(BlockHeader::deserialize(&[1, 2, 3]).is_none());
+
+        let block = new_block(1);
+        assert_eq!(Block::deserialize(block.serialize().as_slice()).map(|block| block.get_hash().to_string()), Some(block.get_hash().to_string()));
+        assert!(Block::deserialize(&[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn test_witness_root() {
+        let address = crate::wallet::Wallet::new().get_address();
+        let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO);
+        let mut tx = Transaction::new_with_outputs(vec![]);
+        tx.add_input(coinbase_tx.get_id(), 0);
+        let mut block = Block::new(String::from("None"), &[coinbase_tx, tx.clone()], 1, ProofOfWork::initial_bits());
+        assert!(block.check_merkle_root() && block.check_witness_root());
+
+        // 修改解锁脚本不改变txid, 但改变wtxid
+        tx.set_script_sig(0, crate::Script::new(vec![crate::Op::PushData(b"signature".to_vec())]));
+        assert_eq!(tx.get_id(), block.transactions[1].get_id());
+        block.transactions[1] = tx;
+        assert!(block.check_merkle_root());
+        assert!(!block.check_witness_root());
+    }
+}
@@ -1,135 +1,793 @@
 // blockchain.rs
 //
 
-use data_encoding::HEXLOWER;
 use sled::{
     Db,
     Tree,
-    transaction::TransactionResult
+    transaction::{ConflictableTransactionResult, TransactionalTree, Transactional, TransactionError}
 };
 use std::{
-    collections::HashMap,
+    collections::HashSet,
     env::current_dir,
+    error::Error,
+    fmt,
+    ops::Range,
     sync::{Arc, RwLock},
 };
 use crate::{
+    Amount,
+    GLOBAL_CONFIG,
+    ProofOfWork,
     Transaction,
-    block::Block,
-    transaction::TxOutput
+    current_timestamp,
+    block::{Block, BlockHeader},
+    addrindex::{AddressIndex, ADDRINDEX_TREE},
+    txindex::{self, TxIndex, TXINDEX_TREE},
+    utxo_set::{CoinsViewCache, UTXOSet, UNDO_TREE, UTXO_TREE},
+    validation::{self, ValidationError}
 };
+use log::{error, info};
+use num_bigint::BigUint;
 
 const BLOCKS_TREE: &str = "blocks";
 const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
+const CHAIN_WORK_TREE: &str = "chain_work";
+const HEADERS_TREE: &str = "headers";
+const BEST_HEADER_HASH_KEY: &str = "best_header_hash";
+/// 主链上区块高度 -> 区块hash的索引
+const HEIGHT_INDEX_TREE: &str = "height_index";
+/// 被标记为非法的区块hash, 再次收到时直接拒绝
+const INVALID_TREE: &str = "invalid";
+/// 计算中位时间的区块数
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// 增加区块或区块头失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    /// 区块或区块头校验失败
+    Invalid(ValidationError),
+    /// 读写数据库失败
+    Storage(sled::Error),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Invalid(e) => write!(f, "{}", e),
+            ChainError::Storage(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl Error for ChainError {}
+
+impl From<ValidationError> for ChainError {
+    fn from(e: ValidationError) -> Self {
+        ChainError::Invalid(e)
+    }
+}
+
+impl From<sled::Error> for ChainError {
+    fn from(e: sled::Error) -> Self {
+        ChainError::Storage(e)
+    }
+}
+
+impl From<TransactionError<ValidationError>> for ChainError {
+    fn from(e: TransactionError<ValidationError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => ChainError::Invalid(e),
+            TransactionError::Storage(e) => ChainError::Storage(e),
+        }
+    }
+}
 
 /// 区块链
 #[derive(Clone)]
 pub struct Blockchain {
     tip_hash: Arc<RwLock<String>>,      //最后一个block的hash
     db: Db,                 // 保存blockchain的db
+    txindex: Option<Tree>,  // 维护交易索引时为索引的tree, 查找交易时复用
+    addrindex: bool,        // 是否维护地址索引
 }
 
 impl Blockchain {
 
-    /// 打开Blockchain实例
+    /// 打开Blockchain实例, 按配置的TXINDEX和ADDRINDEX维护索引
     pub fn open_blockchain() -> Self {
         let db = sled::open(current_dir().unwrap().join("data")).unwrap();
         let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
         let tip_bytes = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap().expect("No existing blockchain found");
         let tip_hash = String::from_utf8(tip_bytes.to_vec()).unwrap();
-
-        Blockchain {
+        let txindex = open_txindex(&db, GLOBAL_CONFIG.is_txindex_enabled());
+        let blockchain = Blockchain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
             db,
-        }
+            txindex,
+            addrindex: GLOBAL_CONFIG.is_addrindex_enabled(),
+        };
+        blockchain.check_consistency().expect("unable to repair the blockchain data");
+
+        blockchain
     }
 
     /// 创建一条新的区块链
     pub fn create_blockchain(genesis_address: &str) -> Self {
-        let tip_hash;
         let db = sled::open(current_dir().unwrap().join("data")).unwrap();
+        Self::create_blockchain_with_db(db, genesis_address)
+    }
+
+    /// 在指定的db上创建一条新的区块链, 按配置的TXINDEX和ADDRINDEX维护索引
+    pub fn create_blockchain_with_db(db: Db, genesis_address: &str) -> Self {
+        Self::create_blockchain_with_indexes(
+            db, genesis_address, GLOBAL_CONFIG.is_txindex_enabled(), GLOBAL_CONFIG.is_addrindex_enabled())
+    }
+
+    /// 在指定的db上创建一条新的区块链, 由txindex和addrindex指定是否维护交易索引和地址索引
+    pub fn create_blockchain_with_indexes(db: Db, genesis_address: &str, txindex: bool, addrindex: bool) -> Self {
+        let txindex = open_txindex(&db, txindex);
         let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
-        let data = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap();
-        if data.is_none() {
-            let coinbase_tx = Transaction::new_coinbase_tx(genesis_address);
-            let block = Block::generate_genesis_block(&coinbase_tx);
-            Self::update_blocks_tree(&blocks_tree, &block);
-            tip_hash = String::from(block.get_hash());
-        } else {
-            tip_hash = String::from_utf8(data.unwrap().to_vec()).unwrap();
+        if let Some(data) = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap() {
+            let tip_hash = String::from_utf8(data.to_vec()).unwrap();
+            let blockchain = Blockchain { tip_hash: Arc::new(RwLock::new(tip_hash)), db, txindex, addrindex };
+            blockchain.check_consistency().expect("unable to repair the blockchain data");
+            return blockchain;
         }
 
-        Blockchain { tip_hash: Arc::new(RwLock::new(tip_hash)), db }
-    }
+        let coinbase_tx = Transaction::new_coinbase_tx(genesis_address, 0, Amount::ZERO);
+        let block = Block::generate_genesis_block(&coinbase_tx);
+        let tip_hash = String::from(block.get_hash());
+        let blockchain = Blockchain { tip_hash: Arc::new(RwLock::new(tip_hash)), db, txindex, addrindex };
+        blockchain.save_header(block.get_hash(), block.get_header(), block.get_height()).unwrap();
+        blockchain.connect_block(&block).expect("the genesis block is valid");
 
-    /// 更新最后一个block
-    fn update_blocks_tree(blocks_tree: &Tree, block: &Block) {
-        let block_hash = block.get_hash();
-        let _: TransactionResult<(), ()> = blocks_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block_hash, block.clone());
-            let _ = tx_db.insert(TIP_BLOCK_HASH_KEY, block_hash);
-            Ok(())
-        });
+        blockchain
     }
 
     pub fn get_db(&self) -> &Db {
         &self.db
     }
 
-    ///
+    /// 是否维护交易索引
+    pub fn is_txindex_enabled(&self) -> bool {
+        self.txindex.is_some()
+    }
+
+    /// 是否维护地址索引
+    pub fn is_addrindex_enabled(&self) -> bool {
+        self.addrindex
+    }
+
+    /// 获取最后一个block的hash
     pub fn get_tip_hash(&self) -> String {
         self.tip_hash.read().unwrap().clone()
     }
 
-    ///
+    /// 设置最后一个block的hash
     pub fn set_tip_hash(&self, new_tip_hash: &str) {
         let mut tip_hash = self.tip_hash.write().unwrap();
         *tip_hash = String::from(new_tip_hash);
     }
 
-    ///
+    /// 获取保存区块的tree
     fn get_block_tree(&self) -> Tree {
         self.db.open_tree(BLOCKS_TREE).unwrap()
     }
 
-    ///
+    fn get_chain_work_tree(&self) -> Tree {
+        self.db.open_tree(CHAIN_WORK_TREE).unwrap()
+    }
+
+    fn get_headers_tree(&self) -> Tree {
+        self.db.open_tree(HEADERS_TREE).unwrap()
+    }
+
+    fn get_invalid_tree(&self) -> Tree {
+        self.db.open_tree(INVALID_TREE).unwrap()
+    }
+
+    fn get_height_index_tree(&self) -> Tree {
+        self.db.open_tree(HEIGHT_INDEX_TREE).unwrap()
+    }
+
+    /// 获取主链上高度为height的区块hash
+    pub fn get_block_hash_by_height(&self, height: usize) -> Option<String> {
+        self.get_height_index_tree()
+            .get((height as u64).to_be_bytes())
+            .unwrap()
+            .map(|bytes| String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    /// 获取主链上高度为height的区块
+    pub fn get_block_by_height(&self, height: usize) -> Option<Block> {
+        self.get_block(self.get_block_hash_by_height(height)?.as_bytes())
+    }
+
+    /// 主链上高度从start到end(不含)的区块, 按高度从低到高
+    pub fn forward_iterator(&self, start: usize, end: usize) -> BlockchainForwardIterator {
+        BlockchainForwardIterator { blockchain: self.clone(), heights: start..end }
+    }
+
+    /// 启动时检查高度索引, utxo set和开启的交易索引及地址索引是否与tip一致,
+    /// 修复中断的写入或旧版本留下的数据, 再继续中断的分支切换
+    fn check_consistency(&self) -> Result<(), ChainError> {
+        self.check_height_index()?;
+        self.check_chain_work()?;
+        let utxo_set = UTXOSet::new(self.clone());
+        if !utxo_set.is_synced() {
+            info!("Rebuild the utxo set");
+            utxo_set.reindex()?;
+        }
+        let txindex = TxIndex::new(self.clone());
+        if self.is_txindex_enabled() && !txindex.is_synced() {
+            info!("Rebuild the transaction index");
+            txindex.reindex()?;
+        }
+        let addrindex = AddressIndex::new(self.clone());
+        if self.addrindex && !addrindex.is_synced() {
+            info!("Rebuild the address index");
+            addrindex.reindex()?;
+        }
+        self.check_best_block()
+    }
+
+    /// tip的累计工作量低于已下载内容的最优区块时(如分支切换中断, 或新分支上的区块非法), 切换到该区块.
+    /// 该区块所在分支非法时只记录日志
+    fn check_best_block(&self) -> Result<(), ChainError> {
+        let best_hash = self.get_best_block_hash();
+        let tip_hash = self.get_tip_hash();
+        if best_hash == tip_hash {
+            return Ok(());
+        }
+        info!("Switch to the best block {}", best_hash);
+        let tip = self.get_block(tip_hash.as_bytes()).expect("the tip hash is not valid");
+        let best = self.get_block(best_hash.as_bytes()).expect("the best block is not found");
+        match self.reorganize(&tip, &best) {
+            Err(ChainError::Invalid(e)) => {
+                error!("Unable to switch to block {}: {}", best_hash, e);
+                Ok(())
+            },
+            result => result,
+        }
+    }
+
+    /// 已下载内容的区块中累计工作量最大的区块hash, 工作量相同时为tip.
+    /// 只考虑到主链的前序区块内容都已保存的区块
+    fn get_best_block_hash(&self) -> String {
+        let mut best_hash = self.get_tip_hash();
+        let mut best_work = self.get_best_chain_work();
+        for (hash, work_bytes) in self.get_chain_work_tree().iter().flatten() {
+            let work = BigUint::from_bytes_be(work_bytes.as_ref());
+            if work > best_work && self.is_branch_stored(hash.as_ref()) {
+                best_hash = String::from_utf8(hash.to_vec()).unwrap();
+                best_work = work;
+            }
+        }
+        best_hash
+    }
+
+    /// 该区块及其到主链的前序区块的内容是否都已保存
+    fn is_branch_stored(&self, block_hash: &[u8]) -> bool {
+        let mut current = self.get_block(block_hash);
+        while let Some(block) = current {
+            if self.get_block_hash_by_height(block.get_height()).as_deref() == Some(block.get_hash()) {
+                return true;
+            }
+            current = self.get_block(block.get_pre_block_hash().as_bytes());
+        }
+        false
+    }
+
+    /// 高度索引中没有tip时, 沿主链重建高度索引.
+    /// 先删除tip高度的索引, 再从低到高写入, 重建中断时tip仍不在索引中, 下次启动重新重建
+    fn check_height_index(&self) -> sled::Result<()> {
+        let tip_hash = self.get_tip_hash();
+        let tip = self.get_block(tip_hash.as_bytes()).expect("the tip hash is not valid");
+        if self.get_block_hash_by_height(tip.get_height()).as_ref() == Some(&tip_hash) {
+            return Ok(());
+        }
+        info!("Rebuild the height index");
+        let height_index_tree = self.get_height_index_tree();
+        height_index_tree.remove((tip.get_height() as u64).to_be_bytes())?;
+        height_index_tree.clear()?;
+        let mut blocks = vec![];
+        let mut iterator = self.iterator();
+        while let Some(block) = iterator.next() {
+            blocks.push((block.get_height(), block.get_hash().to_string()));
+        }
+        for (height, hash) in blocks.iter().rev() {
+            height_index_tree.insert((*height as u64).to_be_bytes(), hash.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// tip没有保存累计工作量时(如旧版本创建的链), 沿前序区块计算并保存缺少的累计工作量
+    fn check_chain_work(&self) -> sled::Result<()> {
+        let tip_hash = self.get_tip_hash();
+        let chain_work_tree = self.get_chain_work_tree();
+        if chain_work_tree.contains_key(tip_hash.as_bytes())? {
+            return Ok(());
+        }
+        info!("Rebuild the chain work");
+        let branch = self.compute_chain_work(tip_hash.as_bytes()).expect("the tip hash is not valid");
+        for (hash, chain_work) in branch {
+            chain_work_tree.insert(hash, chain_work.to_bytes_be())?;
+        }
+        Ok(())
+    }
+
+    /// 获取区块头及其高度, 包括只下载了区块头而没有区块内容的区块
+    pub fn get_header(&self, block_hash: &[u8]) -> Option<(BlockHeader, usize)> {
+        if let Some(bytes) = self.get_headers_tree().get(block_hash).unwrap() {
+            return Some(bincode::deserialize(bytes.as_ref()).expect("unable to deserialize BlockHeader"));
+        }
+        self.get_block(block_hash)
+            .map(|block| (block.get_header().clone(), block.get_height()))
+    }
+
+    /// 保存区块头及其累计工作量, 若该区块头所在分支的累计工作量最大, 则更新最优区块头
+    fn save_header(&self, block_hash: &str, header: &BlockHeader, height: usize) -> sled::Result<()> {
+        let headers_tree = self.get_headers_tree();
+        let bytes = bincode::serialize(&(header, height)).expect("unable to serialize BlockHeader");
+        headers_tree.insert(block_hash, bytes)?;
+        self.save_chain_work(block_hash, header)?;
+
+        let chain_work = self.get_chain_work(block_hash.as_bytes());
+        let best_chain_work = self.get_chain_work(self.get_best_header_hash().as_bytes());
+        if chain_work > best_chain_work {
+            headers_tree.insert(BEST_HEADER_HASH_KEY, block_hash)?;
+        }
+        Ok(())
+    }
+
+    /// 获取累计工作量最大的区块头hash, 其区块内容可能尚未下载
+    pub fn get_best_header_hash(&self) -> String {
+        match self.get_headers_tree().get(BEST_HEADER_HASH_KEY).unwrap() {
+            Some(bytes) => String::from_utf8(bytes.to_vec()).unwrap(),
+            None => self.get_tip_hash(),
+        }
+    }
+
+    /// 校验并保存从网络接收的区块头, 已存在的区块头直接忽略, 已被标记为非法的区块头被拒绝
+    pub fn process_headers(&self, headers: &[BlockHeader]) -> Result<(), ChainError> {
+        let invalid_tree = self.get_invalid_tree();
+        for header in headers {
+            let block_hash = header.hash();
+            if self.get_header(block_hash.as_bytes()).is_some() {
+                continue;
+            }
+            if invalid_tree.contains_key(block_hash.as_str())? {
+                return Err(ChainError::Invalid(ValidationError::KnownInvalid(block_hash)));
+            }
+            let height = validation::validate_header(self, header)?;
+            self.save_header(block_hash.as_str(), header, height)?;
+        }
+
+        Ok(())
+    }
+
+    /// 生成区块定位器: 从最优区块头开始, 前10个区块逐个选取, 之后步长加倍, 最后为创世区块
+    pub fn get_block_locator(&self) -> Vec<Vec<u8>> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut block_hash = self.get_best_header_hash();
+        while let Some((header, height)) = self.get_header(block_hash.as_bytes()) {
+            locator.push(block_hash.as_bytes().to_vec());
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            let target_height = height.saturating_sub(step);
+            let mut current = header;
+            for _ in target_height..height {
+                block_hash = current.get_pre_block_hash();
+                match self.get_header(block_hash.as_bytes()) {
+                    Some((parent, _)) => current = parent,
+                    None => return locator,
+                }
+            }
+        }
+
+        locator
+    }
+
+    /// 根据对端的区块定位器, 返回主链上在共同区块之后的至多max_count个区块头.
+    /// 定位器中没有主链上的区块时(如创世区块不同)返回空
+    pub fn get_headers_after(&self, locator: &[Vec<u8>], max_count: usize) -> Vec<BlockHeader> {
+        let start = locator.iter().find_map(|hash| {
+            let (_, height) = self.get_header(hash.as_slice())?;
+            let main_hash = self.get_block_hash_by_height(height)?;
+            main_hash.as_bytes().eq(hash.as_slice()).then_some(height)
+        });
+        let start = match start {
+            Some(height) => height,
+            None => return vec![],
+        };
+
+        (start + 1..)
+            .take(max_count)
+            .map_while(|height| self.get_block_hash_by_height(height))
+            .filter_map(|hash| self.get_header(hash.as_bytes()))
+            .map(|(header, _)| header)
+            .collect()
+    }
+
+    /// 获取最优区块头所在分支上尚未下载内容的区块hash, 按高度从低到高排列
+    pub fn get_missing_blocks(&self) -> Vec<Vec<u8>> {
+        let mut missing = vec![];
+        let mut block_hash = self.get_best_header_hash();
+        while self.get_block(block_hash.as_bytes()).is_none() {
+            let (header, _) = match self.get_header(block_hash.as_bytes()) {
+                Some(entry) => entry,
+                None => break,
+            };
+            missing.push(block_hash.as_bytes().to_vec());
+            block_hash = header.get_pre_block_hash();
+        }
+        missing.reverse();
+
+        missing
+    }
+
+    /// 获取从创世区块到该区块的累计工作量
+    /// 对于没有保存累计工作量的区块, 沿前序区块计算
+    pub fn get_chain_work(&self, block_hash: &[u8]) -> Option<BigUint> {
+        if let Some(work_bytes) = self.get_chain_work_tree().get(block_hash).unwrap() {
+            return Some(BigUint::from_bytes_be(work_bytes.as_ref()));
+        }
+        self.compute_chain_work(block_hash)?
+            .pop()
+            .map(|(_, chain_work)| chain_work)
+    }
+
+    /// 沿前序区块计算该区块及其之前没有保存累计工作量的区块的累计工作量, 按高度从低到高排列
+    fn compute_chain_work(&self, block_hash: &[u8]) -> Option<Vec<(Vec<u8>, BigUint)>> {
+        let chain_work_tree = self.get_chain_work_tree();
+        let (header, _) = self.get_header(block_hash)?;
+        let mut branch = vec![(block_hash.to_vec(), header)];
+        let mut chain_work = BigUint::default();
+        loop {
+            let pre_block_hash = branch.last().unwrap().1.get_pre_block_hash();
+            if let Some(work_bytes) = chain_work_tree.get(pre_block_hash.as_bytes()).unwrap() {
+                chain_work = BigUint::from_bytes_be(work_bytes.as_ref());
+                break;
+            }
+            match self.get_header(pre_block_hash.as_bytes()) {
+                Some((parent, _)) => branch.push((pre_block_hash.into_bytes(), parent)),
+                None => break,
+            }
+        }
+        let branch = branch.into_iter()
+            .rev()
+            .map(|(hash, header)| {
+                chain_work += ProofOfWork::new(header).get_work();
+                (hash, chain_work.clone())
+            })
+            .collect();
+
+        Some(branch)
+    }
+
+    /// 以block_hash为最后一个区块, 最近MEDIAN_TIME_SPAN个区块时间戳的中位数
+    pub fn get_median_time_past(&self, block_hash: &[u8]) -> u64 {
+        let mut timestamps = vec![];
+        let mut current = self.get_header(block_hash);
+        while let Some((header, _)) = current {
+            timestamps.push(header.get_timestamp());
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            current = self.get_header(header.get_pre_block_hash().as_bytes());
+        }
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or_default()
+    }
+
+    /// 最长链最后一个区块的中位时间, 下一个区块中交易的时间锁按此时间校验
+    pub fn get_tip_median_time_past(&self) -> u64 {
+        self.get_median_time_past(self.get_tip_hash().as_bytes())
+    }
+
+    /// 获取当前最优链的累计工作量
+    pub fn get_best_chain_work(&self) -> BigUint {
+        self.get_chain_work(self.get_tip_hash().as_bytes())
+            .expect("the tip hash is not valid")
+    }
+
+    /// 该区块所在分支的累计工作量是否超过当前最优链
+    /// 工作量相同时保留先收到的分支
+    pub fn is_better_tip(&self, block_hash: &[u8]) -> bool {
+        match self.get_chain_work(block_hash) {
+            Some(chain_work) => chain_work > self.get_best_chain_work(),
+            None => false,
+        }
+    }
+
+    /// 保存区块的累计工作量: 前一个区块的累计工作量 + 本区块工作量
+    fn save_chain_work(&self, block_hash: &str, header: &BlockHeader) -> sled::Result<()> {
+        let mut chain_work = self.get_chain_work(header.get_pre_block_hash().as_bytes())
+            .unwrap_or_default();
+        chain_work += ProofOfWork::new(header.clone()).get_work();
+        self.get_chain_work_tree().insert(block_hash, chain_work.to_bytes_be())?;
+        Ok(())
+    }
+
+    /// 获取最长链的高度
     pub fn get_best_height(&self) -> usize {
         let tip_block_bytes = self.get_block_tree()
             .get(self.get_tip_hash())
             .unwrap()
             .expect("the tip hash is valid");
-        let tip_block = Block::deserialize(tip_block_bytes.as_ref());
+        let tip_block = Block::deserialize(tip_block_bytes.as_ref()).expect("unable to deserialize Block");
 
         tip_block.get_height()
     }
 
-    /// 增加一个区块到链上
-    pub fn add_block(&self, block: &Block) {
+    /// 获取最长链最后一个区块的时间戳
+    pub fn get_tip_timestamp(&self) -> u64 {
+        self.get_block(self.get_tip_hash().as_bytes())
+            .expect("the tip hash is valid")
+            .get_timestamp()
+    }
+
+    /// 增加一个区块到链上, 若该区块所在分支的累计工作量超过当前链, 则切换到该分支.
+    /// 新分支上的区块非法时返回ChainError::Invalid, 此时链停留在累计工作量较大的合法分支上
+    pub fn add_block(&self, block: &Block) -> Result<(), ChainError> {
         let block_tree = self.get_block_tree();
-        if let Some(_) = block_tree.get(block.get_hash()).unwrap() {
-            return;
+        if block_tree.get(block.get_hash())?.is_some() {
+            return Ok(());
         }
+        block_tree.insert(block.get_hash(), block.serialize())?;
+        self.save_header(block.get_hash(), block.get_header(), block.get_height())?;
 
-        let _: TransactionResult<(), ()> = block_tree.transaction(|tx_db|{
-            tx_db.insert(block.get_hash(), block.serialize());
-            let tip_block_bytes = tx_db.get(self.get_tip_hash())
-                .unwrap()
+        if self.is_better_tip(block.get_hash_bytes().as_slice()) {
+            let tip_block = self.get_block(self.get_tip_hash().as_bytes())
                 .expect("the tip hash is not valid");
-            let tip_block = Block::deserialize(tip_block_bytes.as_ref());
-            if block.get_height() > tip_block.get_height() {
-                let _ = tx_db.insert(TIP_BLOCK_HASH_KEY, block.get_hash()).unwrap();
-                self.set_tip_hash(block.get_hash());
+            self.reorganize(&tip_block, block)?;
+        }
+        Ok(())
+    }
+
+    /// 从tip切换到new_tip: 先在内存中断开旧分支上的区块直到共同祖先并依次校验新分支上的区块,
+    /// 再在一个事务中断开旧分支并连接新分支. 新分支上的区块非法时不做任何修改,
+    /// 将该区块及其后代标记为非法, 再切换到剩余区块中累计工作量最大的区块
+    fn reorganize(&self, tip: &Block, new_tip: &Block) -> Result<(), ChainError> {
+        let (disconnected, connected) = self.find_fork(tip, new_tip)?;
+        let utxo_set = UTXOSet::new(self.clone());
+        let mut fork_coins = CoinsViewCache::new(self);
+        for block in &disconnected {
+            fork_coins.disconnect_block(&utxo_set, block);
+        }
+        let mut coins = CoinsViewCache::new(&fork_coins);
+        for block in connected.iter().rev() {
+            if let Err(e) = validation::validate_block_with_coins(self, &coins, block) {
+                error!("Invalid block {}: {}", block.get_hash(), e);
+                self.invalidate_block(block.get_hash())?;
+                self.check_best_block()?;
+                return Err(ChainError::Invalid(e));
+            }
+            for tx in block.get_transactions() {
+                coins.apply(tx, block.get_height());
+            }
+        }
+
+        self.chain_transaction(|trees| {
+            for block in &disconnected {
+                info!("Disconnect block {}", block.get_hash());
+                self.disconnect(trees, block)?;
+            }
+            for block in connected.iter().rev() {
+                info!("Connect block {}", block.get_hash());
+                self.connect(trees, block)?;
             }
             Ok(())
-        });
+        })?;
+        self.set_tip_hash(new_tip.get_hash());
+        Ok(())
+    }
+
+    /// 在一个事务中将非法区块及其后代标记为非法: 删除其区块内容, 区块头和累计工作量并记录其hash,
+    /// 最优区块头改为剩余区块头中累计工作量最大的区块, 工作量相同时为tip
+    fn invalidate_block(&self, block_hash: &str) -> Result<(), ChainError> {
+        let (block_tree, headers_tree, chain_work_tree, invalid_tree) =
+            (self.get_block_tree(), self.get_headers_tree(), self.get_chain_work_tree(), self.get_invalid_tree());
+        let (_, height) = self.get_header(block_hash.as_bytes()).expect("the invalid block is not found");
+        let mut descendants = vec![];
+        for (hash, bytes) in headers_tree.iter().flatten() {
+            if hash == BEST_HEADER_HASH_KEY {
+                continue;
+            }
+            let (header, header_height): (BlockHeader, usize) =
+                bincode::deserialize(bytes.as_ref()).expect("unable to deserialize BlockHeader");
+            if header_height > height {
+                descendants.push((header_height, String::from_utf8(hash.to_vec()).unwrap(), header.get_pre_block_hash()));
+            }
+        }
+        // 按高度从低到高, 前一个区块非法的区块也非法
+        descendants.sort();
+        let mut invalid = HashSet::from([String::from(block_hash)]);
+        for (_, hash, pre_block_hash) in descendants {
+            if invalid.contains(&pre_block_hash) {
+                invalid.insert(hash);
+            }
+        }
+
+        let mut best_header_hash = self.get_tip_hash();
+        let mut best_work = self.get_best_chain_work();
+        for (hash, work_bytes) in chain_work_tree.iter().flatten() {
+            let hash = String::from_utf8(hash.to_vec()).unwrap();
+            let work = BigUint::from_bytes_be(work_bytes.as_ref());
+            if work > best_work && !invalid.contains(&hash) {
+                best_header_hash = hash;
+                best_work = work;
+            }
+        }
+
+        (&block_tree, &headers_tree, &chain_work_tree, &invalid_tree)
+            .transaction(|(blocks, headers, chain_work, invalid_blocks)| -> ConflictableTransactionResult<(), ValidationError> {
+                for hash in &invalid {
+                    blocks.remove(hash.as_str())?;
+                    headers.remove(hash.as_str())?;
+                    chain_work.remove(hash.as_str())?;
+                    invalid_blocks.insert(hash.as_str(), &[])?;
+                }
+                headers.insert(BEST_HEADER_HASH_KEY, best_header_hash.as_str())?;
+                Ok(())
+            })
+            .map_err(ChainError::from)
+    }
+
+    /// 在一个事务中修改区块, tip, utxo set, undo记录, 高度索引, 交易索引和地址索引, f中止时不做任何修改
+    fn chain_transaction<F>(&self, f: F) -> Result<(), ChainError>
+    where
+        F: Fn(&ChainTrees) -> ConflictableTransactionResult<(), ValidationError>,
+    {
+        let db = &self.db;
+        let trees = [BLOCKS_TREE, UTXO_TREE, UNDO_TREE, HEIGHT_INDEX_TREE, TXINDEX_TREE, ADDRINDEX_TREE]
+            .map(|name| db.open_tree(name).unwrap());
+        let [blocks, utxo, undo, height_index, txindex, addrindex] = &trees;
+        (blocks, utxo, undo, height_index, txindex, addrindex)
+            .transaction(|(blocks, utxo, undo, height_index, txindex, addrindex)| {
+                f(&ChainTrees { blocks, utxo, undo, height_index, txindex, addrindex })
+            })
+            .map_err(ChainError::from)
+    }
+
+    /// 连接区块: 保存区块并设为tip, 同时连接到utxo set, 高度索引, 交易索引和地址索引.
+    /// 区块花费了utxo set中不存在的输出时不做任何修改
+    fn connect_block(&self, block: &Block) -> Result<(), ChainError> {
+        self.chain_transaction(|trees| self.connect(trees, block))?;
+        self.set_tip_hash(block.get_hash());
+        Ok(())
+    }
+
+    /// 在事务中连接区块, 区块花费了utxo set中不存在的输出时中止事务
+    fn connect(&self, trees: &ChainTrees, block: &Block) -> ConflictableTransactionResult<(), ValidationError> {
+        trees.blocks.insert(block.get_hash(), block.serialize())?;
+        trees.blocks.insert(TIP_BLOCK_HASH_KEY, block.get_hash())?;
+        UTXOSet::connect(trees.utxo, trees.undo, block)?;
+        trees.height_index.insert(&(block.get_height() as u64).to_be_bytes(), block.get_hash())?;
+        if self.is_txindex_enabled() {
+            TxIndex::connect(trees.txindex, block)?;
+        }
+        if self.addrindex {
+            AddressIndex::connect(trees.addrindex, trees.undo, block)?;
+        }
+        Ok(())
+    }
+
+    /// 在事务中断开tip区块: 将前一个区块设为tip, 同时从地址索引, utxo set, 高度索引和交易索引中断开.
+    /// 地址索引需要读取undo记录, 须在utxo set之前断开
+    fn disconnect(&self, trees: &ChainTrees, block: &Block) -> ConflictableTransactionResult<(), ValidationError> {
+        if self.addrindex {
+            AddressIndex::disconnect(trees.addrindex, trees.undo, block)?;
+        }
+        UTXOSet::disconnect(trees.utxo, trees.undo, block)?;
+        trees.height_index.remove(&(block.get_height() as u64).to_be_bytes())?;
+        if self.is_txindex_enabled() {
+            TxIndex::disconnect(trees.txindex, block)?;
+        }
+        trees.blocks.insert(TIP_BLOCK_HASH_KEY, block.get_pre_block_hash().as_str())?;
+        Ok(())
+    }
+
+    /// 查找两个区块到共同祖先的分支, 返回(old分支上的区块, new分支上的区块), 均按高度从高到低排列.
+    /// 分支上缺少区块内容时返回ValidationError::MissingParent
+    fn find_fork(&self, old: &Block, new: &Block) -> Result<(Vec<Block>, Vec<Block>), ChainError> {
+        let mut old_branch = vec![];
+        let mut new_branch = vec![];
+        let mut old = old.clone();
+        let mut new = new.clone();
+        while new.get_height() > old.get_height() {
+            let parent = self.get_parent(&new)?;
+            new_branch.push(new);
+            new = parent;
+        }
+        while old.get_height() > new.get_height() {
+            let parent = self.get_parent(&old)?;
+            old_branch.push(old);
+            old = parent;
+        }
+        while old.get_hash() != new.get_hash() {
+            let old_parent = self.get_parent(&old)?;
+            let new_parent = self.get_parent(&new)?;
+            old_branch.push(old);
+            new_branch.push(new);
+            old = old_parent;
+            new = new_parent;
+        }
+
+        Ok((old_branch, new_branch))
+    }
+
+    /// 计算高度为parent_height的parent之后下一个区块的bits
+    /// 每隔retarget_interval个区块, 根据上一个窗口内首尾区块的时间戳调整一次, 其余区块沿用parent的bits
+    pub fn get_next_bits(&self, parent: &BlockHeader, parent_height: usize) -> u32 {
+        let interval = GLOBAL_CONFIG.get_retarget_interval();
+        let height = parent_height + 1;
+        if !height.is_multiple_of(interval) {
+            return parent.get_bits();
+        }
+
+        let mut first = parent.clone();
+        for _ in 1..interval {
+            first = self.get_header(first.get_pre_block_hash().as_bytes())
+                .expect("the parent header is not found")
+                .0;
+        }
+        let actual_timespan = parent.get_timestamp().saturating_sub(first.get_timestamp());
+        let expected_timespan = GLOBAL_CONFIG.get_target_block_time() * (interval as u64 - 1);
+
+        ProofOfWork::retarget(parent.get_bits(), actual_timespan, expected_timespan)
+    }
+
+    /// 获取block_hash所在分支上高度为height的区块hash: 沿区块头回溯到主链上的区块, 再查找高度索引
+    pub fn get_ancestor_hash(&self, block_hash: &str, height: usize) -> Option<String> {
+        let mut hash = String::from(block_hash);
+        loop {
+            let (header, block_height) = self.get_header(hash.as_bytes())?;
+            if block_height < height {
+                return None;
+            }
+            if self.get_block_hash_by_height(block_height).as_ref() == Some(&hash) {
+                return self.get_block_hash_by_height(height);
+            }
+            if block_height == height {
+                return Some(hash);
+            }
+            hash = header.get_pre_block_hash();
+        }
+    }
+
+    /// 获取区块的前一个区块
+    fn get_parent(&self, block: &Block) -> Result<Block, ChainError> {
+        let pre_block_hash = block.get_pre_block_hash();
+        self.get_block(pre_block_hash.as_bytes())
+            .ok_or(ChainError::Invalid(ValidationError::MissingParent(pre_block_hash)))
+    }
+
+    /// 校验并增加一个从网络接收的区块, 已存在的区块直接忽略, 已被标记为非法的区块被拒绝.
+    /// 交易在区块连接到tip之后时才按其所在分支的utxo set校验
+    pub fn process_block(&self, block: &Block) -> Result<(), ChainError> {
+        if self.get_block(block.get_hash_bytes().as_slice()).is_some() {
+            return Ok(());
+        }
+        if self.get_invalid_tree().contains_key(block.get_hash())? {
+            return Err(ChainError::Invalid(ValidationError::KnownInvalid(String::from(block.get_hash()))));
+        }
+        validation::check_block(self, block)?;
+        self.add_block(block)
     }
 
     /// 获取block
     pub fn get_block(&self, block_hash: &[u8]) -> Option<Block> {
         if let Some(block_bytes) = self.get_block_tree().get(block_hash).unwrap() {
-            let block = Block::deserialize(block_bytes.as_ref());
+            let block = Block::deserialize(block_bytes.as_ref()).expect("unable to deserialize Block");
             return Some(block);
         }
-        return None;
+        None
     }
 
     /// 获取所有区块hash
@@ -145,24 +803,33 @@ impl Blockchain {
             hashes.push(block.get_hash_bytes());
         }
 
-        return hashes;
+        hashes
     }
 
     /// 挖出一个新块
     pub fn mine_block(&self, transactions: &[Transaction]) -> Block {
-        // 首先检查交易是否合法
+        let tip_block = self.get_block(self.get_tip_hash().as_bytes())
+            .expect("the tip hash is not valid");
+        // 首先检查交易是否合法, 交易可以花费同一区块中之前交易的输出
+        let height = tip_block.get_height() + 1;
+        let median_time_past = self.get_median_time_past(tip_block.get_hash_bytes().as_slice());
+        let mut coins = CoinsViewCache::new(self);
         for tx in transactions {
-            if !tx.verify(self) {
-                panic!("ERROR: Invalid tx");
+            if !tx.is_coinbase() {
+                if let Err(e) = validation::validate_transaction_with_coins(self, &coins, tx, height, median_time_past) {
+                    panic!("ERROR: Invalid tx: {}", e);
+                }
             }
+            coins.apply(tx, height);
         }
 
-        //
-        let best_height = self.get_best_height();
-        let block = Block::new(self.get_tip_hash(), transactions, best_height+1);
-        let block_tree = self.get_block_tree();
-        Self::update_blocks_tree(&block_tree, &block);
-        self.set_tip_hash(block.get_hash());
+        let bits = self.get_next_bits(tip_block.get_header(), tip_block.get_height());
+        // 时间戳不早于前面区块的中位时间
+        let timestamp = current_timestamp().max(median_time_past);
+        let block = Block::new_with_timestamp(self.get_tip_hash(), transactions, tip_block.get_height() + 1, bits, timestamp);
+        self.save_header(block.get_hash(), block.get_header(), block.get_height())
+            .expect("unable to save the mined block header");
+        self.connect_block(&block).expect("the mined block is valid");
 
         block
     }
@@ -171,29 +838,48 @@ impl Blockchain {
         BlockchainIterator::new(self.get_tip_hash(), self.db.clone())
     }
 
-    /// 根据id从链中查找交易
+    /// 开启了交易索引且索引已同步到tip时返回索引的tree
+    fn get_synced_txindex(&self) -> Option<&Tree> {
+        let txindex_tree = self.txindex.as_ref()?;
+        if txindex::get_best_block_hash(txindex_tree)? != self.get_tip_hash() {
+            return None;
+        }
+        Some(txindex_tree)
+    }
+
+    /// 根据id从链中查找交易, 交易索引已同步时直接查找索引
     pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
+        if let Some(txindex_tree) = self.get_synced_txindex() {
+            let (block_hash, position) = txindex::get_location(txindex_tree, txid)?;
+            return self.get_block(block_hash.as_bytes())?.get_transactions().get(position).cloned();
+        }
+        self.find_transaction_block(txid)?
+            .get_transactions()
+            .iter()
+            .find(|transaction| txid.eq(transaction.get_id()))
+            .cloned()
+    }
+
+    /// 从链中查找数据输出携带data的区块及交易
+    pub fn find_data(&self, data: &[u8]) -> Option<(Block, Transaction)> {
         let mut iterator = self.iterator();
-        loop {
-            let option = iterator.next();
-            if option.is_none() {
-                break;
-            }
-            let block = option.unwrap();
-            for transaction in block.get_transactions() {
-                if txid.eq(transaction.get_id()) {
-                    return Some(transaction.clone());
-                }
+        while let Some(block) = iterator.next() {
+            let tx = block.get_transactions().iter()
+                .find(|tx| tx.get_vout().iter().any(|out| out.get_script_pubkey().get_data() == Some(data)))
+                .cloned();
+            if let Some(tx) = tx {
+                return Some((block, tx));
             }
         }
         None
     }
 
-    /// 查找该链上所有未花费的交易输出
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TxOutput>> {
-        let mut utxo: HashMap<String, Vec<TxOutput>> = HashMap::new();
-        let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
-
+    /// 根据交易id从链中查找包含该交易的区块, 交易索引已同步时直接查找索引
+    pub fn find_transaction_block(&self, txid: &[u8]) -> Option<Block> {
+        if let Some(txindex_tree) = self.get_synced_txindex() {
+            let (block_hash, _) = txindex::get_location(txindex_tree, txid)?;
+            return self.get_block(block_hash.as_bytes());
+        }
         let mut iterator = self.iterator();
         loop {
             let option = iterator.next();
@@ -201,45 +887,29 @@ impl Blockchain {
                 break;
             }
             let block = option.unwrap();
-            'outer: for tx in block.get_transactions() {
-                let txid_hex = HEXLOWER.encode(tx.get_id());
-                for (idx, out) in tx.get_vout().iter().enumerate() {
-                    // 过滤已花费的输出
-                    if let Some(outs) = spent_txos.get(txid_hex.as_str()) {
-                        for spend_out_idx in outs {
-                            if idx.eq(spend_out_idx) {
-                                continue 'outer;
-                            }
-                        }
-                    }
-                    if utxo.contains_key(txid_hex.as_str()) {
-                        utxo.get_mut(txid_hex.as_str()).unwrap().push(out.clone());
-                    } else {
-                        utxo.insert(txid_hex.clone(), vec![out.clone()]);
-                    }
-                }
-                if tx.is_coinbase() {
-                    continue;
-                }
-                // 在输入中查找已花费输出
-                for txin in tx.get_vin() {
-                    let txid_hex = HEXLOWER.encode(txin.get_txid());
-                    if spent_txos.contains_key(txid_hex.as_str()) {
-                        spent_txos
-                            .get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(txin.get_outid());
-                    } else {
-                        spent_txos.insert(txid_hex, vec![txin.get_outid()]);
-                    }
-                }
+            if block.get_transactions().iter().any(|transaction| txid.eq(transaction.get_id())) {
+                return Some(block);
             }
         }
-
-        utxo
+        None
     }
 }
 
+/// 开启交易索引时打开索引的tree
+fn open_txindex(db: &Db, txindex: bool) -> Option<Tree> {
+    txindex.then(|| db.open_tree(TXINDEX_TREE).unwrap())
+}
+
+/// 连接和断开区块时在同一个事务中修改的tree
+struct ChainTrees<'a> {
+    blocks: &'a TransactionalTree,
+    utxo: &'a TransactionalTree,
+    undo: &'a TransactionalTree,
+    height_index: &'a TransactionalTree,
+    txindex: &'a TransactionalTree,
+    addrindex: &'a TransactionalTree,
+}
+
 /// blockchain 迭代器
 pub struct BlockchainIterator {
     db: Db,
@@ -255,20 +925,431 @@ impl BlockchainIterator {
     /// blochchain 中的下一个block
     pub fn next(&mut self) -> Option<Block> {
         let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let data = block_tree.get(self.current_hash.clone()).unwrap();
-        if data.is_none() {
-            return None;
-        }
-        let block = Block::deserialize(data.unwrap().to_vec().as_slice());
+        let data = block_tree.get(self.current_hash.clone()).unwrap()?;
+        let block = Block::deserialize(data.as_ref()).expect("unable to deserialize Block");
         self.current_hash = block.get_pre_block_hash().clone();
 
-        return Some(block);
+        Some(block)
     }
 }
 
+/// 按高度从低到高遍历主链区块的迭代器
+pub struct BlockchainForwardIterator {
+    blockchain: Blockchain,
+    heights: Range<usize>,
+}
+
+impl Iterator for BlockchainForwardIterator {
+    type Item = Block;
+
+    /// 下一个高度的区块, 超过主链高度时结束
+    fn next(&mut self) -> Option<Block> {
+        let height = self.heights.next()?;
+        let block = self.blockchain.get_block_by_height(height);
+        if block.is_none() {
+            self.heights = 0..0;
+        }
+        block
+    }
+}
 
 #[cfg(test)]
 mod tests{
+    use super::*;
+    use crate::{
+        test_utils::{mine_to_maturity, new_blockchain, temporary_db},
+        wallet::Wallet
+    };
 
-}
+    /// 新建一条与source共享创世区块的区块链
+    fn new_blockchain_from_genesis(source: &Blockchain) -> Blockchain {
+        let mut hashes = source.get_block_hashes();
+        let genesis = source.get_block(hashes.pop().unwrap().as_slice()).unwrap();
+        let db = temporary_db();
+        let blockchain = Blockchain {
+            tip_hash: Arc::new(RwLock::new(String::from(genesis.get_hash()))),
+            db,
+            txindex: None,
+            addrindex: false,
+        };
+        blockchain.save_header(genesis.get_hash(), genesis.get_header(), 0).unwrap();
+        blockchain.connect_block(&genesis).unwrap();
+
+        blockchain
+    }
+
+    #[test]
+    fn test_headers_first_sync() {
+        let source = new_blockchain(Wallet::new().get_address().as_str());
+        let address = Wallet::new().get_address();
+        for height in 1..=3 {
+            source.mine_block(&[Transaction::new_coinbase_tx(address.as_str(), height, Amount::ZERO)]);
+        }
+        let blockchain = new_blockchain_from_genesis(&source);
+        let genesis_hash = blockchain.get_tip_hash();
+
+        // 先同步并校验区块头
+        let headers = source.get_headers_after(blockchain.get_block_locator().as_slice(), 2000);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(blockchain.process_headers(headers.as_slice()), Ok(()));
+        assert_eq!(blockchain.get_best_header_hash(), source.get_tip_hash());
+        assert_eq!(blockchain.get_tip_hash(), genesis_hash);
+
+        // 再按顺序下载区块内容
+        let mut expected = source.get_block_hashes();
+        expected.pop();
+        expected.reverse();
+        let missing_blocks = blockchain.get_missing_blocks();
+        assert_eq!(missing_blocks, expected);
+        for block_hash in missing_blocks {
+            let block = source.get_block(block_hash.as_slice()).unwrap();
+            assert_eq!(blockchain.process_block(&block), Ok(()));
+        }
+        assert_eq!(blockchain.get_tip_hash(), source.get_tip_hash());
+        assert!(blockchain.get_missing_blocks().is_empty());
+        assert_eq!(blockchain.get_block_locator().len(), 4);
+
+        // 从定位器中第一个主链区块之后开始, 没有主链区块时返回空
+        let locator = vec![b"unknown".to_vec(), genesis_hash.as_bytes().to_vec()];
+        assert_eq!(source.get_headers_after(locator.as_slice(), 2).len(), 2);
+        assert!(source.get_headers_after(&[b"unknown".to_vec()], 2000).is_empty());
+    }
+
+    #[test]
+    fn test_fork_choice_by_chain_work() {
+        let bits = ProofOfWork::initial_bits();
+        let blockchain = new_blockchain(Wallet::new().get_address().as_str());
+        let address = Wallet::new().get_address();
+        let genesis_hash = blockchain.get_tip_hash();
+        let genesis_work = blockchain.get_best_chain_work();
+
+        let a1 = blockchain.mine_block(&[Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO)]);
+        assert_eq!(blockchain.get_best_chain_work(), &genesis_work * 2u8);
+
+        // 工作量相同, 保留先收到的a1
+        let b1_coinbase = Transaction::new_coinbase_tx_with_extra(address.as_str(), 1, Amount::ZERO, b"b");
+        let b1 = Block::new(genesis_hash, &[b1_coinbase], 1, bits);
+        blockchain.add_block(&b1).unwrap();
+        assert_eq!(blockchain.get_chain_work(b1.get_hash_bytes().as_slice()), Some(&genesis_work * 2u8));
+        assert!(!blockchain.is_better_tip(b1.get_hash_bytes().as_slice()));
+        assert_eq!(blockchain.get_tip_hash(), a1.get_hash());
+
+        let b2 = Block::new(String::from(b1.get_hash()), &[Transaction::new_coinbase_tx(address.as_str(), 2, Amount::ZERO)], 2, bits);
+        blockchain.add_block(&b2).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+        assert_eq!(blockchain.get_best_chain_work(), &genesis_work * 3u8);
+    }
+
+    #[test]
+    fn test_height_index() {
+        let bits = ProofOfWork::initial_bits();
+        let blockchain = new_blockchain(Wallet::new().get_address().as_str());
+        let address = Wallet::new().get_address();
+        let genesis_hash = blockchain.get_tip_hash();
+        let a1 = blockchain.mine_block(&[Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO)]);
+        assert_eq!(blockchain.get_block_hash_by_height(0), Some(genesis_hash.clone()));
+        assert_eq!(blockchain.get_block_hash_by_height(1), Some(String::from(a1.get_hash())));
+
+        // 切换分支后高度索引指向新分支上的区块
+        let b1_coinbase = Transaction::new_coinbase_tx_with_extra(address.as_str(), 1, Amount::ZERO, b"b");
+        let b1 = Block::new(genesis_hash.clone(), &[b1_coinbase], 1, bits);
+        blockchain.add_block(&b1).unwrap();
+        let b2 = Block::new(String::from(b1.get_hash()), &[Transaction::new_coinbase_tx(address.as_str(), 2, Amount::ZERO)], 2, bits);
+        blockchain.add_block(&b2).unwrap();
+        assert_eq!(blockchain.get_block_by_height(1).map(|block| block.get_hash_bytes()), Some(b1.get_hash_bytes()));
+        assert!(blockchain.get_block_by_height(3).is_none());
+
+        let expected = vec![genesis_hash.clone(), String::from(b1.get_hash()), String::from(b2.get_hash())];
+        let hashes: Vec<String> = blockchain.forward_iterator(0, 10).map(|block| String::from(block.get_hash())).collect();
+        assert_eq!(hashes, expected);
+        let hashes: Vec<String> = blockchain.forward_iterator(1, 2).map(|block| String::from(block.get_hash())).collect();
+        assert_eq!(hashes, expected[1..2]);
+
+        // 高度索引丢失时重建
+        blockchain.get_height_index_tree().clear().unwrap();
+        blockchain.check_height_index().unwrap();
+        let hashes: Vec<String> = blockchain.forward_iterator(0, 10).map(|block| String::from(block.get_hash())).collect();
+        assert_eq!(hashes, expected);
 
+        // 重建中断时只写入了较低的高度, tip不在索引中, 再次重建
+        let tip_height = blockchain.get_best_height() as u64;
+        blockchain.get_height_index_tree().remove(tip_height.to_be_bytes()).unwrap();
+        blockchain.check_height_index().unwrap();
+        let hashes: Vec<String> = blockchain.forward_iterator(0, 10).map(|block| String::from(block.get_hash())).collect();
+        assert_eq!(hashes, expected);
+    }
+
+    #[test]
+    fn test_reorganize_invalid_branch() {
+        let bits = ProofOfWork::initial_bits();
+        let blockchain = new_blockchain(Wallet::new().get_address().as_str());
+        let address = Wallet::new().get_address();
+        let genesis_hash = blockchain.get_tip_hash();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let a1_coinbase = Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO);
+        let a1 = blockchain.mine_block(std::slice::from_ref(&a1_coinbase));
+
+        // b分支上花费a1的coinbase输出, 连接时才发现非法
+        let invalid_block = |pre_block_hash: &str, height: usize| {
+            let mut tx = Transaction::new_with_outputs(vec![crate::TxOutput::new(Amount::from_sat(1), address.as_str())]);
+            tx.add_input(a1_coinbase.get_id(), 0);
+            let coinbase = Transaction::new_coinbase_tx(address.as_str(), height, Amount::ZERO);
+            Block::new(String::from(pre_block_hash), &[coinbase, tx], height, bits)
+        };
+        let expected = Err(ChainError::Invalid(ValidationError::MissingInput {
+            txid: data_encoding::HEXLOWER.encode(a1_coinbase.get_id()),
+            outid: 0,
+        }));
+
+        // 切换失败后删除非法区块, 切换回累计工作量更大的a分支
+        let b1_coinbase = Transaction::new_coinbase_tx_with_extra(address.as_str(), 1, Amount::ZERO, b"b");
+        let b1 = Block::new(genesis_hash, &[b1_coinbase], 1, bits);
+        blockchain.add_block(&b1).unwrap();
+        let b2 = invalid_block(b1.get_hash(), 2);
+        assert_eq!(blockchain.add_block(&b2), expected);
+        assert_eq!(blockchain.get_tip_hash(), a1.get_hash());
+        assert_eq!(blockchain.get_best_header_hash(), a1.get_hash());
+        assert_eq!(blockchain.get_block_hash_by_height(1), Some(String::from(a1.get_hash())));
+        assert!(blockchain.get_block(b2.get_hash_bytes().as_slice()).is_none());
+        assert!(utxo_set.is_synced());
+        assert_eq!(utxo_set.count_transactions(), 2);
+
+        // 合法的b2切换到b分支后, 直接接在tip之后的非法区块被拒绝, tip不变
+        let b2 = Block::new(String::from(b1.get_hash()), &[Transaction::new_coinbase_tx(address.as_str(), 2, Amount::ZERO)], 2, bits);
+        blockchain.add_block(&b2).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+        let b3 = invalid_block(b2.get_hash(), 3);
+        assert_eq!(blockchain.add_block(&b3), expected);
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+        assert_eq!(blockchain.get_best_header_hash(), b2.get_hash());
+        assert!(blockchain.get_block(b3.get_hash_bytes().as_slice()).is_none());
+        assert!(utxo_set.is_synced());
+
+        // 已下载c3的区块头, 非法的c2及其后代都被标记为非法, 再次收到时直接拒绝
+        let c1 = Block::new(String::from(b1.get_hash()), &[Transaction::new_coinbase_tx_with_extra(address.as_str(), 2, Amount::ZERO, b"c")], 2, bits);
+        blockchain.add_block(&c1).unwrap();
+        let c2 = invalid_block(c1.get_hash(), 3);
+        let c3 = Block::new(String::from(c2.get_hash()), &[Transaction::new_coinbase_tx(address.as_str(), 4, Amount::ZERO)], 4, bits);
+        blockchain.process_headers(&[c2.get_header().clone(), c3.get_header().clone()]).unwrap();
+        assert_eq!(blockchain.get_best_header_hash(), c3.get_hash());
+        assert_eq!(blockchain.add_block(&c2), expected);
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+        assert_eq!(blockchain.get_best_header_hash(), b2.get_hash());
+        assert!(blockchain.get_header(c3.get_hash_bytes().as_slice()).is_none());
+        assert!(blockchain.get_chain_work(c3.get_hash_bytes().as_slice()).is_none());
+        let known_invalid = |block: &Block| Err(ChainError::Invalid(ValidationError::KnownInvalid(String::from(block.get_hash()))));
+        assert_eq!(blockchain.process_block(&c2), known_invalid(&c2));
+        assert_eq!(blockchain.process_headers(&[c3.get_header().clone()]), known_invalid(&c3));
+
+        // d3非法时, 新分支上合法的d1, d2累计工作量超过tip, 切换到d2
+        let d_block = |pre_block_hash: &str, height: usize| {
+            let coinbase = Transaction::new_coinbase_tx_with_extra(address.as_str(), height, Amount::ZERO, b"d");
+            Block::new(String::from(pre_block_hash), &[coinbase], height, bits)
+        };
+        let d1 = d_block(b1.get_hash(), 2);
+        let d2 = d_block(d1.get_hash(), 3);
+        let d3 = invalid_block(d2.get_hash(), 4);
+        for block in [&d1, &d2] {
+            blockchain.get_block_tree().insert(block.get_hash(), block.serialize()).unwrap();
+            blockchain.save_header(block.get_hash(), block.get_header(), block.get_height()).unwrap();
+        }
+        assert_eq!(blockchain.add_block(&d3), expected);
+        assert_eq!(blockchain.get_tip_hash(), d2.get_hash());
+        assert_eq!(blockchain.get_best_header_hash(), d2.get_hash());
+        assert!(utxo_set.is_synced());
+    }
+
+    #[test]
+    fn test_process_side_fork_block() {
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let blockchain = new_blockchain(address.as_str());
+        mine_to_maturity(&blockchain, address.as_str());
+        let fork = blockchain.get_block(blockchain.get_tip_hash().as_bytes()).unwrap();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+
+        // tx2花费tx1的找零, 两者只出现在b分支上
+        let tx1 = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(1), Amount::from_sat(1), &utxo_set);
+        let mut coins = CoinsViewCache::new(&blockchain);
+        coins.apply(&tx1, fork.get_height() + 1);
+        let change = tx1.get_vout()[1].get_cost();
+        let mut tx2 = Transaction::new_with_outputs(vec![crate::TxOutput::new(change.checked_sub(Amount::from_sat(1)).unwrap(), address.as_str())]);
+        tx2.add_input(tx1.get_id(), 1);
+        tx2.sign(&coins, &wallet);
+
+        let miner = Wallet::new().get_address();
+        for height in fork.get_height() + 1..=fork.get_height() + 2 {
+            blockchain.mine_block(&[Transaction::new_coinbase_tx(miner.as_str(), height, Amount::ZERO)]);
+        }
+        let main_tip = blockchain.get_tip_hash();
+
+        let new_block = |parent: &Block, tx: Option<&Transaction>| {
+            let height = parent.get_height() + 1;
+            let mut txs = vec![Transaction::new_coinbase_tx(address.as_str(), height, Amount::ZERO)];
+            txs.extend(tx.cloned());
+            let bits = blockchain.get_next_bits(parent.get_header(), parent.get_height());
+            Block::new(String::from(parent.get_hash()), &txs, height, bits)
+        };
+        // 分叉区块花费本分支上的输出, 接收时不按主链的utxo set校验
+        let b1 = new_block(&fork, Some(&tx1));
+        assert_eq!(blockchain.process_block(&b1), Ok(()));
+        let b2 = new_block(&b1, Some(&tx2));
+        assert_eq!(blockchain.process_block(&b2), Ok(()));
+        assert_eq!(blockchain.get_tip_hash(), main_tip);
+
+        // b分支累计工作量超过主链后, 连接时校验其中的交易
+        let b3 = new_block(&b2, None);
+        assert_eq!(blockchain.process_block(&b3), Ok(()));
+        assert_eq!(blockchain.get_tip_hash(), b3.get_hash());
+        assert!(utxo_set.get_coin(tx1.get_id(), 1).is_none());
+        assert!(utxo_set.get_coin(tx2.get_id(), 0).is_some());
+
+        // c分支包含相同的交易, 校验时在断开b分支后的视图上重新执行
+        let new_c_block = |parent: &Block, tx: Option<&Transaction>| {
+            let height = parent.get_height() + 1;
+            let mut txs = vec![Transaction::new_coinbase_tx_with_extra(miner.as_str(), height, Amount::ZERO, b"c")];
+            txs.extend(tx.cloned());
+            let bits = blockchain.get_next_bits(parent.get_header(), parent.get_height());
+            Block::new(String::from(parent.get_hash()), &txs, height, bits)
+        };
+        let c1 = new_c_block(&fork, Some(&tx1));
+        let c2 = new_c_block(&c1, Some(&tx2));
+        let c3 = new_c_block(&c2, None);
+        let c4 = new_c_block(&c3, None);
+        for block in [&c1, &c2, &c3, &c4] {
+            assert_eq!(blockchain.process_block(block), Ok(()));
+        }
+        assert_eq!(blockchain.get_tip_hash(), c4.get_hash());
+        assert!(utxo_set.is_synced());
+        assert!(utxo_set.get_coin(tx1.get_id(), 1).is_none());
+        assert!(utxo_set.get_coin(tx2.get_id(), 0).is_some());
+    }
+
+    #[test]
+    fn test_connect_block_missing_coin() {
+        let blockchain = new_blockchain(Wallet::new().get_address().as_str());
+        let address = Wallet::new().get_address();
+        let tip_hash = blockchain.get_tip_hash();
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let count = utxo_set.count_transactions();
+
+        // 花费不存在的输出时中止事务, 不修改tip和utxo set
+        let mut tx = Transaction::new_with_outputs(vec![crate::TxOutput::new(Amount::from_sat(1), address.as_str())]);
+        tx.add_input(&[1; 32], 0);
+        let txs = [Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO), tx];
+        let block = Block::new(tip_hash.clone(), &txs, 1, ProofOfWork::initial_bits());
+        assert_eq!(
+            blockchain.connect_block(&block),
+            Err(ChainError::Invalid(ValidationError::MissingInput { txid: data_encoding::HEXLOWER.encode(&[1; 32]), outid: 0 }))
+        );
+        assert_eq!(blockchain.get_tip_hash(), tip_hash);
+        assert!(utxo_set.is_synced());
+        assert_eq!(utxo_set.count_transactions(), count);
+        assert!(blockchain.get_block_by_height(1).is_none());
+    }
+
+    #[test]
+    fn test_check_consistency_indexes() {
+        let address = Wallet::new().get_address();
+        let db = temporary_db();
+        let blockchain = Blockchain::create_blockchain_with_indexes(db.clone(), address.as_str(), true, true);
+        let coinbase = Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO);
+        let tip = blockchain.mine_block(std::slice::from_ref(&coinbase));
+        let hash = crate::Script::from_address(address.as_str()).unwrap().get_address_hash().unwrap().to_vec();
+        let history = AddressIndex::new(blockchain.clone()).get_history(hash.as_slice());
+
+        // 模拟索引停在前一个区块, 重新打开时重建开启的索引
+        TxIndex::new(blockchain.clone()).disconnect_block(&tip);
+        AddressIndex::new(blockchain.clone()).disconnect_block(&tip);
+        let blockchain = Blockchain::create_blockchain_with_indexes(db.clone(), address.as_str(), true, true);
+        let txindex = TxIndex::new(blockchain.clone());
+        let addrindex = AddressIndex::new(blockchain.clone());
+        assert!(txindex.is_synced());
+        assert_eq!(txindex.get_location(coinbase.get_id()), Some((String::from(tip.get_hash()), 0)));
+        assert!(addrindex.is_synced());
+        assert_eq!(addrindex.get_history(hash.as_slice()), history);
+
+        // 未开启的索引不重建
+        TxIndex::new(blockchain.clone()).disconnect_block(&tip);
+        let blockchain = Blockchain::create_blockchain_with_indexes(db, address.as_str(), false, true);
+        assert!(!TxIndex::new(blockchain).is_synced());
+    }
+
+    #[test]
+    fn test_resume_reorganization() {
+        let bits = ProofOfWork::initial_bits();
+        let address = Wallet::new().get_address();
+        let db = temporary_db();
+        let blockchain = Blockchain::create_blockchain_with_db(db.clone(), address.as_str());
+        let genesis_hash = blockchain.get_tip_hash();
+        let a1 = blockchain.mine_block(&[Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO)]);
+        let b1_coinbase = Transaction::new_coinbase_tx_with_extra(address.as_str(), 1, Amount::ZERO, b"b");
+        let b1 = Block::new(genesis_hash, &[b1_coinbase], 1, bits);
+        blockchain.add_block(&b1).unwrap();
+
+        // 模拟保存b2后断开a1时中断, 重新打开时继续切换到b2
+        let b2 = Block::new(String::from(b1.get_hash()), &[Transaction::new_coinbase_tx(address.as_str(), 2, Amount::ZERO)], 2, bits);
+        blockchain.get_block_tree().insert(b2.get_hash(), b2.serialize()).unwrap();
+        blockchain.save_header(b2.get_hash(), b2.get_header(), b2.get_height()).unwrap();
+        blockchain.chain_transaction(|trees| blockchain.disconnect(trees, &a1)).unwrap();
+        blockchain.set_tip_hash(a1.get_pre_block_hash().as_str());
+        assert_eq!(blockchain.get_best_height(), 0);
+
+        let blockchain = Blockchain::create_blockchain_with_db(db.clone(), address.as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+        assert_eq!(blockchain.get_block_hash_by_height(1), Some(String::from(b1.get_hash())));
+        assert!(utxo_set.is_synced());
+        assert_eq!(utxo_set.count_transactions(), 3);
+
+        // 累计工作量更大的c3缺少前一个区块c2的内容, 重新打开时不切换且不中止
+        let c2_coinbase = Transaction::new_coinbase_tx_with_extra(address.as_str(), 2, Amount::ZERO, b"c");
+        let c2 = Block::new(String::from(b1.get_hash()), &[c2_coinbase], 2, bits);
+        let c3 = Block::new(String::from(c2.get_hash()), &[Transaction::new_coinbase_tx(address.as_str(), 3, Amount::ZERO)], 3, bits);
+        blockchain.save_header(c2.get_hash(), c2.get_header(), c2.get_height()).unwrap();
+        blockchain.get_block_tree().insert(c3.get_hash(), c3.serialize()).unwrap();
+        blockchain.save_header(c3.get_hash(), c3.get_header(), c3.get_height()).unwrap();
+        assert_eq!(
+            blockchain.find_fork(&b2, &c3).map(|_| ()),
+            Err(ChainError::Invalid(ValidationError::MissingParent(String::from(c2.get_hash()))))
+        );
+        let blockchain = Blockchain::create_blockchain_with_db(db, address.as_str());
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+    }
+
+    #[test]
+    fn test_check_consistency() {
+        let address = Wallet::new().get_address();
+        let db = temporary_db();
+        let blockchain = Blockchain::create_blockchain_with_db(db.clone(), address.as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let tip = blockchain.mine_block(&[Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO)]);
+        assert!(utxo_set.is_synced());
+        let script_pubkey = crate::Script::from_address(address.as_str()).unwrap();
+        let balance = utxo_set.get_balance(&script_pubkey);
+
+        // 模拟只写入了tip而utxo set停在前一个区块, 重新打开时重建utxo set
+        utxo_set.disconnect_block(&tip);
+        assert!(!utxo_set.is_synced());
+        let blockchain = Blockchain::create_blockchain_with_db(db.clone(), address.as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        assert_eq!(blockchain.get_tip_hash(), tip.get_hash());
+        assert!(utxo_set.is_synced());
+        assert_eq!(utxo_set.get_balance(&script_pubkey), balance);
+        assert_eq!(utxo_set.count_transactions(), 2);
+
+        // 模拟重建在连接创世区块后中断, 以及缺少tip的累计工作量, 重新打开时继续修复
+        let genesis = blockchain.get_block_by_height(0).unwrap();
+        let chain_work = blockchain.get_best_chain_work();
+        db.open_tree(UTXO_TREE).unwrap().clear().unwrap();
+        db.open_tree(UNDO_TREE).unwrap().clear().unwrap();
+        utxo_set.update(&genesis).unwrap();
+        blockchain.get_chain_work_tree().remove(tip.get_hash()).unwrap();
+        assert!(!utxo_set.is_synced());
+        let blockchain = Blockchain::create_blockchain_with_db(db, address.as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        assert!(utxo_set.is_synced());
+        assert_eq!(utxo_set.get_balance(&script_pubkey), balance);
+        assert_eq!(blockchain.get_chain_work_tree().get(tip.get_hash()).unwrap(), Some(chain_work.to_bytes_be().into()));
+    }
+}
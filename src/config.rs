@@ -1,20 +1,67 @@
 // config.rs
 
-use std::{sync::RwLock, collections::HashMap, env};
+use std::{sync::RwLock, collections::HashMap, env, str::FromStr};
 
 use once_cell::sync::Lazy;
 
-pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(||Config::new());
+use crate::amount::{Amount, COIN};
+
+pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(Config::new);
 
 static DEFAULT_NODE_ADDR: &str = "127.0.0.1:2001";
 const NODE_ADDRESS_KEY: &str = "NODE_ADDRESS";
 const MINING_ADDRESS_KEY: &str = "MINING_ADDRESS";
+const REGTEST_KEY: &str = "REGTEST";
+const MAX_DATA_SIZE_KEY: &str = "MAX_DATA_SIZE";
+const RETARGET_INTERVAL_KEY: &str = "RETARGET_INTERVAL";
+const TARGET_BLOCK_TIME_KEY: &str = "TARGET_BLOCK_TIME";
+const HALVING_INTERVAL_KEY: &str = "HALVING_INTERVAL";
+const INITIAL_SUBSIDY_KEY: &str = "INITIAL_SUBSIDY";
+const COINBASE_MATURITY_KEY: &str = "COINBASE_MATURITY";
+const TXINDEX_KEY: &str = "TXINDEX";
+const ADDRINDEX_KEY: &str = "ADDRINDEX";
+
+/// 共识参数的默认值, 同一网络的所有节点必须一致, 可由同名配置覆盖
+struct ConsensusParams {
+    retarget_interval: usize,   // 每隔多少个区块调整一次难度, 至少为2
+    target_block_time: u64,     // 期望出块间隔(毫秒)
+    halving_interval: usize,    // 每隔多少个区块挖矿奖励减半, 至少为1
+    initial_subsidy: Amount,    // 初始挖矿奖励
+    coinbase_maturity: usize,   // coinbase交易的输出至少经过多少个区块才能花费
+}
+
+/// 默认数据输出最多携带的字节数
+const DEFAULT_MAX_DATA_SIZE: usize = 80;
+
+/// 主网共识参数
+const MAIN_PARAMS: ConsensusParams = ConsensusParams {
+    retarget_interval: 10,
+    target_block_time: 10_000,
+    halving_interval: 1000,
+    initial_subsidy: Amount::from_sat(10 * COIN),
+    coinbase_maturity: 10,
+};
+
+/// 本地测试网络的共识参数, 出块更快, 奖励减半和coinbase成熟所需的区块更少
+const REGTEST_PARAMS: ConsensusParams = ConsensusParams {
+    retarget_interval: 10,
+    target_block_time: 1_000,
+    halving_interval: 150,
+    initial_subsidy: Amount::from_sat(50 * COIN),
+    coinbase_maturity: 2,
+};
 
 /// 配置
 pub struct Config {
     inner: RwLock<HashMap<String, String>>,
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Config {
     /// 新建一个配置
     pub fn new() -> Self {
@@ -24,6 +71,22 @@ impl Config {
         }
         let mut map = HashMap::new();
         map.insert(String::from(NODE_ADDRESS_KEY), node_addr);
+        let keys = [
+            REGTEST_KEY,
+            MAX_DATA_SIZE_KEY,
+            RETARGET_INTERVAL_KEY,
+            TARGET_BLOCK_TIME_KEY,
+            HALVING_INTERVAL_KEY,
+            INITIAL_SUBSIDY_KEY,
+            COINBASE_MATURITY_KEY,
+            TXINDEX_KEY,
+            ADDRINDEX_KEY,
+        ];
+        for key in keys {
+            if let Ok(value) = env::var(key) {
+                map.insert(String::from(key), value);
+            }
+        }
 
         Config {
             inner: RwLock::new(map)
@@ -59,4 +122,110 @@ impl Config {
             .unwrap()
             .contains_key(MINING_ADDRESS_KEY)
     }
+
+    /// 是否为本地测试网络, REGTEST为1或true时使用本地测试网络的共识参数
+    pub fn is_regtest(&self) -> bool {
+        self.inner.read()
+            .unwrap()
+            .get(REGTEST_KEY)
+            .is_some_and(|v| v == "1" || v == "true")
+    }
+
+    /// 获取key对应的配置, 未设置时返回default, 无法解析时panic
+    fn get_or<T: FromStr>(&self, key: &str, default: T) -> T {
+        match self.inner.read().unwrap().get(key) {
+            Some(v) => v.parse().unwrap_or_else(|_| panic!("ERROR: {} is not a valid {}", v, key)),
+            None => default,
+        }
+    }
+
+    /// 默认共识参数, REGTEST开启时为本地测试网络的参数
+    fn get_consensus_params(&self) -> &'static ConsensusParams {
+        if self.is_regtest() {
+            return &REGTEST_PARAMS;
+        }
+        &MAIN_PARAMS
+    }
+
+    /// 每隔多少个区块调整一次难度, 由RETARGET_INTERVAL设置, 至少为2
+    pub fn get_retarget_interval(&self) -> usize {
+        let interval = self.get_or(RETARGET_INTERVAL_KEY, self.get_consensus_params().retarget_interval);
+        assert!(interval >= 2, "ERROR: {} must be at least 2", RETARGET_INTERVAL_KEY);
+        interval
+    }
+
+    /// 期望出块间隔(毫秒), 由TARGET_BLOCK_TIME设置, 至少为1
+    pub fn get_target_block_time(&self) -> u64 {
+        let block_time = self.get_or(TARGET_BLOCK_TIME_KEY, self.get_consensus_params().target_block_time);
+        assert!(block_time >= 1, "ERROR: {} must be at least 1", TARGET_BLOCK_TIME_KEY);
+        block_time
+    }
+
+    /// 每隔多少个区块挖矿奖励减半, 由HALVING_INTERVAL设置, 至少为1
+    pub fn get_halving_interval(&self) -> usize {
+        let interval = self.get_or(HALVING_INTERVAL_KEY, self.get_consensus_params().halving_interval);
+        assert!(interval >= 1, "ERROR: {} must be at least 1", HALVING_INTERVAL_KEY);
+        interval
+    }
+
+    /// 初始挖矿奖励, 由INITIAL_SUBSIDY设置, 单位为币
+    pub fn get_initial_subsidy(&self) -> Amount {
+        self.get_or(INITIAL_SUBSIDY_KEY, self.get_consensus_params().initial_subsidy)
+    }
+
+    /// coinbase交易的输出至少经过多少个区块才能花费, 由COINBASE_MATURITY设置, 至少为1
+    pub fn get_coinbase_maturity(&self) -> usize {
+        let maturity = self.get_or(COINBASE_MATURITY_KEY, self.get_consensus_params().coinbase_maturity);
+        assert!(maturity >= 1, "ERROR: {} must be at least 1", COINBASE_MATURITY_KEY);
+        maturity
+    }
+
+    /// 内存池策略: 数据输出最多携带的字节数, 由MAX_DATA_SIZE设置
+    pub fn get_max_data_size(&self) -> usize {
+        self.get_or(MAX_DATA_SIZE_KEY, DEFAULT_MAX_DATA_SIZE)
+    }
+
+    /// 是否维护交易索引, TXINDEX为1或true时开启
+    pub fn is_txindex_enabled(&self) -> bool {
+        self.inner.read()
+            .unwrap()
+            .get(TXINDEX_KEY)
+            .is_some_and(|v| v == "1" || v == "true")
+    }
+
+    /// 是否维护地址索引, ADDRINDEX为1或true时开启
+    pub fn is_addrindex_enabled(&self) -> bool {
+        self.inner.read()
+            .unwrap()
+            .get(ADDRINDEX_KEY)
+            .is_some_and(|v| v == "1" || v == "true")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consensus_params_override() {
+        let config = Config { inner: RwLock::new(HashMap::new()) };
+        assert_eq!(config.get_target_block_time(), MAIN_PARAMS.target_block_time);
+
+        config.inner.write().unwrap().insert(String::from(REGTEST_KEY), String::from("1"));
+        assert_eq!(config.get_target_block_time(), REGTEST_PARAMS.target_block_time);
+
+        // 设置了变量时以变量为准
+        config.inner.write().unwrap().insert(String::from(TARGET_BLOCK_TIME_KEY), String::from("5000"));
+        config.inner.write().unwrap().insert(String::from(RETARGET_INTERVAL_KEY), String::from("20"));
+        assert_eq!(config.get_target_block_time(), 5000);
+        assert_eq!(config.get_retarget_interval(), 20);
+
+        config.inner.write().unwrap().insert(String::from(HALVING_INTERVAL_KEY), String::from("300"));
+        config.inner.write().unwrap().insert(String::from(INITIAL_SUBSIDY_KEY), String::from("12.5"));
+        assert_eq!(config.get_halving_interval(), 300);
+        assert_eq!(config.get_initial_subsidy(), Amount::from_sat(25 * COIN / 2));
+
+        config.inner.write().unwrap().insert(String::from(COINBASE_MATURITY_KEY), String::from("5"));
+        assert_eq!(config.get_coinbase_maturity(), 5);
+    }
 }
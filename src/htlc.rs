@@ -0,0 +1,194 @@
+// htlc.rs
+//
+
+use crate::{
+    Amount,
+    Blockchain,
+    Script,
+    Transaction,
+    UTXOSet,
+    script::{self, Op},
+    transaction::SIGHASH_ALL,
+    wallet::Wallet
+};
+
+/// 哈希时间锁合约(HTLC), 通过P2SH地址锁定资金:
+/// 接收方公布hash的原像即可取走资金, 到达时间锁后退款方可取回资金.
+/// 脚本只能限制退款不早于时间锁, 无法限制取款不晚于时间锁: 到达时间锁后资金未被取走时,
+/// 接收方的取款和退款方的退款都有效, 先被打包的一方得到资金. 接收方须在时间锁之前取款,
+/// 原子交换中后锁定的一方须使用更短的时间锁, 以便在对方退款前用公布的原像取款
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Htlc {
+    hash: Vec<u8>,      // 原像的sha256
+    recipient: Vec<u8>, // 接收方公钥hash
+    refund: Vec<u8>,    // 退款方公钥hash
+    lock_time: u64,     // 退款的时间锁, 区块高度或时间戳(毫秒)
+}
+
+impl Htlc {
+    /// 新建HTLC, recipient和refund须为P2PKH地址
+    pub fn new(hash: &[u8], recipient: &str, refund: &str, lock_time: u64) -> Self {
+        let pub_key_hash = |address: &str| {
+            Script::from_address(address)
+                .and_then(|script| script.get_p2pkh_hash().map(<[u8]>::to_vec))
+                .unwrap_or_else(|| panic!("ERROR: address {} is not valid", address))
+        };
+        Htlc {
+            hash: hash.to_vec(),
+            recipient: pub_key_hash(recipient),
+            refund: pub_key_hash(refund),
+            lock_time,
+        }
+    }
+
+    /// 赎回脚本:
+    /// If Sha256 <hash> EqualVerify Dup Hash160 <recipient>
+    /// Else <lock_time> CheckLockTimeVerify Drop Dup Hash160 <refund>
+    /// EndIf EqualVerify CheckSig
+    pub fn get_script(&self) -> Script {
+        Script::new(vec![
+            Op::If,
+            Op::Sha256,
+            Op::PushData(self.hash.clone()),
+            Op::EqualVerify,
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(self.recipient.clone()),
+            Op::Else,
+            Op::PushData(self.lock_time.to_le_bytes().to_vec()),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(self.refund.clone()),
+            Op::EndIf,
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// 由赎回脚本解析HTLC, 不是HTLC脚本时返回None
+    pub fn from_script(script: &Script) -> Option<Self> {
+        let htlc = Htlc {
+            hash: script.get_push_data(2)?.to_vec(),
+            recipient: script.get_push_data(6)?.to_vec(),
+            refund: script.get_push_data(13)?.to_vec(),
+            lock_time: script::read_u64(script.get_push_data(8)?).ok()?,
+        };
+        if htlc.get_script() != *script {
+            return None;
+        }
+        Some(htlc)
+    }
+
+    /// 锁定资金的P2SH地址
+    pub fn get_address(&self) -> String {
+        crate::convert_script_address(self.get_script().hash().as_slice())
+    }
+
+    pub fn get_hash(&self) -> &[u8] {
+        self.hash.as_slice()
+    }
+
+    pub fn get_lock_time(&self) -> u64 {
+        self.lock_time
+    }
+
+    /// 接收方公布原像, 将HTLC地址上的全部资金转给wallet. 到达时间锁后与退款竞争, 见Htlc
+    pub fn claim(&self, wallet: &Wallet, preimage: &[u8], fee: Amount, utxo_set: &UTXOSet) -> Transaction {
+        self.spend(wallet, vec![Op::PushData(preimage.to_vec()), Op::PushData(vec![1])], None, fee, utxo_set)
+    }
+
+    /// 退款方将HTLC地址上的全部资金转回wallet, 交易的lock_time为HTLC的时间锁, 到达后才能被打包
+    pub fn refund(&self, wallet: &Wallet, fee: Amount, utxo_set: &UTXOSet) -> Transaction {
+        self.spend(wallet, vec![Op::PushData(vec![])], Some(self.lock_time), fee, utxo_set)
+    }
+
+    /// 解锁脚本: <signature> <pub_key> <branch...> <redeem_script>
+    fn spend(&self, wallet: &Wallet, branch: Vec<Op>, lock_time: Option<u64>, fee: Amount, utxo_set: &UTXOSet) -> Transaction {
+        let redeem_script = self.get_script();
+        let from = Script::new_p2sh(redeem_script.hash().as_slice());
+        let (balance, _) = utxo_set.get_balance(&from);
+        if balance == Amount::ZERO {
+            panic!("ERROR: no funds locked in {}", self.get_address())
+        }
+        let amount = balance.checked_sub(fee).expect("Error! not enough funds");
+
+        let mut tx = Transaction::new_unsigned_transaction(&from, wallet.get_address().as_str(), amount, fee, utxo_set);
+        if let Some(lock_time) = lock_time {
+            tx.set_lock_time(lock_time);
+        }
+        for idx in 0..tx.get_vin().len() {
+            let signature = tx.create_signature(idx, &redeem_script, wallet, SIGHASH_ALL);
+            let mut ops = vec![
+                Op::PushData(signature),
+                Op::PushData(wallet.get_public_key().to_vec()),
+            ];
+            ops.extend(branch.iter().cloned());
+            ops.push(Op::PushData(redeem_script.serialize()));
+            tx.set_script_sig(idx, Script::new(ops));
+        }
+        tx
+    }
+
+    /// 从花费该HTLC的交易中提取接收方公布的原像
+    pub fn extract_preimage(&self, tx: &Transaction) -> Option<Vec<u8>> {
+        let redeem_script = self.get_script().serialize();
+        tx.get_vin().iter()
+            .map(|vin| vin.get_script_sig())
+            .filter(|script_sig| script_sig.get_push_data(4) == Some(redeem_script.as_slice()))
+            .filter_map(|script_sig| script_sig.get_push_data(2))
+            .find(|preimage| crate::sha256_digest(preimage) == self.hash)
+            .map(<[u8]>::to_vec)
+    }
+
+    /// 在链上查找接收方公布的原像
+    pub fn find_preimage(&self, blockchain: &Blockchain) -> Option<Vec<u8>> {
+        let mut iterator = blockchain.iterator();
+        while let Some(block) = iterator.next() {
+            let preimage = block.get_transactions().iter().find_map(|tx| self.extract_preimage(tx));
+            if preimage.is_some() {
+                return preimage;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::{mine_to_maturity, new_blockchain}, utils};
+
+    #[test]
+    fn test_from_script() {
+        let (recipient, refund) = (Wallet::new().get_address(), Wallet::new().get_address());
+        let htlc = Htlc::new(&utils::sha256_digest(b"secret"), recipient.as_str(), refund.as_str(), 100);
+        assert_eq!(Htlc::from_script(&htlc.get_script()), Some(htlc.clone()));
+
+        // 多出或缺少操作的脚本不是HTLC脚本
+        let mut ops = htlc.get_script().get_ops().to_vec();
+        ops.push(Op::Drop);
+        assert_eq!(Htlc::from_script(&Script::new(ops.clone())), None);
+        ops.truncate(ops.len() - 2);
+        assert_eq!(Htlc::from_script(&Script::new(ops)), None);
+        assert_eq!(Htlc::from_script(&Script::new_multisig(1, &[b"a".to_vec()]).unwrap()), None);
+    }
+
+    #[test]
+    fn test_extract_preimage() {
+        let (recipient, refund) = (Wallet::new(), Wallet::new());
+        let secret = utils::new_secret();
+        let htlc = Htlc::new(&utils::sha256_digest(secret.as_slice()), recipient.get_address().as_str(), refund.get_address().as_str(), 100);
+        let blockchain = new_blockchain(htlc.get_address().as_str());
+        mine_to_maturity(&blockchain, Wallet::new().get_address().as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+
+        let claim = htlc.claim(&recipient, secret.as_slice(), Amount::ZERO, &utxo_set);
+        assert_eq!(htlc.extract_preimage(&claim), Some(secret.clone()));
+        // 退款交易不公布原像, 其他HTLC的交易不匹配
+        assert_eq!(htlc.extract_preimage(&htlc.refund(&refund, Amount::ZERO, &utxo_set)), None);
+        let other = Htlc::new(htlc.get_hash(), recipient.get_address().as_str(), refund.get_address().as_str(), 200);
+        assert_eq!(other.extract_preimage(&claim), None);
+    }
+}
@@ -1,27 +1,47 @@
 // lib.rs
 //
 
+mod amount;
+pub use amount::{Amount, MAX_MONEY};
+
 mod block;
 mod memory_pool;
-use block::Block;
+pub use block::{Block, BlockHeader};
 
 mod blockchain;
-pub use crate::blockchain::Blockchain;
+pub use crate::blockchain::{Blockchain, BlockchainForwardIterator, ChainError};
+
+mod merkle;
+pub use merkle::{MerkleProof, MerkleTree};
 
 mod proof_of_work;
 use proof_of_work::ProofOfWork;
 
+mod signature;
+pub use signature::{BatchVerifier, SignatureScheme};
+
 mod wallet;
 pub use wallet::convert_address;
+pub use wallet::convert_pub_key_address;
+pub use wallet::convert_script_address;
 pub use wallet::hash_pub_key;
 pub use wallet::validate_address;
 pub use wallet::ADDRESS_CHECKSUM_LEN;
+pub use wallet::Wallet;
 
 mod wallets;
 pub use wallets::Wallets;
 
+mod script;
+pub use script::{is_valid_multisig, Op, Script, ScriptError, MAX_MULTISIG_KEYS};
+
 mod transaction;
-pub use transaction::Transaction;
+mod psbt;
+pub use psbt::PartiallySignedTransaction;
+mod htlc;
+pub use htlc::Htlc;
+pub use transaction::{RelativeLock, Transaction, TxInput, TxOutput};
+pub use transaction::{SIGHASH_ALL, SIGHASH_ANYONECANPAY, SIGHASH_NONE, SIGHASH_SINGLE};
 
 pub mod utils;
 use utils::{base58_encode, base58_decode};
@@ -32,14 +52,27 @@ use utils::ripemd160_digest;
 use utils::ecdsa_p256_sha256_sign_digest;
 use utils::ecdsa_p256_sha256_sign_verify;
 
+mod validation;
+pub use validation::{validate_block, validate_transaction, validate_transaction_with_coins, ValidationError};
+
 mod utxo_set;
-pub use utxo_set::UTXOSet;
+pub use utxo_set::{Coin, CoinsView, CoinsViewCache, UTXOSet};
+
+mod txindex;
+pub use txindex::TxIndex;
+
+mod addrindex;
+pub use addrindex::{AddressIndex, AddressTx};
 
 mod config;
 pub use config::Config;
 pub use config::GLOBAL_CONFIG;
 
 mod node;
+pub use node::{Node, Nodes};
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 
 mod server;
 pub use server::send_tx;
@@ -1,6 +1,8 @@
 // main.rs
 
-use blockchain::{Blockchain, UTXOSet, Wallets, validate_address, utils, Transaction, ADDRESS_CHECKSUM_LEN, send_tx, CENTERAL_NODE, convert_address, hash_pub_key, GLOBAL_CONFIG, Server};
+use std::fs;
+
+use blockchain::{Amount, Blockchain, UTXOSet, Wallets, validate_address, Transaction, send_tx, CENTERAL_NODE, convert_pub_key_address, convert_script_address, GLOBAL_CONFIG, Server, Script, PartiallySignedTransaction, Htlc, SignatureScheme, TxIndex, AddressIndex, utils, is_valid_multisig, MAX_MULTISIG_KEYS};
 use data_encoding::HEXLOWER;
 use log::LevelFilter;
 use structopt::StructOpt;
@@ -22,7 +24,10 @@ enum Command {
         address: String,
     },
     #[structopt(name="create-wallet", about="Create a new wallet")]
-    CreateWallet,
+    CreateWallet {
+        #[structopt(long="scheme", default_value="p256", help="Signature scheme: p256, ed25519 or secp256k1")]
+        scheme: SignatureScheme,
+    },
     #[structopt(name="get-balance", about="Create a new wallet")]
     GetBalance {
         #[structopt(name="address", help="The wallet adddress")]
@@ -37,14 +42,127 @@ enum Command {
         #[structopt(name="to", help="Destination wallet address")]
         to: String,
         #[structopt(name="amount", help="Amount to send")]
-        amount: i32,
+        amount: Amount,
         #[structopt(name="mine", help="Mine immediately on the same node")]
         mine: usize,
+        #[structopt(long="fee", default_value="0", help="Transaction fee paid to the miner")]
+        fee: Amount,
+    },
+    #[structopt(name="get-pubkey", about="Print the public key of a local wallet")]
+    GetPubKey {
+        #[structopt(name="address", help="The wallet adddress")]
+        address: String,
+    },
+    #[structopt(name="create-multisig", about="Create an M-of-N multisig address")]
+    CreateMultisig {
+        #[structopt(name="m", help="Number of signatures required")]
+        m: usize,
+        #[structopt(name="pubkeys", help="Hex encoded public keys of the N owners")]
+        pub_keys: Vec<String>,
+    },
+    #[structopt(name="multisig-send", about="Create a partially signed transaction spending from a multisig address")]
+    MultisigSend {
+        #[structopt(name="redeem-script", help="Hex encoded redeem script of the multisig address")]
+        redeem_script: String,
+        #[structopt(name="to", help="Destination wallet address")]
+        to: String,
+        #[structopt(name="amount", help="Amount to send")]
+        amount: Amount,
+        #[structopt(name="psbt", help="File to write the partially signed transaction to")]
+        psbt: String,
+        #[structopt(long="fee", default_value="0", help="Transaction fee paid to the miner")]
+        fee: Amount,
+    },
+    #[structopt(name="multisig-sign", about="Add a signature to a partially signed transaction")]
+    MultisigSign {
+        #[structopt(name="psbt", help="Partially signed transaction file")]
+        psbt: String,
+        #[structopt(name="address", help="Local wallet address of the co-signer")]
+        address: String,
+    },
+    #[structopt(name="multisig-finalize", about="Finalize a partially signed transaction and send it")]
+    MultisigFinalize {
+        #[structopt(name="psbt", help="Partially signed transaction file")]
+        psbt: String,
+        #[structopt(long="miner", help="Mine immediately on the same node and send reward to ADDRESS")]
+        miner: Option<String>,
+    },
+    #[structopt(name="htlc-secret", about="Generate a random secret and its sha256 hash")]
+    HtlcSecret,
+    #[structopt(name="htlc-create", about="Create a hash time-locked address")]
+    HtlcCreate {
+        #[structopt(name="hash", help="Hex encoded sha256 hash of the secret")]
+        hash: String,
+        #[structopt(name="recipient", help="Address that can claim with the secret")]
+        recipient: String,
+        #[structopt(name="refund", help="Address that can refund after the lock time")]
+        refund: String,
+        #[structopt(name="lock-time", help="Block height, or timestamp in milliseconds if >= 500000000")]
+        lock_time: u64,
+    },
+    #[structopt(name="htlc-claim", about="Claim the funds of a hash time-locked address with the secret")]
+    HtlcClaim {
+        #[structopt(name="redeem-script", help="Hex encoded redeem script of the hash time-locked address")]
+        redeem_script: String,
+        #[structopt(name="address", help="Local wallet address of the recipient")]
+        address: String,
+        #[structopt(name="secret", help="Hex encoded secret")]
+        secret: String,
+        #[structopt(long="fee", default_value="0", help="Transaction fee paid to the miner")]
+        fee: Amount,
+        #[structopt(long="miner", help="Mine immediately on the same node and send reward to ADDRESS")]
+        miner: Option<String>,
+    },
+    #[structopt(name="htlc-refund", about="Refund the funds of a hash time-locked address after the lock time")]
+    HtlcRefund {
+        #[structopt(name="redeem-script", help="Hex encoded redeem script of the hash time-locked address")]
+        redeem_script: String,
+        #[structopt(name="address", help="Local wallet address of the refund owner")]
+        address: String,
+        #[structopt(long="fee", default_value="0", help="Transaction fee paid to the miner")]
+        fee: Amount,
+        #[structopt(long="miner", help="Mine immediately on the same node and send reward to ADDRESS")]
+        miner: Option<String>,
+    },
+    #[structopt(name="htlc-secret-of", about="Find the secret revealed by a claim of a hash time-locked address")]
+    HtlcSecretOf {
+        #[structopt(name="redeem-script", help="Hex encoded redeem script of the hash time-locked address")]
+        redeem_script: String,
+    },
+    #[structopt(name="publish", about="Publish a hash in a data output")]
+    Publish {
+        #[structopt(name="address", help="Local wallet address paying the fee")]
+        address: String,
+        #[structopt(name="hash", help="Hex encoded hash to publish")]
+        hash: String,
+        #[structopt(long="fee", default_value="0", help="Transaction fee paid to the miner")]
+        fee: Amount,
+        #[structopt(long="miner", help="Mine immediately on the same node and send reward to ADDRESS")]
+        miner: Option<String>,
+    },
+    #[structopt(name="find-data", about="Find the block containing a published hash")]
+    FindData {
+        #[structopt(name="hash", help="Hex encoded hash")]
+        hash: String,
+    },
+    #[structopt(name="print-chain", about="Print the blocks of the main chain from genesis")]
+    PrintChain {
+        #[structopt(long="from", default_value="0", help="Height of the first block to print")]
+        from: usize,
+        #[structopt(long="count", help="Number of blocks to print, all blocks up to the tip by default")]
+        count: Option<usize>,
     },
-    #[structopt(name="print-chain", about="Print local wallet address")]
-    PrintChain,
     #[structopt(name="reindex-utxo", about="Reindex utxo set")]
     ReindexUTXO,
+    #[structopt(name="reindex-tx", about="Rebuild the transaction index")]
+    ReindexTx,
+    #[structopt(name="reindex-addr", about="Rebuild the address index")]
+    ReindexAddr,
+    #[structopt(name="history", about="Print the transactions paying to or spending from an address")]
+    History {
+        #[structopt(name="address", help="The wallet or P2SH address")]
+        address: String,
+    },
     #[structopt(name="start-node", about="Start a node")]
     StartNode {
         #[structopt(name="miner", help="Enable mining mode and send reward to ADDRESS")]
@@ -59,12 +177,14 @@ fn main() {
         Command::CreateBlockchain { address } => {
             let blockchain = Blockchain::create_blockchain(address.as_str());
             let utxo_set = UTXOSet::new(blockchain);
-            utxo_set.reindex();
+            if let Err(e) = utxo_set.reindex() {
+                panic!("ERROR: unable to rebuild the utxo set: {}", e)
+            }
             println!("Create blockchain addr: {} Done!", address);
         },
-        Command::CreateWallet => {
+        Command::CreateWallet { scheme } => {
             let mut wallets = Wallets::new();
-            let address = wallets.create_wallet();
+            let address = wallets.create_wallet(scheme);
             println!("Your new address: {}", address);
         },
         Command::GetBalance { address } => {
@@ -73,17 +193,14 @@ fn main() {
                 panic!("ERROR: address {} is not valid", address)
             }
 
-            let payload = utils::base58_decode(address.as_str());
-            let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECKSUM_LEN];
+            let script_pubkey = Script::from_address(address.as_str())
+                .unwrap_or_else(|| panic!("ERROR: address {} is not valid", address));
 
             let blockchain = Blockchain::open_blockchain();
             let utxo_set = UTXOSet::new(blockchain);
-            let utxos = utxo_set.find_utxo(pub_key_hash);
-            let mut balance = 0;
-            for utxo in utxos {
-                balance += utxo.get_cost();
-            }
+            let (balance, immature) = utxo_set.get_balance(&script_pubkey);
             println!("Balance of {}: {}", address, balance);
+            println!("Immature balance of {}: {}", address, immature);
         },
         Command::ListAddresses => {
             let wallets = Wallets::new();
@@ -91,7 +208,7 @@ fn main() {
                 println!("{}", address)
             }
         },
-        Command::Send { from, to, amount, mine } => {
+        Command::Send { from, to, amount, mine, fee } => {
             if !validate_address(from.as_str()) {
                 panic!("ERROR: Sender address is not valid")
             }
@@ -102,40 +219,156 @@ fn main() {
             let utxo_set = UTXOSet::new(blockchain.clone());
             // 创建 UTXO 交易
             let transaction =
-                Transaction::new_utxo_transaction(from.as_str(), to.as_str(), amount, &utxo_set);
+                Transaction::new_utxo_transaction(from.as_str(), to.as_str(), amount, fee, &utxo_set);
 
-            if mine == MINE_TRUE {
-                // 挖矿奖励
-                let coinbase_tx = Transaction::new_coinbase_tx(from.as_str());
-                // 挖新区块
-                let block = blockchain.mine_block(&vec![transaction, coinbase_tx]);
-                // 更新 UTXO 集
-                utxo_set.update(block);
-            } else {
-                send_tx(CENTERAL_NODE, &transaction);
+            let miner = if mine == MINE_TRUE { Some(from.as_str()) } else { None };
+            submit_transaction(&blockchain, transaction, fee, miner);
+            println!("Success!")
+        },
+        Command::GetPubKey { address } => {
+            let wallets = Wallets::new();
+            let wallet = wallets.get_wallet(address.as_str())
+                .unwrap_or_else(|| panic!("ERROR: wallet {} not found", address));
+            println!("{}", HEXLOWER.encode(wallet.get_public_key()));
+        },
+        Command::CreateMultisig { m, pub_keys } => {
+            let pub_keys: Vec<Vec<u8>> = pub_keys.iter()
+                .map(|pub_key| HEXLOWER.decode(pub_key.as_bytes()).expect("ERROR: public key is not valid hex"))
+                .collect();
+            if !is_valid_multisig(m, pub_keys.len()) {
+                panic!("ERROR: invalid {}-of-{} multisig, require 1 <= M <= N <= {}", m, pub_keys.len(), MAX_MULTISIG_KEYS)
             }
+            let redeem_script = Script::new_multisig(m, &pub_keys).expect("the multisig is checked");
+            println!("Multisig address: {}", convert_script_address(redeem_script.hash().as_slice()));
+            println!("Redeem script: {}", HEXLOWER.encode(redeem_script.serialize().as_slice()));
+        },
+        Command::MultisigSend { redeem_script, to, amount, psbt, fee } => {
+            if !validate_address(to.as_str()) {
+                panic!("ERROR: Recipient address is not valid")
+            }
+            let redeem_script = HEXLOWER.decode(redeem_script.as_bytes()).ok()
+                .and_then(|bytes| Script::deserialize(bytes.as_slice()))
+                .expect("ERROR: redeem script is not valid");
+            let blockchain = Blockchain::open_blockchain();
+            let utxo_set = UTXOSet::new(blockchain);
+            let partial = PartiallySignedTransaction::new(&redeem_script, to.as_str(), amount, fee, &utxo_set);
+            fs::write(psbt.as_str(), partial.serialize()).expect("unable to write psbt file");
+            println!("Partially signed transaction written to {}", psbt);
+        },
+        Command::MultisigSign { psbt, address } => {
+            let mut partial = read_psbt(psbt.as_str());
+            let wallets = Wallets::new();
+            let wallet = wallets.get_wallet(address.as_str())
+                .unwrap_or_else(|| panic!("ERROR: wallet {} not found", address));
+            if !partial.sign(wallet) {
+                panic!("ERROR: {} is not an owner of the multisig address", address)
+            }
+            fs::write(psbt.as_str(), partial.serialize()).expect("unable to write psbt file");
+            println!("Signed, complete: {}", partial.is_complete());
+        },
+        Command::MultisigFinalize { psbt, miner } => {
+            let partial = read_psbt(psbt.as_str());
+            let transaction = partial.finalize().expect("ERROR: not enough signatures");
+            let blockchain = Blockchain::open_blockchain();
+            let fee = transaction.get_fee(&blockchain).expect("ERROR: transaction inputs not found");
+            submit_transaction(&blockchain, transaction, fee, miner.as_deref());
             println!("Success!")
         },
-        Command::PrintChain => {
-            let mut block_iterator = Blockchain::open_blockchain().iterator();
-            loop {
-                let option = block_iterator.next();
-                if option.is_none() {
-                    break;
-                }
-                let block = option.unwrap();
+        Command::HtlcSecret => {
+            let secret = utils::new_secret();
+            println!("Secret: {}", HEXLOWER.encode(secret.as_slice()));
+            println!("Hash: {}", HEXLOWER.encode(utils::sha256_digest(secret.as_slice()).as_slice()));
+        },
+        Command::HtlcCreate { hash, recipient, refund, lock_time } => {
+            let hash = HEXLOWER.decode(hash.as_bytes()).expect("ERROR: hash is not valid hex");
+            let htlc = Htlc::new(hash.as_slice(), recipient.as_str(), refund.as_str(), lock_time);
+            println!("HTLC address: {}", htlc.get_address());
+            println!("Redeem script: {}", HEXLOWER.encode(htlc.get_script().serialize().as_slice()));
+        },
+        Command::HtlcClaim { redeem_script, address, secret, fee, miner } => {
+            let htlc = parse_htlc(redeem_script.as_str());
+            let secret = HEXLOWER.decode(secret.as_bytes()).expect("ERROR: secret is not valid hex");
+            let wallets = Wallets::new();
+            let wallet = wallets.get_wallet(address.as_str())
+                .unwrap_or_else(|| panic!("ERROR: wallet {} not found", address));
+            let blockchain = Blockchain::open_blockchain();
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            let transaction = htlc.claim(wallet, secret.as_slice(), fee, &utxo_set);
+            submit_transaction(&blockchain, transaction, fee, miner.as_deref());
+            println!("Success!")
+        },
+        Command::HtlcRefund { redeem_script, address, fee, miner } => {
+            let htlc = parse_htlc(redeem_script.as_str());
+            let wallets = Wallets::new();
+            let wallet = wallets.get_wallet(address.as_str())
+                .unwrap_or_else(|| panic!("ERROR: wallet {} not found", address));
+            let blockchain = Blockchain::open_blockchain();
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            let transaction = htlc.refund(wallet, fee, &utxo_set);
+            submit_transaction(&blockchain, transaction, fee, miner.as_deref());
+            println!("Success!")
+        },
+        Command::HtlcSecretOf { redeem_script } => {
+            let htlc = parse_htlc(redeem_script.as_str());
+            let blockchain = Blockchain::open_blockchain();
+            match htlc.find_preimage(&blockchain) {
+                Some(secret) => println!("Secret: {}", HEXLOWER.encode(secret.as_slice())),
+                None => println!("Secret not revealed yet"),
+            }
+        },
+        Command::Publish { address, hash, fee, miner } => {
+            let data = HEXLOWER.decode(hash.as_bytes()).expect("ERROR: hash is not valid hex");
+            let max_data_size = GLOBAL_CONFIG.get_max_data_size();
+            if data.len() > max_data_size {
+                panic!("ERROR: data exceeds {} bytes", max_data_size)
+            }
+            let wallets = Wallets::new();
+            let wallet = wallets.get_wallet(address.as_str())
+                .unwrap_or_else(|| panic!("ERROR: wallet {} not found", address));
+            let blockchain = Blockchain::open_blockchain();
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            let transaction = Transaction::new_data_transaction(wallet, data.as_slice(), fee, &utxo_set);
+            println!("Transaction: {}", HEXLOWER.encode(transaction.get_id()));
+            submit_transaction(&blockchain, transaction, fee, miner.as_deref());
+            println!("Success!")
+        },
+        Command::FindData { hash } => {
+            let data = HEXLOWER.decode(hash.as_bytes()).expect("ERROR: hash is not valid hex");
+            let blockchain = Blockchain::open_blockchain();
+            match blockchain.find_data(data.as_slice()) {
+                Some((block, tx)) => {
+                    println!("Block: {}", block.get_hash());
+                    println!("Height: {}", block.get_height());
+                    println!("Timestamp: {}", block.get_timestamp());
+                    println!("Transaction: {}", HEXLOWER.encode(tx.get_id()));
+                },
+                None => println!("Not found"),
+            }
+        },
+        Command::PrintChain { from, count } => {
+            let blockchain = Blockchain::open_blockchain();
+            let end = match count {
+                Some(count) => from.saturating_add(count),
+                None => blockchain.get_best_height() + 1,
+            };
+            for block in blockchain.forward_iterator(from, end) {
+                println!("Height: {}", block.get_height());
                 println!("Pre block hash: {}", block.get_pre_block_hash());
                 println!("Cur block hash: {}", block.get_hash());
                 println!("Cur block Timestamp: {}", block.get_timestamp());
+                if let Some(chain_work) = blockchain.get_chain_work(block.get_hash_bytes().as_slice()) {
+                    println!("Cur block chain work: {}", chain_work);
+                }
                 for tx in block.get_transactions() {
                     let cur_txid_hex = HEXLOWER.encode(tx.get_id());
                     println!("- Transaction txid_hex: {}", cur_txid_hex);
 
-                    if tx.is_coinbase() == false {
+                    if !tx.is_coinbase() {
                         for input in tx.get_vin() {
                             let txid_hex = HEXLOWER.encode(input.get_txid());
-                            let pub_key_hash = hash_pub_key(input.get_pub_key());
-                            let address = convert_address(pub_key_hash.as_slice());
+                            let address = input.get_script_sig().get_p2pkh_pub_key()
+                                .and_then(convert_pub_key_address)
+                                .unwrap_or_else(|| format!("{:?}", input.get_script_sig()));
                             println!(
                                 "-- Input txid = {}, vout = {}, from = {}",
                                 txid_hex,
@@ -145,8 +378,11 @@ fn main() {
                         }
                     }
                     for output in tx.get_vout() {
-                        let pub_key_hash = output.get_pub_key_hash();
-                        let address = convert_address(pub_key_hash);
+                        let script_pubkey = output.get_script_pubkey();
+                        let address = match script_pubkey.get_data() {
+                            Some(data) => format!("data {}", HEXLOWER.encode(data)),
+                            None => script_pubkey.get_address().unwrap_or_else(|| format!("{:?}", script_pubkey)),
+                        };
                         println!("-- Output value = {}, to = {}", output.get_cost(), address,)
                     }
                 }
@@ -156,13 +392,61 @@ fn main() {
         Command::ReindexUTXO => {
             let blockchain = Blockchain::open_blockchain();
             let utxo_set = UTXOSet::new(blockchain);
-            utxo_set.reindex();
+            if let Err(e) = utxo_set.reindex() {
+                panic!("ERROR: unable to rebuild the utxo set: {}", e)
+            }
             let count = utxo_set.count_transactions();
             println!("Done! There are {} transactions in the UTXO set.", count);
         },
+        Command::ReindexTx => {
+            let blockchain = Blockchain::open_blockchain();
+            let txindex = TxIndex::new(blockchain.clone());
+            if let Err(e) = txindex.reindex() {
+                panic!("ERROR: unable to rebuild the transaction index: {}", e)
+            }
+            println!("Done! There are {} transactions in the transaction index.", txindex.count_transactions());
+            if !blockchain.is_txindex_enabled() {
+                println!("TXINDEX is not enabled, the index will not be maintained for new blocks.");
+            }
+        },
+        Command::ReindexAddr => {
+            let blockchain = Blockchain::open_blockchain();
+            if let Err(e) = AddressIndex::new(blockchain.clone()).reindex() {
+                panic!("ERROR: unable to rebuild the address index: {}", e)
+            }
+            println!("Done! The address index is rebuilt.");
+            if !blockchain.is_addrindex_enabled() {
+                println!("ADDRINDEX is not enabled, the index will not be maintained for new blocks.");
+            }
+        },
+        Command::History { address } => {
+            let hash = Script::from_address(address.as_str())
+                .and_then(|script| script.get_address_hash().map(<[u8]>::to_vec))
+                .unwrap_or_else(|| panic!("ERROR: address {} is not valid", address));
+            let blockchain = Blockchain::open_blockchain();
+            let addrindex = AddressIndex::new(blockchain.clone());
+            if !addrindex.is_synced() {
+                panic!("ERROR: the address index is not synced, set ADDRINDEX=1 and run reindex-addr");
+            }
+            let tip_height = blockchain.get_best_height();
+            let (mut received, mut sent) = (Amount::ZERO, Amount::ZERO);
+            for tx in addrindex.get_history(hash.as_slice()) {
+                println!(
+                    "- txid = {}, height = {}, confirmations = {}, received = {}, sent = {}",
+                    HEXLOWER.encode(tx.get_txid()),
+                    tx.get_height(),
+                    tip_height - tx.get_height() + 1,
+                    tx.get_received(),
+                    tx.get_sent(),
+                );
+                received = received.checked_add(tx.get_received()).expect("amount overflow");
+                sent = sent.checked_add(tx.get_sent()).expect("amount overflow");
+            }
+            println!("Total received: {}, total sent: {}", received, sent);
+        },
         Command::StartNode { miner } => {
             if let Some(addr) = miner {
-                if validate_address(addr.as_str()) == false {
+                if !validate_address(addr.as_str()) {
                     panic!("Wrong miner address!")
                 }
                 println!("Mining is on. Address to receive rewards: {}", addr);
@@ -175,3 +459,31 @@ fn main() {
     }
 }
 
+/// 由十六进制编码的赎回脚本解析HTLC
+fn parse_htlc(redeem_script: &str) -> Htlc {
+    HEXLOWER.decode(redeem_script.as_bytes()).ok()
+        .and_then(|bytes| Script::deserialize(bytes.as_slice()))
+        .and_then(|script| Htlc::from_script(&script))
+        .expect("ERROR: redeem script is not a valid HTLC")
+}
+
+/// 读取部分签名交易文件
+fn read_psbt(path: &str) -> PartiallySignedTransaction {
+    let bytes = fs::read(path).expect("unable to read psbt file");
+    PartiallySignedTransaction::deserialize(bytes.as_slice())
+        .expect("ERROR: invalid psbt file")
+}
+
+/// 指定miner时在本节点挖新区块打包该交易, 否则发送给中心节点
+fn submit_transaction(blockchain: &Blockchain, transaction: Transaction, fee: Amount, miner: Option<&str>) {
+    match miner {
+        Some(miner) => {
+            // 挖矿奖励, 手续费也归矿工所有
+            let height = blockchain.get_best_height() + 1;
+            let coinbase_tx = Transaction::new_coinbase_tx(miner, height, fee);
+            // 挖新区块, 同时更新 UTXO 集
+            blockchain.mine_block(&[transaction, coinbase_tx]);
+        },
+        None => send_tx(CENTERAL_NODE, &transaction),
+    }
+}
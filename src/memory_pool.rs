@@ -6,7 +6,23 @@ use std::{
     collections::HashMap
 };
 use data_encoding::HEXLOWER;
-use crate::Transaction;
+use crate::{
+    Amount,
+    Blockchain,
+    GLOBAL_CONFIG,
+    Transaction,
+    utxo_set::CoinsViewCache,
+    validation::validate_transaction_with_coins
+};
+
+/// 交易是否满足内存池策略: 数据输出携带的字节数不超过MAX_DATA_SIZE,
+/// 超过的交易不转发也不打包, 但仍可出现在其他节点的区块中
+pub fn is_standard(tx: &Transaction) -> bool {
+    let max_data_size = GLOBAL_CONFIG.get_max_data_size();
+    tx.get_vout().iter()
+        .filter_map(|out| out.get_script_pubkey().get_data())
+        .all(|data| data.len() <= max_data_size)
+}
 
 /// 交易内存池 ( K -> txid_hex, V => Transaction )
 pub struct MemoryPool {
@@ -36,23 +52,106 @@ impl MemoryPool {
         self.inner.read().unwrap().get(txid_hex).cloned()
     }
 
-    pub fn remove(&self, txid_hex: &str) {
-        self.inner.write()
-            .unwrap()
-            .remove(txid_hex);
-    }
-
     pub fn get_all(&self) -> Vec<Transaction> {
         self.inner.read()
             .unwrap()
-            .iter()
-            .map(|(_, v)|v.clone())
+            .values()
+            .cloned()
             .collect()
     }
 
     pub fn len(&self) -> usize {
         self.inner.read().unwrap().len()
     }
+
+    /// 执行了内存池中所有交易的utxo set视图, 新交易可以花费内存池中交易的输出, 但不能与其花费同一输出
+    pub fn get_coins_view<'a>(&self, blockchain: &'a Blockchain) -> CoinsViewCache<'a> {
+        let height = blockchain.get_best_height() + 1;
+        let mut coins = CoinsViewCache::new(blockchain);
+        for tx in self.inner.read().unwrap().values() {
+            coins.apply(tx, height);
+        }
+        coins
+    }
+
+    /// 连接区块后移除已打包或与链上交易花费同一输出的交易, 以及花费这些交易输出的交易
+    pub fn remove_conflicts(&self, blockchain: &Blockchain) {
+        let height = blockchain.get_best_height() + 1;
+        let time = blockchain.get_tip_median_time_past();
+        let mut inner = self.inner.write().unwrap();
+        // 依次在utxo set视图中校验, 父交易通过后子交易才能通过
+        let mut coins = CoinsViewCache::new(blockchain);
+        let mut pending: Vec<&Transaction> = inner.values().collect();
+        let mut valid = vec![];
+        loop {
+            let count = valid.len();
+            pending.retain(|tx| {
+                if validate_transaction_with_coins(blockchain, &coins, tx, height, time).is_err() {
+                    return true;
+                }
+                coins.apply(tx, height);
+                valid.push(HEXLOWER.encode(tx.get_id()));
+                false
+            });
+            if valid.len() == count {
+                break;
+            }
+        }
+        inner.retain(|txid_hex, _| valid.contains(txid_hex));
+    }
+
+    /// 按手续费率(手续费/交易字节数)从高到低选取交易, 总字节数不超过max_size, 返回选取的交易及手续费总额.
+    /// 交易按花费顺序排列, 其输入所引用的内存池交易须先被选取.
+    /// 不满足内存池策略, 在下一个区块中校验不通过或与已选交易花费同一输出的交易不会被选取
+    pub fn select_by_fee_rate(&self, blockchain: &Blockchain, max_size: usize) -> (Vec<Transaction>, Amount) {
+        let height = blockchain.get_best_height() + 1;
+        let time = blockchain.get_tip_median_time_past();
+        // 按手续费率排序时, 输入可以引用内存池中任一交易的输出
+        let mut outputs = CoinsViewCache::new(blockchain);
+        let txs = self.get_all();
+        for tx in &txs {
+            outputs.add_outputs(tx, height);
+        }
+        let mut candidates: Vec<(u128, u128, Transaction)> = txs
+            .into_iter()
+            .filter(is_standard)
+            .filter_map(|tx| {
+                let fee = tx.get_fee(&outputs)?;
+                Some((fee.as_sat() as u128, tx.get_size() as u128, tx))
+            })
+            .collect();
+        // a/b > c/d <=> a*d > c*b, 避免浮点数比较
+        candidates.sort_by(|(fee_a, size_a, _), (fee_b, size_b, _)| {
+            (fee_b * size_a).cmp(&(fee_a * size_b))
+        });
+
+        // 依次在区块的utxo set视图中校验, 直到没有可选取的交易; 依赖未选取交易的交易留到下一轮
+        let mut coins = CoinsViewCache::new(blockchain);
+        let mut total_size = 0;
+        let mut fees = Amount::ZERO;
+        let mut selected = vec![];
+        loop {
+            let count = selected.len();
+            candidates.retain(|(_, size, tx)| {
+                if total_size + *size as usize > max_size {
+                    return false;
+                }
+                let fee = match validate_transaction_with_coins(blockchain, &coins, tx, height, time) {
+                    Ok(fee) => fee,
+                    Err(_) => return true,
+                };
+                coins.apply(tx, height);
+                total_size += *size as usize;
+                fees = fees.checked_add(fee).expect("fees overflow");
+                selected.push(tx.clone());
+                false
+            });
+            if selected.len() == count {
+                break;
+            }
+        }
+        (selected, fees)
+    }
 }
 
 /// 传输中的块, 用于来跟踪已下载的块, 这能够实现从不同的节点下载块.
@@ -101,5 +200,124 @@ impl BlockInTransit {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::{
+        GLOBAL_CONFIG,
+        UTXOSet,
+        ValidationError,
+        test_utils::{mine_blocks, mine_to_maturity, new_blockchain},
+        utxo_set::CoinsView,
+        transaction::TxOutput,
+        validation::validate_transaction,
+        wallet::Wallet
+    };
+
+    #[test]
+    fn test_select_by_fee_rate() {
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let blockchain = new_blockchain(address.as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        // 等待创世区块的coinbase成熟
+        mine_to_maturity(&blockchain, address.as_str());
+
+        // 两笔交易花费同一个输出, 只选取手续费高的一笔
+        let low = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(1), Amount::from_sat(1), &utxo_set);
+        let high = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(1), Amount::from_sat(2), &utxo_set);
+        let pool = MemoryPool::new();
+        pool.add(low.clone());
+        pool.add(high.clone());
+        let (selected, fees) = pool.select_by_fee_rate(&blockchain, usize::MAX);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].get_id(), high.get_id());
+        assert_eq!(fees, Amount::from_sat(2));
+
+        // 超过区块大小限制的交易不会被选取
+        assert!(pool.select_by_fee_rate(&blockchain, high.get_size() - 1).0.is_empty());
+
+        // 数据超过MAX_DATA_SIZE的交易不满足内存池策略
+        let data = vec![1; GLOBAL_CONFIG.get_max_data_size() + 1];
+        let data = Transaction::new_data_transaction(&wallet, data.as_slice(), Amount::from_sat(3), &utxo_set);
+        assert!(!is_standard(&data));
+        let pool = MemoryPool::new();
+        pool.add(data);
+        assert!(pool.select_by_fee_rate(&blockchain, usize::MAX).0.is_empty());
+    }
+
+    #[test]
+    fn test_spend_unconfirmed_output() {
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let blockchain = new_blockchain(address.as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        mine_to_maturity(&blockchain, address.as_str());
+        let height = blockchain.get_best_height() + 1;
+        let time = blockchain.get_tip_median_time_past();
 
+        // 子交易花费内存池中父交易的找零, 手续费率高于父交易
+        let parent = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(1), Amount::from_sat(1), &utxo_set);
+        let pool = MemoryPool::new();
+        pool.add(parent.clone());
+        let change = parent.get_vout()[1].get_cost();
+        let mut child = Transaction::new_with_outputs(vec![TxOutput::new(change.checked_sub(Amount::from_sat(5)).unwrap(), address.as_str())]);
+        child.add_input(parent.get_id(), 1);
+        child.sign(&pool.get_coins_view(&blockchain), &wallet);
+
+        // 只在叠加了内存池的视图中能找到父交易的输出
+        assert_eq!(
+            validate_transaction(&blockchain, &child, height, time),
+            Err(ValidationError::MissingInput { txid: HEXLOWER.encode(parent.get_id()), outid: 1 })
+        );
+        let coins = pool.get_coins_view(&blockchain);
+        assert_eq!(validate_transaction_with_coins(&blockchain, &coins, &child, height, time), Ok(Amount::from_sat(5)));
+        // 与内存池交易花费同一输出的交易被拒绝
+        let conflict = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(1), Amount::from_sat(2), &utxo_set);
+        assert!(validate_transaction_with_coins(&blockchain, &coins, &conflict, height, time).is_err());
+
+        // 父交易先于子交易被选取, 两者可以打包在同一区块
+        pool.add(child.clone());
+        let (mut selected, fees) = pool.select_by_fee_rate(&blockchain, usize::MAX);
+        assert_eq!(selected.iter().map(Transaction::get_id).collect::<Vec<_>>(), vec![parent.get_id(), child.get_id()]);
+        assert_eq!(fees, Amount::from_sat(6));
+        selected.push(Transaction::new_coinbase_tx(address.as_str(), height, fees));
+        let block = blockchain.mine_block(&selected);
+        assert_eq!(blockchain.get_tip_hash(), block.get_hash());
+        assert!(utxo_set.get_coin(parent.get_id(), 1).is_none());
+        assert!(utxo_set.get_coin(child.get_id(), 0).is_some());
+    }
+
+    #[test]
+    fn test_remove_conflicts() {
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let blockchain = new_blockchain(address.as_str());
+        // 等待高度0到2的coinbase成熟
+        mine_blocks(&blockchain, address.as_str(), GLOBAL_CONFIG.get_coinbase_maturity() + 1);
+        let spend = |txid: &[u8], value: Amount, coins: &dyn CoinsView| {
+            let mut tx = Transaction::new_with_outputs(vec![TxOutput::new(value, address.as_str())]);
+            tx.add_input(txid, 0);
+            tx.sign(coins, &wallet);
+            tx
+        };
+        let coinbase = |height| blockchain.get_block_by_height(height).unwrap().get_transactions()[0].clone();
+        let value = coinbase(0).get_vout()[0].get_cost().checked_sub(Amount::from_sat(2)).unwrap();
+
+        let pool = MemoryPool::new();
+        let parent = spend(coinbase(0).get_id(), value, &blockchain);
+        pool.add(parent.clone());
+        let child = spend(parent.get_id(), value.checked_sub(Amount::from_sat(1)).unwrap(), &pool.get_coins_view(&blockchain));
+        pool.add(child);
+        let mined = spend(coinbase(1).get_id(), value, &blockchain);
+        pool.add(mined.clone());
+        let unrelated = spend(coinbase(2).get_id(), value, &blockchain);
+        pool.add(unrelated.clone());
+
+        // 区块中的交易与parent花费同一输出, parent, 花费parent输出的child和已打包的交易被移除
+        let conflict = spend(coinbase(0).get_id(), Amount::from_sat(1), &blockchain);
+        let height = blockchain.get_best_height() + 1;
+        blockchain.mine_block(&[conflict, mined, Transaction::new_coinbase_tx(address.as_str(), height, Amount::ZERO)]);
+        pool.remove_conflicts(&blockchain);
+        let remaining = pool.get_all();
+        assert_eq!(remaining.iter().map(Transaction::get_id).collect::<Vec<_>>(), vec![unrelated.get_id()]);
+    }
 }
@@ -0,0 +1,137 @@
+// merkle.rs
+//
+
+use serde::{Deserialize, Serialize};
+
+/// Merkle树
+/// 叶子节点为交易id, 父节点为 sha256(左子节点 + 右子节点), 某层节点数为奇数时复制最后一个节点.
+/// 因此末尾重复交易的列表与原列表的根相同, 区块校验时须拒绝重复的交易
+pub struct MerkleTree {
+    levels: Vec<Vec<Vec<u8>>>,  // 从叶子层到根节点的各层节点
+}
+
+impl MerkleTree {
+    /// 根据叶子节点构建Merkle树
+    pub fn new(leaves: &[Vec<u8>]) -> Self {
+        let mut levels = vec![leaves.to_vec()];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let parents = level.chunks(2)
+                .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+                .collect();
+            levels.push(parents);
+        }
+
+        MerkleTree { levels }
+    }
+
+    /// 获取Merkle根, 没有叶子节点时为全0
+    pub fn root(&self) -> Vec<u8> {
+        match self.levels.last().unwrap().first() {
+            Some(root) => root.clone(),
+            None => vec![0; 32],
+        }
+    }
+
+    /// 生成第index个叶子节点的Merkle路径
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        let leaf = self.levels[0].get(index)?.clone();
+        let mut branch = vec![];
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = level.get(position ^ 1).unwrap_or(&level[position]);
+            branch.push(sibling.clone());
+            position >>= 1;
+        }
+
+        Some(MerkleProof { leaf, index, branch })
+    }
+}
+
+/// Merkle路径, 用于证明某个交易包含在区块中
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    leaf: Vec<u8>,          // 叶子节点, 即交易id
+    index: usize,           // 叶子节点在区块中的位置
+    branch: Vec<Vec<u8>>,   // 从叶子层到根节点路径上的兄弟节点
+}
+
+impl MerkleProof {
+    /// 获取被证明的交易id
+    pub fn get_leaf(&self) -> &[u8] {
+        self.leaf.as_slice()
+    }
+
+    /// 获取交易在区块中的位置
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    /// 获取路径上的兄弟节点
+    pub fn get_branch(&self) -> &[Vec<u8>] {
+        self.branch.as_slice()
+    }
+
+    /// 沿路径计算Merkle根, 并与给定的根比较
+    pub fn verify(&self, root: &[u8]) -> bool {
+        let mut hash = self.leaf.clone();
+        let mut position = self.index;
+        for sibling in &self.branch {
+            hash = if position & 1 == 0 {
+                hash_pair(&hash, sibling)
+            } else {
+                hash_pair(sibling, &hash)
+            };
+            position >>= 1;
+        }
+
+        hash.eq(root)
+    }
+}
+
+/// 计算父节点hash
+fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut data = left.to_vec();
+    data.extend(right);
+    crate::sha256_digest(data.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: u8) -> Vec<Vec<u8>> {
+        (0..n).map(|i| crate::sha256_digest(&[i])).collect()
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let leaves = leaves(3);
+        let left = hash_pair(&leaves[0], &leaves[1]);
+        let right = hash_pair(&leaves[2], &leaves[2]);
+        assert_eq!(MerkleTree::new(&leaves).root(), hash_pair(&left, &right));
+
+        assert_eq!(MerkleTree::new(&leaves[..1]).root(), leaves[0]);
+        assert_eq!(MerkleTree::new(&[]).root(), vec![0; 32]);
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        for n in 1..=7 {
+            let leaves = leaves(n);
+            let tree = MerkleTree::new(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert_eq!(proof.get_leaf(), leaf.as_slice());
+                assert!(proof.verify(tree.root().as_slice()));
+            }
+            assert!(tree.proof(leaves.len()).is_none());
+        }
+
+        // 位置错误的路径不能通过校验
+        let tree = MerkleTree::new(&leaves(4));
+        let proof = tree.proof(1).unwrap();
+        let forged = MerkleProof { index: 0, ..proof };
+        assert!(!forged.verify(tree.root().as_slice()));
+    }
+}
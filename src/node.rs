@@ -8,7 +8,7 @@ pub struct Node {
 }
 
 impl Node {
-    ///
+    /// 新建一个节点
     fn new(addr: String) -> Node {
         Node{ addr }
     }
@@ -17,6 +17,7 @@ impl Node {
         self.addr.clone()
     }
 
+    /// 解析节点的套接字地址
     pub fn parse_socket_addr(&self) -> SocketAddr {
         self.addr.parse().unwrap()
     }
@@ -27,6 +28,12 @@ pub struct Nodes {
     inner: RwLock<Vec<Node>>,
 }
 
+impl Default for Nodes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Nodes {
     pub fn new() -> Self {
         Nodes { inner: RwLock::new(vec![]) }
@@ -34,7 +41,7 @@ impl Nodes {
 
     pub fn add_node(&self, addr: String) {
         let mut inner = self.inner.write().unwrap();
-        if let None = inner.iter().position(|n| n.get_addr().eq(addr.as_str())) {
+        if inner.iter().position(|n| n.get_addr().eq(addr.as_str())).is_none() {
             inner.push(Node::new(addr));
         }
     }
@@ -62,12 +69,16 @@ impl Nodes {
         self.inner.read().unwrap().len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().unwrap().is_empty()
+    }
+
     pub fn node_is_known(&self, addr: &str) -> bool {
         let inner = self.inner.read().unwrap();
-        if let Some(_) = inner.iter().position(|x| x.get_addr().eq(addr)) {
+        if inner.iter().position(|x| x.get_addr().eq(addr)).is_some() {
             return true;
         }
-        return false;
+        false
     }
 }
 
@@ -1,37 +1,58 @@
 // proof_of_work.rs
 //
 
-use std::borrow::Borrow;
 use data_encoding::HEXLOWER;
 
-use crate::Block;
-use num_bigint::{BigInt, Sign};
+use crate::block::BlockHeader;
+use num_bigint::{BigInt, BigUint, Sign};
 
-const TARGET_BITS: i32 = 8;             //
+const TARGET_BITS: i32 = 8;             // 创世区块目标值的前导0位数
+const MIN_TARGET_BITS: i32 = 4;         // 目标值前导0位数的下限, 即难度下限
+const MAX_ADJUST_FACTOR: u64 = 4;       // 每次调整难度的最大倍数
 const MAX_NONCE:   i64 = i64::MAX;        //
 
 /// 工作量证明
 pub struct ProofOfWork {
-    block: Block,       // 该证明所在的区块
-    target: BigInt,     // 证明的目标值
+    header: BlockHeader,    // 该证明所在的区块头
+    target: BigInt,         // 证明的目标值
 }
 
 impl ProofOfWork {
-    /// 新建一个pow
-    pub fn new(block: Block) -> Self {
-        let mut target = BigInt::from(1);
-        target <<= 256 - TARGET_BITS;
+    /// 新建一个pow, 目标值由区块头中的bits给出
+    pub fn new(header: BlockHeader) -> Self {
+        let target = BigInt::from(bits_to_target(header.get_bits()));
 
-        ProofOfWork{block, target}
+        ProofOfWork{header, target}
+    }
+
+    /// 创世区块的bits
+    pub fn initial_bits() -> u32 {
+        target_to_bits(&(BigUint::from(1u8) << (256 - TARGET_BITS)))
+    }
+
+    /// 根据上一个窗口的实际耗时调整bits
+    /// 新目标值 = 旧目标值 * 实际耗时 / 期望耗时, 每次最多调整MAX_ADJUST_FACTOR倍, 且不低于难度下限
+    pub fn retarget(bits: u32, actual_timespan: u64, expected_timespan: u64) -> u32 {
+        let expected_timespan = expected_timespan.max(1);
+        let actual_timespan = actual_timespan.clamp(
+            expected_timespan / MAX_ADJUST_FACTOR,
+            expected_timespan * MAX_ADJUST_FACTOR,
+        );
+        let max_target = BigUint::from(1u8) << (256 - MIN_TARGET_BITS);
+        let target = bits_to_target(bits) * actual_timespan / expected_timespan;
+
+        target_to_bits(&target.min(max_target))
     }
 
     /// 准备运行数据
     fn prepare_data(&self, nonce: i64) -> Vec<u8> {
         let mut datas = vec![];
-        datas.extend(self.block.get_pre_block_hash().as_bytes());
-        datas.extend(self.block.hash_transactions());
-        datas.extend(self.block.get_timestamp().to_be_bytes());
-        datas.extend(TARGET_BITS.to_be_bytes());
+        datas.extend(self.header.get_version().to_be_bytes());
+        datas.extend(self.header.get_pre_block_hash().as_bytes());
+        datas.extend(self.header.get_merkle_root());
+        datas.extend(self.header.get_witness_root());
+        datas.extend(self.header.get_timestamp().to_be_bytes());
+        datas.extend(self.header.get_bits().to_be_bytes());
         datas.extend(nonce.to_be_bytes());
 
         datas
@@ -55,13 +76,67 @@ impl ProofOfWork {
             }
         }
 
-        println!("");
-        return (nonce, HEXLOWER.encode(hash.as_slice()));
+        println!();
+        (nonce, HEXLOWER.encode(hash.as_slice()))
+    }
+
+    /// 区块的工作量, 即找到满足目标值的hash平均所需的计算次数: 2^256 / (target + 1)
+    pub fn get_work(&self) -> BigUint {
+        let target = self.target.to_biguint().expect("the target is positive");
+        (BigUint::from(1u8) << 256) / (target + 1u8)
+    }
+
+    /// 计算区块头在其nonce下的hash
+    pub fn get_hash(&self) -> String {
+        let data = self.prepare_data(self.header.get_nonce());
+        HEXLOWER.encode(crate::sha256_digest(data.as_slice()).as_slice())
+    }
+
+    /// 验证区块头的hash是否满足工作量证明
+    pub fn validate(&self) -> bool {
+        let data = self.prepare_data(self.header.get_nonce());
+        let hash = crate::sha256_digest(data.as_slice());
+        let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
+
+        hash_int < self.target
     }
 }
 
+/// 紧凑格式bits -> 目标值
+/// bits的高8位为目标值的字节数, 低23位为目标值的最高3个字节
+pub fn bits_to_target(bits: u32) -> BigUint {
+    let size = bits >> 24;
+    let mantissa = BigUint::from(bits & 0x007f_ffff);
+    if size <= 3 {
+        mantissa >> (8 * (3 - size))
+    } else {
+        mantissa << (8 * (size - 3))
+    }
+}
+
+/// 目标值 -> 紧凑格式bits, 只保留目标值的最高3个字节
+pub fn target_to_bits(target: &BigUint) -> u32 {
+    let bytes = target.to_bytes_be();
+    if bytes == [0] {
+        return 0;
+    }
+    let mut size = bytes.len() as u32;
+    let mut mantissa = bytes.iter()
+        .chain([0u8; 3].iter())
+        .take(3)
+        .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+    // 最高位为符号位, 需要多占用一个字节
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    (size << 24) | mantissa
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
     use num_bigint::BigInt;
 
     #[test]
@@ -74,4 +149,32 @@ mod tests {
         let b = BigInt::from_signed_bytes_be(vec.as_slice());
         println!("{}", b)
     }
+
+    #[test]
+    fn test_bits_target_round_trip() {
+        let bits = ProofOfWork::initial_bits();
+        assert_eq!(bits, 0x2001_0000);
+        assert_eq!(bits_to_target(bits), BigUint::from(1u8) << 248);
+        assert_eq!(target_to_bits(&BigUint::from(0x80u8)), 0x0200_8000);
+        assert_eq!(bits_to_target(0x0200_8000), BigUint::from(0x80u8));
+    }
+
+    #[test]
+    fn test_retarget() {
+        let bits = ProofOfWork::initial_bits();
+        let target = bits_to_target(bits);
+
+        // 出块耗时等于期望, 难度不变
+        assert_eq!(ProofOfWork::retarget(bits, 100, 100), bits);
+        // 出块快一倍, 目标值减半
+        assert_eq!(bits_to_target(ProofOfWork::retarget(bits, 50, 100)), &target / 2u8);
+        // 最多调整4倍
+        assert_eq!(bits_to_target(ProofOfWork::retarget(bits, 1, 100)), &target / 4u8);
+        // 目标值不超过难度下限
+        let easy_bits = target_to_bits(&(BigUint::from(1u8) << 251));
+        assert_eq!(
+            bits_to_target(ProofOfWork::retarget(easy_bits, 1000, 100)),
+            BigUint::from(1u8) << (256 - MIN_TARGET_BITS)
+        );
+    }
 }
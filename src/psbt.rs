@@ -0,0 +1,170 @@
+// psbt.rs
+//
+
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Amount,
+    Script,
+    Transaction,
+    UTXOSet,
+    script::Op,
+    transaction::SIGHASH_ALL,
+    wallet::Wallet
+};
+
+/// 部分签名交易, 花费多签地址的资金
+/// 由任一持有人创建, 各持有人依次添加签名, 签名数量足够后生成可广播的交易
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PartiallySignedTransaction {
+    tx: Transaction,                            // 未签名的交易
+    redeem_script: Script,                      // 多签赎回脚本
+    signatures: Vec<HashMap<Vec<u8>, Vec<u8>>>, // 每个输入已收集的签名, 公钥 -> 签名
+}
+
+impl PartiallySignedTransaction {
+    /// 从redeem_script对应的P2SH地址向to转账amount, 找零回到该P2SH地址
+    pub fn new(redeem_script: &Script, to: &str, amount: Amount, fee: Amount, utxo_set: &UTXOSet) -> Self {
+        if redeem_script.get_multisig().is_none() {
+            panic!("ERROR: redeem script is not a multisig script")
+        }
+        let from = Script::new_p2sh(redeem_script.hash().as_slice());
+        let tx = Transaction::new_unsigned_transaction(&from, to, amount, fee, utxo_set);
+        let signatures = vec![HashMap::new(); tx.get_vin().len()];
+
+        PartiallySignedTransaction {
+            tx,
+            redeem_script: redeem_script.clone(),
+            signatures,
+        }
+    }
+
+    /// 获取未签名的交易
+    pub fn get_transaction(&self) -> &Transaction {
+        &self.tx
+    }
+
+    /// 获取多签赎回脚本
+    pub fn get_redeem_script(&self) -> &Script {
+        &self.redeem_script
+    }
+
+    /// 使用钱包私钥对每个输入签名, 钱包公钥不在赎回脚本中时返回false
+    pub fn sign(&mut self, wallet: &Wallet) -> bool {
+        let (_, pub_keys) = self.redeem_script.get_multisig().unwrap();
+        if !pub_keys.contains(&wallet.get_public_key()) {
+            return false;
+        }
+        for (idx, signatures) in self.signatures.iter_mut().enumerate() {
+            let signature = self.tx.create_signature(idx, &self.redeem_script, wallet, SIGHASH_ALL);
+            signatures.insert(wallet.get_public_key().to_vec(), signature);
+        }
+        true
+    }
+
+    /// 合并其他持有人对同一交易添加的签名
+    pub fn combine(&mut self, other: &PartiallySignedTransaction) {
+        if self.tx.get_id() != other.tx.get_id() {
+            panic!("ERROR: cannot combine different transactions")
+        }
+        for (signatures, other) in self.signatures.iter_mut().zip(&other.signatures) {
+            signatures.extend(other.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+    }
+
+    /// 每个输入都有m个赎回脚本中公钥的签名时才能生成最终交易
+    pub fn is_complete(&self) -> bool {
+        let (m, _) = self.redeem_script.get_multisig().unwrap();
+        (0..self.signatures.len()).all(|idx| self.member_signatures(idx).len() >= m)
+    }
+
+    /// 第idx个输入收集的签名中, 由赎回脚本中公钥生成的签名, 按公钥顺序排列
+    fn member_signatures(&self, idx: usize) -> Vec<&Vec<u8>> {
+        let (_, pub_keys) = self.redeem_script.get_multisig().unwrap();
+        pub_keys.iter()
+            .filter_map(|pub_key| self.signatures[idx].get(*pub_key))
+            .collect()
+    }
+
+    /// 生成可广播的交易, 签名按赎回脚本中的公钥顺序排列, 签名不足时返回None
+    pub fn finalize(&self) -> Option<Transaction> {
+        if !self.is_complete() {
+            return None;
+        }
+        let (m, _) = self.redeem_script.get_multisig().unwrap();
+        let mut tx = self.tx.clone();
+        for idx in 0..self.signatures.len() {
+            let mut ops: Vec<Op> = self.member_signatures(idx)
+                .into_iter()
+                .take(m)
+                .map(|signature| Op::PushData(signature.clone()))
+                .collect();
+            ops.push(Op::PushData(self.redeem_script.serialize()));
+            tx.set_script_sig(idx, Script::new(ops));
+        }
+        Some(tx)
+    }
+
+    /// 序列化, 用于在持有人之间传递
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// 反序列化, 数据不合法或赎回脚本不是多签脚本时返回None
+    pub fn deserialize(bytes: &[u8]) -> Option<Self> {
+        let psbt: PartiallySignedTransaction = bincode::deserialize(bytes).ok()?;
+        if psbt.redeem_script.get_multisig().is_none() || psbt.signatures.len() != psbt.tx.get_vin().len() {
+            return None;
+        }
+        Some(psbt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{convert_script_address, test_utils::{mine_to_maturity, new_blockchain}};
+
+    #[test]
+    fn test_multisig_spend() {
+        let wallets: Vec<Wallet> = (0..3).map(|_| Wallet::new()).collect();
+        let pub_keys: Vec<Vec<u8>> = wallets.iter().map(|w| w.get_public_key().to_vec()).collect();
+        let redeem_script = Script::new_multisig(2, &pub_keys).unwrap();
+        let address = convert_script_address(redeem_script.hash().as_slice());
+
+        // 创世区块奖励给多签地址, 等待成熟
+        let blockchain = new_blockchain(address.as_str());
+        mine_to_maturity(&blockchain, Wallet::new().get_address().as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+
+        let to = Wallet::new().get_address();
+        let mut psbt = PartiallySignedTransaction::new(&redeem_script, to.as_str(), Amount::from_sat(5), Amount::ZERO, &utxo_set);
+        assert!(!psbt.sign(&Wallet::new()));
+        assert!(psbt.sign(&wallets[2]));
+        assert!(psbt.finalize().is_none());
+
+        // 另一个持有人签名后合并
+        let mut cosigned = PartiallySignedTransaction::deserialize(psbt.serialize().as_slice()).unwrap();
+        assert!(cosigned.sign(&wallets[0]));
+        psbt.combine(&cosigned);
+        let tx = psbt.finalize().unwrap();
+        assert!(tx.verify(&blockchain));
+
+        // 只有一个签名的交易校验失败
+        let mut single = PartiallySignedTransaction::deserialize(psbt.serialize().as_slice()).unwrap();
+        single.signatures[0].remove(wallets[0].get_public_key());
+        single.signatures[0].insert(wallets[1].get_public_key().to_vec(), vec![0; 64]);
+        assert!(!single.finalize().unwrap().verify(&blockchain));
+
+        // 不在赎回脚本中的公钥的签名不计入
+        let mut outsider = PartiallySignedTransaction::deserialize(psbt.serialize().as_slice()).unwrap();
+        outsider.signatures[0].remove(wallets[0].get_public_key());
+        outsider.signatures[0].insert(Wallet::new().get_public_key().to_vec(), vec![0; 64]);
+        assert!(!outsider.is_complete());
+        assert!(outsider.finalize().is_none());
+
+        // 不合法的数据
+        assert!(PartiallySignedTransaction::deserialize(b"not a psbt").is_none());
+    }
+}
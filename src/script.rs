@@ -0,0 +1,685 @@
+// script.rs
+//
+
+use std::{
+    error::Error,
+    fmt
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    wallet::{self, SCRIPT_VERSION},
+    SignatureScheme
+};
+
+/// 脚本执行时栈的最大深度
+const MAX_STACK_SIZE: usize = 1000;
+/// 多签脚本中公钥的最大个数
+pub const MAX_MULTISIG_KEYS: usize = 16;
+/// 时间锁小于该值时表示区块高度, 否则表示时间戳(毫秒)
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+
+/// 脚本操作码
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    /// 将数据压入栈
+    PushData(Vec<u8>),
+    /// 复制栈顶元素
+    Dup,
+    /// 弹出栈顶元素
+    Drop,
+    /// 栈顶元素替换为其 ripemd160(sha256(x))
+    Hash160,
+    /// 栈顶元素替换为其 sha256(x)
+    Sha256,
+    /// 弹出两个元素, 相等时压入true, 否则压入false
+    Equal,
+    /// Equal + Verify
+    EqualVerify,
+    /// 弹出栈顶元素, 为false时脚本失败
+    Verify,
+    /// 弹出公钥和签名, 签名合法时压入true, 否则压入false
+    CheckSig,
+    /// CheckSig + Verify
+    CheckSigVerify,
+    /// 依次弹出n, n个公钥, m, m个签名, m个签名按公钥顺序分别由其中m个公钥签发时压入true, 否则压入false
+    CheckMultiSig,
+    /// CheckMultiSig + Verify
+    CheckMultiSigVerify,
+    /// 弹出栈顶元素, 为true时执行其后到Else的操作码, 否则执行Else到EndIf之间的操作码
+    If,
+    /// 与If配对, 切换执行的分支
+    Else,
+    /// 结束If分支
+    EndIf,
+    /// 栈顶元素为绝对时间锁, 交易的lock_time未到达该高度或时间则脚本失败, 不弹出栈顶元素
+    CheckLockTimeVerify,
+    /// 栈顶元素为相对时间锁(sequence编码), 输入的sequence未满足则脚本失败, 不弹出栈顶元素
+    CheckSequenceVerify,
+    /// 脚本立即失败, 用于标记不可花费的数据输出
+    Return,
+    /// 弹出栈顶的类型标签, 新的栈顶元素(公钥)的类型标签与之不符则脚本失败
+    CheckKeyTypeVerify,
+}
+
+/// 脚本执行失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    /// 解锁脚本只能包含PushData
+    UnlockingNotPushOnly,
+    /// 栈中元素不足
+    StackUnderflow,
+    /// 栈深度超过上限
+    StackOverflow,
+    /// Verify类操作码失败
+    VerifyFailed,
+    /// 脚本执行完毕后栈顶不为true
+    EvalFalse,
+    /// 栈中元素不是合法的小整数
+    InvalidNumber,
+    /// 多签的m, n不满足 1 <= m <= n <= MAX_MULTISIG_KEYS
+    InvalidMultisig,
+    /// P2SH赎回脚本无法解析
+    InvalidRedeemScript,
+    /// If, Else, EndIf不配对
+    UnbalancedConditional,
+    /// 未到达时间锁
+    UnsatisfiedLockTime,
+    /// 执行了Return
+    OpReturn,
+    /// 公钥的类型标签与锁定脚本中的不符
+    KeyTypeMismatch,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::UnlockingNotPushOnly => write!(f, "unlocking script is not push only"),
+            ScriptError::StackUnderflow => write!(f, "stack underflow"),
+            ScriptError::StackOverflow => write!(f, "stack size exceeds {}", MAX_STACK_SIZE),
+            ScriptError::VerifyFailed => write!(f, "verify failed"),
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+            ScriptError::InvalidNumber => write!(f, "invalid number"),
+            ScriptError::InvalidMultisig => write!(f, "invalid multisig key count"),
+            ScriptError::InvalidRedeemScript => write!(f, "invalid redeem script"),
+            ScriptError::UnbalancedConditional => write!(f, "unbalanced conditional"),
+            ScriptError::UnsatisfiedLockTime => write!(f, "lock time not satisfied"),
+            ScriptError::OpReturn => write!(f, "return executed"),
+            ScriptError::KeyTypeMismatch => write!(f, "public key type mismatch"),
+        }
+    }
+}
+
+impl Error for ScriptError {}
+
+/// 签名校验, 由交易提供被签名的消息
+pub trait SignatureChecker {
+    /// 校验签名是否由pub_key对应的私钥生成, script_code为当前执行的脚本, 参与签名消息的计算
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8], script_code: &Script) -> bool;
+
+    /// 交易的lock_time是否满足时间锁lock_time, 默认不满足任何时间锁
+    fn check_lock_time(&self, _lock_time: u64) -> bool {
+        false
+    }
+
+    /// 当前输入的sequence是否满足相对时间锁sequence, 默认不满足任何时间锁
+    fn check_sequence(&self, _sequence: u32) -> bool {
+        false
+    }
+}
+
+/// 脚本, 由操作码序列组成
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Script {
+    ops: Vec<Op>,
+}
+
+impl Script {
+    /// 由操作码新建脚本
+    pub fn new(ops: Vec<Op>) -> Self {
+        Script { ops }
+    }
+
+    /// P2PKH锁定脚本: Dup Hash160 <pub_key_hash> EqualVerify CheckSig
+    pub fn new_p2pkh(pub_key_hash: &[u8]) -> Self {
+        Script::new(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(pub_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// 指定签名算法的P2PKH锁定脚本, 锁定脚本中记录公钥的类型标签:
+    /// Dup Hash160 <pub_key_hash> EqualVerify <tag> CheckKeyTypeVerify CheckSig.
+    /// P-256沿用不带标签的new_p2pkh, 与原有输出保持一致
+    pub fn new_p2pkh_with_scheme(scheme: SignatureScheme, pub_key_hash: &[u8]) -> Self {
+        if scheme == SignatureScheme::EcdsaP256 {
+            return Script::new_p2pkh(pub_key_hash);
+        }
+        Script::new(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::PushData(pub_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::PushData(vec![scheme.get_tag()]),
+            Op::CheckKeyTypeVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// P2PKH解锁脚本: <signature> <pub_key>
+    pub fn new_p2pkh_unlock(signature: &[u8], pub_key: &[u8]) -> Self {
+        Script::new(vec![
+            Op::PushData(signature.to_vec()),
+            Op::PushData(pub_key.to_vec()),
+        ])
+    }
+
+    /// M-of-N多签脚本: <m> <pub_key_1> ... <pub_key_n> <n> CheckMultiSig, 不满足1 <= m <= n <= MAX_MULTISIG_KEYS时返回None
+    pub fn new_multisig(m: usize, pub_keys: &[Vec<u8>]) -> Option<Self> {
+        if !is_valid_multisig(m, pub_keys.len()) {
+            return None;
+        }
+        let mut ops = vec![Op::PushData(vec![m as u8])];
+        ops.extend(pub_keys.iter().map(|pub_key| Op::PushData(pub_key.clone())));
+        ops.push(Op::PushData(vec![pub_keys.len() as u8]));
+        ops.push(Op::CheckMultiSig);
+        Some(Script::new(ops))
+    }
+
+    /// P2SH锁定脚本: Hash160 <script_hash> Equal
+    pub fn new_p2sh(script_hash: &[u8]) -> Self {
+        Script::new(vec![
+            Op::Hash160,
+            Op::PushData(script_hash.to_vec()),
+            Op::Equal,
+        ])
+    }
+
+    /// 数据输出的锁定脚本: Return <data>, 不可花费
+    pub fn new_data(data: &[u8]) -> Self {
+        Script::new(vec![Op::Return, Op::PushData(data.to_vec())])
+    }
+
+    /// 由地址生成锁定脚本, 地址非法时返回None
+    pub fn from_address(address: &str) -> Option<Self> {
+        match wallet::decode_address(address)? {
+            (SCRIPT_VERSION, hash) => Some(Script::new_p2sh(hash.as_slice())),
+            (version, hash) => SignatureScheme::from_address_version(version)
+                .map(|scheme| Script::new_p2pkh_with_scheme(scheme, hash.as_slice())),
+        }
+    }
+
+    /// 锁定脚本对应的地址, 非P2PKH或P2SH脚本返回None
+    pub fn get_address(&self) -> Option<String> {
+        if let Some((scheme, hash)) = self.get_p2pkh() {
+            return Some(wallet::convert_p2pkh_address(scheme, hash));
+        }
+        self.get_p2sh_hash().map(wallet::convert_script_address)
+    }
+
+    /// 序列化, Script -> 字节数组
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// 反序列化, 字节数组 -> Script
+    pub fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+
+    /// 脚本hash, 即 ripemd160(sha256(序列化后的脚本))
+    pub fn hash(&self) -> Vec<u8> {
+        crate::hash_pub_key(self.serialize().as_slice())
+    }
+
+    /// 获取操作码
+    pub fn get_ops(&self) -> &[Op] {
+        self.ops.as_slice()
+    }
+
+    /// 是否为空脚本
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// 以Return开头的脚本不可花费
+    pub fn is_unspendable(&self) -> bool {
+        self.ops.first() == Some(&Op::Return)
+    }
+
+    /// 若为数据输出的锁定脚本, 返回其中的数据
+    pub fn get_data(&self) -> Option<&[u8]> {
+        match self.ops.as_slice() {
+            [Op::Return, Op::PushData(data)] => Some(data.as_slice()),
+            _ => None,
+        }
+    }
+
+    /// 是否只包含PushData
+    pub fn is_push_only(&self) -> bool {
+        self.ops.iter().all(|op| matches!(op, Op::PushData(_)))
+    }
+
+    /// 若为P2PKH锁定脚本, 返回其中的公钥hash
+    pub fn get_p2pkh_hash(&self) -> Option<&[u8]> {
+        self.get_p2pkh().map(|(_, hash)| hash)
+    }
+
+    /// 若为P2PKH锁定脚本, 返回(签名算法, 公钥hash), 不带类型标签的为P-256
+    pub fn get_p2pkh(&self) -> Option<(SignatureScheme, &[u8])> {
+        match self.ops.as_slice() {
+            [Op::Dup, Op::Hash160, Op::PushData(hash), Op::EqualVerify, Op::CheckSig] => {
+                Some((SignatureScheme::EcdsaP256, hash.as_slice()))
+            },
+            [Op::Dup, Op::Hash160, Op::PushData(hash), Op::EqualVerify, Op::PushData(tag), Op::CheckKeyTypeVerify, Op::CheckSig] => {
+                match tag.as_slice() {
+                    [tag] => SignatureScheme::from_tag(*tag).map(|scheme| (scheme, hash.as_slice())),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
+
+    /// 锁定脚本对应地址中的hash, 即P2PKH的公钥hash或P2SH的脚本hash
+    pub fn get_address_hash(&self) -> Option<&[u8]> {
+        self.get_p2pkh_hash().or_else(|| self.get_p2sh_hash())
+    }
+
+    /// 若为P2SH锁定脚本, 返回其中的脚本hash
+    pub fn get_p2sh_hash(&self) -> Option<&[u8]> {
+        match self.ops.as_slice() {
+            [Op::Hash160, Op::PushData(hash), Op::Equal] => Some(hash.as_slice()),
+            _ => None,
+        }
+    }
+
+    /// 若为多签脚本, 返回(m, 公钥列表)
+    pub fn get_multisig(&self) -> Option<(usize, Vec<&[u8]>)> {
+        let (last, ops) = self.ops.split_last()?;
+        if *last != Op::CheckMultiSig || ops.len() < 2 {
+            return None;
+        }
+        let m = small_int(self.get_push_data(0)?).ok()?;
+        let n = small_int(self.get_push_data(ops.len() - 1)?).ok()?;
+        let pub_keys: Vec<&[u8]> = (1..ops.len() - 1)
+            .map(|idx| self.get_push_data(idx))
+            .collect::<Option<_>>()?;
+        if pub_keys.len() != n || !is_valid_multisig(m, n) {
+            return None;
+        }
+        Some((m, pub_keys))
+    }
+
+    /// 若为P2PKH解锁脚本, 返回其中的公钥
+    pub fn get_p2pkh_pub_key(&self) -> Option<&[u8]> {
+        match self.ops.as_slice() {
+            [Op::PushData(_), Op::PushData(pub_key)] => Some(pub_key.as_slice()),
+            _ => None,
+        }
+    }
+
+    /// 获取第index个PushData的数据
+    pub fn get_push_data(&self, index: usize) -> Option<&[u8]> {
+        match self.ops.get(index)? {
+            Op::PushData(data) => Some(data.as_slice()),
+            _ => None,
+        }
+    }
+}
+
+/// 依次执行解锁脚本和锁定脚本, 执行完毕后栈顶为true则校验通过
+/// 锁定脚本为P2SH时, 解锁脚本的最后一项为赎回脚本, 还需以解锁脚本的其余部分执行赎回脚本
+pub fn verify_script(unlocking: &Script, locking: &Script, checker: &dyn SignatureChecker) -> Result<(), ScriptError> {
+    if !unlocking.is_push_only() {
+        return Err(ScriptError::UnlockingNotPushOnly);
+    }
+    let mut stack = vec![];
+    execute(unlocking, &mut stack, checker)?;
+    let mut redeem_stack = stack.clone();
+    execute(locking, &mut stack, checker)?;
+    check_top(&stack)?;
+
+    if locking.get_p2sh_hash().is_none() {
+        return Ok(());
+    }
+    let redeem_script = pop(&mut redeem_stack)?;
+    let redeem_script = Script::deserialize(redeem_script.as_slice()).ok_or(ScriptError::InvalidRedeemScript)?;
+    execute(&redeem_script, &mut redeem_stack, checker)?;
+    check_top(&redeem_stack)
+}
+
+/// 栈顶为true时校验通过
+fn check_top(stack: &[Vec<u8>]) -> Result<(), ScriptError> {
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse),
+    }
+}
+
+/// 在stack上执行脚本
+fn execute(script: &Script, stack: &mut Vec<Vec<u8>>, checker: &dyn SignatureChecker) -> Result<(), ScriptError> {
+    // 嵌套的If分支是否执行
+    let mut branches: Vec<bool> = vec![];
+    for op in script.get_ops() {
+        let executing = branches.iter().all(|branch| *branch);
+        match op {
+            Op::If => {
+                let condition = executing && cast_to_bool(pop(stack)?.as_slice());
+                branches.push(condition);
+            },
+            Op::Else => {
+                let branch = branches.last_mut().ok_or(ScriptError::UnbalancedConditional)?;
+                *branch = !*branch;
+            },
+            Op::EndIf => {
+                branches.pop().ok_or(ScriptError::UnbalancedConditional)?;
+            },
+            _ if !executing => {},
+            Op::PushData(data) => stack.push(data.clone()),
+            Op::Dup => {
+                let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                stack.push(top);
+            },
+            Op::Drop => {
+                pop(stack)?;
+            },
+            Op::Hash160 => {
+                let data = pop(stack)?;
+                stack.push(crate::hash_pub_key(data.as_slice()));
+            },
+            Op::Sha256 => {
+                let data = pop(stack)?;
+                stack.push(crate::sha256_digest(data.as_slice()));
+            },
+            Op::Equal | Op::EqualVerify => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                stack.push(bool_to_bytes(a == b));
+                if *op == Op::EqualVerify {
+                    verify(stack)?;
+                }
+            },
+            Op::Verify => verify(stack)?,
+            Op::CheckSig | Op::CheckSigVerify => {
+                let pub_key = pop(stack)?;
+                let signature = pop(stack)?;
+                stack.push(bool_to_bytes(checker.check_sig(signature.as_slice(), pub_key.as_slice(), script)));
+                if *op == Op::CheckSigVerify {
+                    verify(stack)?;
+                }
+            },
+            Op::CheckMultiSig | Op::CheckMultiSigVerify => {
+                let n = small_int(pop(stack)?.as_slice())?;
+                if n > MAX_MULTISIG_KEYS {
+                    return Err(ScriptError::InvalidMultisig);
+                }
+                let pub_keys = pop_n(stack, n)?;
+                let m = small_int(pop(stack)?.as_slice())?;
+                if !is_valid_multisig(m, n) {
+                    return Err(ScriptError::InvalidMultisig);
+                }
+                let signatures = pop_n(stack, m)?;
+                stack.push(bool_to_bytes(check_multisig(&signatures, &pub_keys, script, checker)));
+                if *op == Op::CheckMultiSigVerify {
+                    verify(stack)?;
+                }
+            },
+            Op::CheckLockTimeVerify => {
+                let lock_time = read_u64(stack.last().ok_or(ScriptError::StackUnderflow)?)?;
+                if !checker.check_lock_time(lock_time) {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            },
+            Op::Return => return Err(ScriptError::OpReturn),
+            Op::CheckKeyTypeVerify => {
+                let tag = pop(stack)?;
+                let pub_key = stack.last().ok_or(ScriptError::StackUnderflow)?;
+                if tag.len() != 1 || pub_key.first() != tag.first() {
+                    return Err(ScriptError::KeyTypeMismatch);
+                }
+            },
+            Op::CheckSequenceVerify => {
+                let sequence = read_u64(stack.last().ok_or(ScriptError::StackUnderflow)?)?;
+                let sequence = u32::try_from(sequence).map_err(|_| ScriptError::InvalidNumber)?;
+                if !checker.check_sequence(sequence) {
+                    return Err(ScriptError::UnsatisfiedLockTime);
+                }
+            },
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackOverflow);
+        }
+    }
+    if !branches.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+    Ok(())
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::StackUnderflow)
+}
+
+/// 弹出n个元素, 按压栈顺序返回
+fn pop_n(stack: &mut Vec<Vec<u8>>, n: usize) -> Result<Vec<Vec<u8>>, ScriptError> {
+    if stack.len() < n {
+        return Err(ScriptError::StackUnderflow);
+    }
+    Ok(stack.split_off(stack.len() - n))
+}
+
+/// 多签至少需要一个签名: 1 <= m <= n <= MAX_MULTISIG_KEYS
+pub fn is_valid_multisig(m: usize, n: usize) -> bool {
+    1 <= m && m <= n && n <= MAX_MULTISIG_KEYS
+}
+
+/// 签名需按公钥顺序排列, 每个公钥最多匹配一个签名
+fn check_multisig(signatures: &[Vec<u8>], pub_keys: &[Vec<u8>], script_code: &Script, checker: &dyn SignatureChecker) -> bool {
+    let mut keys = pub_keys.iter();
+    signatures.iter().all(|signature| {
+        keys.any(|pub_key| checker.check_sig(signature.as_slice(), pub_key.as_slice(), script_code))
+    })
+}
+
+/// 小整数编码为单字节, 空数组表示0
+fn small_int(data: &[u8]) -> Result<usize, ScriptError> {
+    match data {
+        [] => Ok(0),
+        [n] => Ok(*n as usize),
+        _ => Err(ScriptError::InvalidNumber),
+    }
+}
+
+/// 小端编码的无符号整数, 最多8字节
+pub fn read_u64(data: &[u8]) -> Result<u64, ScriptError> {
+    if data.len() > 8 {
+        return Err(ScriptError::InvalidNumber);
+    }
+    let mut bytes = [0u8; 8];
+    bytes[..data.len()].copy_from_slice(data);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// 弹出栈顶元素, 为false时失败
+fn verify(stack: &mut Vec<Vec<u8>>) -> Result<(), ScriptError> {
+    if cast_to_bool(pop(stack)?.as_slice()) {
+        Ok(())
+    } else {
+        Err(ScriptError::VerifyFailed)
+    }
+}
+
+/// 空数组和全0数组为false, 其余为true
+fn cast_to_bool(data: &[u8]) -> bool {
+    data.iter().any(|b| *b != 0)
+}
+
+fn bool_to_bytes(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { vec![] }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 只接受指定签名的校验器
+    struct FixedChecker(Vec<u8>);
+
+    impl SignatureChecker for FixedChecker {
+        fn check_sig(&self, signature: &[u8], _pub_key: &[u8], _script_code: &Script) -> bool {
+            self.0.as_slice() == signature
+        }
+    }
+
+    /// 签名为 公钥 + "sig" 时合法
+    struct KeyChecker;
+
+    impl SignatureChecker for KeyChecker {
+        fn check_sig(&self, signature: &[u8], pub_key: &[u8], _script_code: &Script) -> bool {
+            [pub_key, b"sig"].concat() == signature
+        }
+    }
+
+    #[test]
+    fn test_p2pkh() {
+        let pub_key = b"public key".to_vec();
+        let locking = Script::new_p2pkh(crate::hash_pub_key(pub_key.as_slice()).as_slice());
+        let checker = FixedChecker(b"signature".to_vec());
+
+        let unlocking = Script::new_p2pkh_unlock(b"signature", pub_key.as_slice());
+        assert_eq!(verify_script(&unlocking, &locking, &checker), Ok(()));
+
+        let unlocking = Script::new_p2pkh_unlock(b"forged", pub_key.as_slice());
+        assert_eq!(verify_script(&unlocking, &locking, &checker), Err(ScriptError::EvalFalse));
+
+        let unlocking = Script::new_p2pkh_unlock(b"signature", b"other key");
+        assert_eq!(verify_script(&unlocking, &locking, &checker), Err(ScriptError::VerifyFailed));
+
+        let unlocking = Script::new(vec![Op::PushData(pub_key)]);
+        assert_eq!(verify_script(&unlocking, &locking, &checker), Err(ScriptError::StackUnderflow));
+
+        let unlocking = Script::new(vec![Op::Dup]);
+        assert_eq!(verify_script(&unlocking, &locking, &checker), Err(ScriptError::UnlockingNotPushOnly));
+    }
+
+    #[test]
+    fn test_p2pkh_with_scheme() {
+        let pub_key = [vec![SignatureScheme::Ed25519.get_tag()], b"public key".to_vec()].concat();
+        let pub_key_hash = crate::hash_pub_key(pub_key.as_slice());
+        let checker = FixedChecker(b"signature".to_vec());
+        let unlocking = Script::new_p2pkh_unlock(b"signature", pub_key.as_slice());
+
+        let locking = Script::new_p2pkh_with_scheme(SignatureScheme::Ed25519, pub_key_hash.as_slice());
+        assert_eq!(locking.get_p2pkh(), Some((SignatureScheme::Ed25519, pub_key_hash.as_slice())));
+        assert_eq!(Script::from_address(locking.get_address().unwrap().as_str()), Some(locking.clone()));
+        assert_eq!(verify_script(&unlocking, &locking, &checker), Ok(()));
+
+        // 锁定脚本中的类型标签与公钥不符
+        let locking = Script::new_p2pkh_with_scheme(SignatureScheme::Secp256k1, pub_key_hash.as_slice());
+        assert_eq!(verify_script(&unlocking, &locking, &checker), Err(ScriptError::KeyTypeMismatch));
+
+        // P-256沿用原有的锁定脚本和地址
+        let locking = Script::new_p2pkh_with_scheme(SignatureScheme::EcdsaP256, pub_key_hash.as_slice());
+        assert_eq!(locking, Script::new_p2pkh(pub_key_hash.as_slice()));
+        assert_eq!(locking.get_address(), Some(wallet::convert_address(pub_key_hash.as_slice())));
+    }
+
+    #[test]
+    fn test_p2sh_multisig() {
+        let pub_keys: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        let redeem_script = Script::new_multisig(2, &pub_keys).unwrap();
+        assert_eq!(redeem_script.get_multisig(), Some((2, vec![&b"a"[..], &b"b"[..], &b"c"[..]])));
+        let locking = Script::new_p2sh(redeem_script.hash().as_slice());
+        let unlock = |signatures: &[&[u8]]| {
+            let mut ops: Vec<Op> = signatures.iter().map(|sig| Op::PushData(sig.to_vec())).collect();
+            ops.push(Op::PushData(redeem_script.serialize()));
+            Script::new(ops)
+        };
+
+        assert_eq!(verify_script(&unlock(&[b"asig", b"csig"]), &locking, &KeyChecker), Ok(()));
+        // 签名顺序与公钥顺序不一致
+        assert_eq!(verify_script(&unlock(&[b"csig", b"asig"]), &locking, &KeyChecker), Err(ScriptError::EvalFalse));
+        // 同一个公钥的签名不能使用两次
+        assert_eq!(verify_script(&unlock(&[b"asig", b"asig"]), &locking, &KeyChecker), Err(ScriptError::EvalFalse));
+        assert_eq!(verify_script(&unlock(&[b"asig"]), &locking, &KeyChecker), Err(ScriptError::StackUnderflow));
+
+        // 赎回脚本与锁定脚本中的hash不符
+        let other = Script::new_multisig(1, &pub_keys).unwrap();
+        let unlocking = Script::new(vec![Op::PushData(b"asig".to_vec()), Op::PushData(other.serialize())]);
+        assert_eq!(verify_script(&unlocking, &locking, &KeyChecker), Err(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn test_zero_of_n_multisig() {
+        let pub_keys: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b".to_vec()];
+        assert!(Script::new_multisig(0, &pub_keys).is_none());
+        assert!(Script::new_multisig(3, &pub_keys).is_none());
+
+        // 手工构造的0-of-2脚本无法解析, 也不能不带签名花费
+        let redeem_script = Script::new(vec![
+            Op::PushData(vec![]),
+            Op::PushData(b"a".to_vec()),
+            Op::PushData(b"b".to_vec()),
+            Op::PushData(vec![2]),
+            Op::CheckMultiSig,
+        ]);
+        assert_eq!(redeem_script.get_multisig(), None);
+        let locking = Script::new_p2sh(redeem_script.hash().as_slice());
+        let unlocking = Script::new(vec![Op::PushData(redeem_script.serialize())]);
+        assert_eq!(verify_script(&unlocking, &locking, &KeyChecker), Err(ScriptError::InvalidMultisig));
+    }
+
+    /// 接受任何签名, 交易的lock_time为指定高度的校验器
+    struct HeightChecker(u64);
+
+    impl SignatureChecker for HeightChecker {
+        fn check_sig(&self, _signature: &[u8], _pub_key: &[u8], _script_code: &Script) -> bool {
+            true
+        }
+
+        fn check_lock_time(&self, lock_time: u64) -> bool {
+            lock_time <= self.0
+        }
+    }
+
+    #[test]
+    fn test_conditional_lock_time() {
+        // If <a> Else <lock_time> CheckLockTimeVerify Drop <b> EndIf Equal
+        let locking = Script::new(vec![
+            Op::If,
+            Op::PushData(b"a".to_vec()),
+            Op::Else,
+            Op::PushData(10u64.to_le_bytes().to_vec()),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+            Op::PushData(b"b".to_vec()),
+            Op::EndIf,
+            Op::Equal,
+        ]);
+        let unlock = |data: &[u8], branch: bool| Script::new(vec![Op::PushData(data.to_vec()), Op::PushData(bool_to_bytes(branch))]);
+
+        assert_eq!(verify_script(&unlock(b"a", true), &locking, &HeightChecker(0)), Ok(()));
+        assert_eq!(verify_script(&unlock(b"b", true), &locking, &HeightChecker(0)), Err(ScriptError::EvalFalse));
+        assert_eq!(verify_script(&unlock(b"b", false), &locking, &HeightChecker(10)), Ok(()));
+        assert_eq!(verify_script(&unlock(b"b", false), &locking, &HeightChecker(9)), Err(ScriptError::UnsatisfiedLockTime));
+
+        let unbalanced = Script::new(vec![Op::If, Op::PushData(vec![1])]);
+        assert_eq!(verify_script(&unlock(b"a", true), &unbalanced, &HeightChecker(0)), Err(ScriptError::UnbalancedConditional));
+        let unbalanced = Script::new(vec![Op::PushData(vec![1]), Op::EndIf]);
+        assert_eq!(verify_script(&Script::default(), &unbalanced, &HeightChecker(0)), Err(ScriptError::UnbalancedConditional));
+    }
+
+    #[test]
+    fn test_data_output() {
+        let locking = Script::new_data(b"document hash");
+        assert!(locking.is_unspendable());
+        assert_eq!(locking.get_data(), Some(&b"document hash"[..]));
+        assert_eq!(locking.get_address(), None);
+        assert_eq!(verify_script(&Script::default(), &locking, &KeyChecker), Err(ScriptError::OpReturn));
+        assert!(!Script::new_p2pkh(b"hash").is_unspendable());
+    }
+}
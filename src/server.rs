@@ -9,6 +9,7 @@ use std::{
 };
 use data_encoding::HEXLOWER;
 use log::{error, info};
+use num_bigint::BigUint;
 
 use once_cell::sync::Lazy;
 use serde::{Serialize, Deserialize};
@@ -16,36 +17,43 @@ use serde_json::Deserializer;
 
 use crate::{
     Blockchain,
+    ChainError,
     GLOBAL_CONFIG,
     Transaction,
-    block::Block,
-    memory_pool::{MemoryPool, BlockInTransit},
+    ValidationError,
+    block::{Block, BlockHeader, MAX_BLOCK_SIZE},
+    memory_pool::{self, MemoryPool, BlockInTransit},
     node::Nodes,
-    utxo_set::UTXOSet
+    validation::validate_transaction_with_coins
 };
 
 /// 版本硬编码
-const NODE_VERSION: usize = 1;
+const NODE_VERSION: usize = 2;
 
 /// 中心节点硬编码
 pub const CENTERAL_NODE: &str = "127.0.0.1:2001";
 
 /// 内存池中的交易到达阈值, 触发矿工挖新区块
 pub const TRANSACTION_THRESHOLD: usize = 2;
+/// 挖矿时为区块头和coinbase交易预留的字节数
+const BLOCK_RESERVED_SIZE: usize = 1000;
 
 /// 全网的节点地址
 static GLOBAL_NODES: Lazy<Nodes> = Lazy::new(|| {
     let nodes = Nodes::new();
     // 记录中心地址
     nodes.add_node(String::from(CENTERAL_NODE));
-    return nodes;
+    nodes
 });
 
 /// 交易内存池
-static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::new());
+static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(MemoryPool::new);
 
 /// 传输中的Block, 用于来跟踪已下载的块, 这能够实现从不同的节点下载块
-static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(|| BlockInTransit::new());
+static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(BlockInTransit::new);
+
+/// 每个Headers数据包中区块头的最大个数
+const MAX_HEADERS: usize = 2000;
 
 /// 网络写超时
 const TCP_WRITE_TIMEOUT: u64 = 1000;
@@ -64,9 +72,8 @@ impl Server {
 
         // 发送 version 握手
         if !addr.eq(CENTERAL_NODE) {
-            let best_height = self.blockchain.get_best_height();
-            info!("send sersion best_height: {}", best_height);
-            send_version(CENTERAL_NODE, best_height);
+            info!("send sersion best_height: {}", self.blockchain.get_best_height());
+            send_version(CENTERAL_NODE, &self.blockchain);
         }
         info!("Start node server on {}", addr);
         for stream in listener.incoming() {
@@ -99,14 +106,19 @@ pub enum Package {
         addr_from: String,
         block: Vec<u8>,
     },
-    GetBlocks {
-        addr_from: String,
-    },
     GetData {
         addr_from: String,
         op_type: OpType,
         id: Vec<u8>,
     },
+    GetHeaders {
+        addr_from: String,
+        locator: Vec<Vec<u8>>,
+    },
+    Headers {
+        addr_from: String,
+        headers: Vec<Vec<u8>>,
+    },
     Inv {
         addr_from: String,
         op_type: OpType,
@@ -120,6 +132,7 @@ pub enum Package {
         addr_from: String,
         version: usize,
         best_height: usize,
+        chain_work: Vec<u8>,    // 最优链的累计工作量, 大端字节
     },
 }
 
@@ -132,22 +145,75 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
         info!("Receive request from {}: {:?}", peer_addr, pkg);
         match pkg {
             Package::Block { addr_from, block } => {
-                let block = Block::deserialize(block.as_slice());
-                blockchain.add_block(&block);
+                let block = match Block::deserialize(block.as_slice()) {
+                    Some(block) => block,
+                    None => {
+                        error!("Malformed block from {}", addr_from);
+                        GLOBAL_BLOCKS_IN_TRANSIT.clear();
+                        GLOBAL_NODES.evict_node(addr_from.as_str());
+                        continue;
+                    }
+                };
+                if let Err(e) = blockchain.process_block(&block) {
+                    error!("Rejected block {}: {}", block.get_hash(), e);
+                    match e {
+                        // 缺少前序区块, 重新向对端同步区块头
+                        ChainError::Invalid(ValidationError::MissingParent(_)) => {
+                            send_get_headers(addr_from.as_str(), &blockchain.get_block_locator())
+                        },
+                        // 非法区块, 驱逐发送该区块的节点
+                        ChainError::Invalid(_) => {
+                            GLOBAL_BLOCKS_IN_TRANSIT.clear();
+                            GLOBAL_NODES.evict_node(addr_from.as_str());
+                        },
+                        // 本地存储出错, 与对端无关
+                        ChainError::Storage(_) => {},
+                    }
+                    continue;
+                }
                 info!("Added block {}", block.get_hash());
+                GLOBAL_MEMORY_POOL.remove_conflicts(&blockchain);
 
                 if GLOBAL_BLOCKS_IN_TRANSIT.len() > 0 {
                     let block_hash = GLOBAL_BLOCKS_IN_TRANSIT.first().unwrap();
                     send_get_data(addr_from.as_str(), OpType::Block, &block_hash);
                     GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash.as_slice());
-                } else {
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
                 }
             },
-            Package::GetBlocks { addr_from } => {
-                let blocks = blockchain.get_block_hashes();
-                send_inv(addr_from.as_str(), OpType::Block, &blocks);
+            Package::GetHeaders { addr_from, locator } => {
+                let headers = blockchain.get_headers_after(locator.as_slice(), MAX_HEADERS);
+                send_headers(addr_from.as_str(), &headers);
+            },
+            Package::Headers { addr_from, headers } => {
+                let headers: Vec<BlockHeader> = match headers.iter()
+                    .map(|bytes| BlockHeader::deserialize(bytes.as_slice()))
+                    .collect() {
+                    Some(headers) => headers,
+                    None => {
+                        error!("Malformed headers from {}", addr_from);
+                        GLOBAL_NODES.evict_node(addr_from.as_str());
+                        continue;
+                    }
+                };
+                if let Err(e) = blockchain.process_headers(headers.as_slice()) {
+                    error!("Rejected headers from {}: {}", addr_from, e);
+                    if let ChainError::Invalid(_) = e {
+                        GLOBAL_NODES.evict_node(addr_from.as_str());
+                    }
+                    continue;
+                }
+                // 区块头未下载完, 继续请求
+                if headers.len() >= MAX_HEADERS {
+                    send_get_headers(addr_from.as_str(), &blockchain.get_block_locator());
+                    continue;
+                }
+                // 区块头链校验通过后, 按高度从低到高下载区块内容
+                let missing_blocks = blockchain.get_missing_blocks();
+                if let Some(block_hash) = missing_blocks.first() {
+                    GLOBAL_BLOCKS_IN_TRANSIT.clear();
+                    GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(&missing_blocks[1..]);
+                    send_get_data(addr_from.as_str(), OpType::Block, block_hash);
+                }
             },
             Package::GetData { addr_from, op_type, id } => match op_type {
                 OpType::Tx => {
@@ -166,12 +232,12 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 OpType::Block => {
                     GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(items.as_slice());
 
-                    let block_hash = items.get(0).unwrap();
+                    let block_hash = items.first().unwrap();
                     send_get_data(addr_from.as_str(), OpType::Block, block_hash);
                     GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash);
                 },
                 OpType::Tx => {
-                    let txid = items.get(0).unwrap();
+                    let txid = items.first().unwrap();
                     let txid_hex = HEXLOWER.encode(txid);
 
                     if !GLOBAL_MEMORY_POOL.contains(txid_hex.as_str()) {
@@ -180,8 +246,27 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 }
             },
             Package::Tx { addr_from, transaction } => {
-                let tx = Transaction::deserialize(transaction.as_slice());
+                let tx = match Transaction::deserialize(transaction.as_slice()) {
+                    Some(tx) => tx,
+                    None => {
+                        error!("Malformed transaction from {}", addr_from);
+                        GLOBAL_NODES.evict_node(addr_from.as_str());
+                        continue;
+                    }
+                };
                 let txid = tx.get_id_bytes();
+                // 只接受能被下一个区块打包的交易
+                let height = blockchain.get_best_height() + 1;
+                let time = blockchain.get_tip_median_time_past();
+                let coins = GLOBAL_MEMORY_POOL.get_coins_view(&blockchain);
+                if let Err(e) = validate_transaction_with_coins(&blockchain, &coins, &tx, height, time) {
+                    error!("reject transaction {}: {}", HEXLOWER.encode(txid.as_slice()), e);
+                    continue;
+                }
+                if !memory_pool::is_standard(&tx) {
+                    error!("reject non-standard transaction {}", HEXLOWER.encode(txid.as_slice()));
+                    continue;
+                }
                 GLOBAL_MEMORY_POOL.add(tx);
 
                 let local_addr = GLOBAL_CONFIG.get_node_addr();
@@ -193,27 +278,28 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                         if local_addr.eq(node_addr.as_str()) || addr_from.eq(node_addr.as_str()) {
                             continue;
                         }
-                        send_inv(node_addr.as_str(), OpType::Tx, &vec![txid.clone()]);
+                        send_inv(node_addr.as_str(), OpType::Tx, std::slice::from_ref(&txid));
                     }
                 }
                 // 矿工节点, 缓存中累积的交易数超过限制,则挖新区块
                 if GLOBAL_CONFIG.is_miner() && GLOBAL_MEMORY_POOL.len() >= TRANSACTION_THRESHOLD {
-                    // 生成一个coinbase_tx
+                    // 按手续费率选取交易
+                    let (mut txs, fees) = GLOBAL_MEMORY_POOL.select_by_fee_rate(
+                        &blockchain, MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE);
+                    if txs.is_empty() {
+                        continue;
+                    }
+                    // 生成一个coinbase_tx, 收取所选交易的手续费
                     let mining_addr = GLOBAL_CONFIG.get_mining_addr().unwrap();
-                    let coinbase_tx = Transaction::new_coinbase_tx(mining_addr.as_str());
-                    let mut txs = GLOBAL_MEMORY_POOL.get_all();
+                    let height = blockchain.get_best_height() + 1;
+                    let coinbase_tx = Transaction::new_coinbase_tx(mining_addr.as_str(), height, fees);
                     txs.push(coinbase_tx);
 
-                    // 生成新区块
+                    // 生成新区块, 同时更新 UTXO 集
                     let new_block = blockchain.mine_block(&txs);
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
 
-                    // 从缓存池中移除tx
-                    for tx in &txs {
-                        let txid_hex = HEXLOWER.encode(tx.get_id());
-                        GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
-                    }
+                    // 从缓存池中移除已打包及与区块冲突的tx
+                    GLOBAL_MEMORY_POOL.remove_conflicts(&blockchain);
 
                     // 广播新区块
                     let nodes = GLOBAL_NODES.get_nodes();
@@ -222,18 +308,20 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                         if local_addr.eq(node_addr.as_str()) {
                             continue;
                         }
-                        send_inv(node_addr.as_str(), OpType::Block, &vec![new_block.get_hash_bytes()]);
+                        send_inv(node_addr.as_str(), OpType::Block, &[new_block.get_hash_bytes()]);
                     }
                 }
             },
-            Package::Version { addr_from, version, best_height } => {
+            Package::Version { addr_from, version, best_height, chain_work } => {
                 info!("version: {}, best_height: {}", version, best_height);
-                let local_best_height = blockchain.get_best_height();
-                if local_best_height < best_height {
-                    send_get_blocks(addr_from.as_str());
+                // 按累计工作量而不是高度判断哪一方的链更优
+                let chain_work = BigUint::from_bytes_be(chain_work.as_slice());
+                let local_chain_work = blockchain.get_best_chain_work();
+                if local_chain_work < chain_work {
+                    send_get_headers(addr_from.as_str(), &blockchain.get_block_locator());
                 }
-                if local_best_height > best_height {
-                    send_version(addr_from.as_str(), blockchain.get_best_height());
+                if local_chain_work > chain_work {
+                    send_version(addr_from.as_str(), &blockchain);
                 }
 
                 if !GLOBAL_NODES.node_is_known(peer_addr.to_string().as_str()) {
@@ -247,7 +335,7 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
     Ok(())
 }
 
-fn send_version(addr: &str, height: usize) {
+fn send_version(addr: &str, blockchain: &Blockchain) {
     let socket_addr = addr.parse().unwrap();
 
     let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
@@ -256,7 +344,8 @@ fn send_version(addr: &str, height: usize) {
         Package::Version {
             addr_from: node_addr,
             version: NODE_VERSION,
-            best_height: height,
+            best_height: blockchain.get_best_height(),
+            chain_work: blockchain.get_best_chain_work().to_bytes_be(),
         },
     );
 }
@@ -287,13 +376,26 @@ fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) {
     );
 }
 
-fn send_get_blocks(addr: &str) {
+fn send_get_headers(addr: &str, locator: &[Vec<u8>]) {
+    let socket_addr = addr.parse().unwrap();
+    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+    send_data(
+        socket_addr,
+        Package::GetHeaders {
+            addr_from: node_addr,
+            locator: locator.to_vec(),
+        },
+    );
+}
+
+fn send_headers(addr: &str, headers: &[BlockHeader]) {
     let socket_addr = addr.parse().unwrap();
     let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
     send_data(
         socket_addr,
-        Package::GetBlocks {
+        Package::Headers {
             addr_from: node_addr,
+            headers: headers.iter().map(|header| header.serialize()).collect(),
         },
     );
 }
@@ -0,0 +1,263 @@
+// signature.rs
+//
+
+use std::{fmt, str::FromStr};
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+/// P-256公钥的类型标签, 即未压缩SEC1编码的首字节
+const P256_TAG: u8 = 0x04;
+const ED25519_TAG: u8 = 0x10;
+const SECP256K1_TAG: u8 = 0x11;
+const SCHNORR_TAG: u8 = 0x12;
+
+/// P-256地址的版本, 与原有地址相同
+const P256_ADDRESS_VERSION: u8 = 0x00;
+const ED25519_ADDRESS_VERSION: u8 = 0x01;
+const SECP256K1_ADDRESS_VERSION: u8 = 0x02;
+const SCHNORR_ADDRESS_VERSION: u8 = 0x03;
+
+/// 签名算法, 公钥的首字节为其类型标签, 验证签名时按标签选择算法
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// ECDSA P-256 + SHA256, 公钥为65字节的未压缩SEC1编码
+    EcdsaP256,
+    /// Ed25519, 公钥为标签 + 32字节公钥
+    Ed25519,
+    /// ECDSA secp256k1 + SHA256, 公钥为标签 + 33字节的压缩SEC1编码
+    Secp256k1,
+    /// Schnorr(sr25519), 支持批量验证, 公钥为标签 + 32字节公钥
+    Schnorr,
+}
+
+impl SignatureScheme {
+    /// 公钥的类型标签
+    pub fn get_tag(&self) -> u8 {
+        match self {
+            SignatureScheme::EcdsaP256 => P256_TAG,
+            SignatureScheme::Ed25519 => ED25519_TAG,
+            SignatureScheme::Secp256k1 => SECP256K1_TAG,
+            SignatureScheme::Schnorr => SCHNORR_TAG,
+        }
+    }
+
+    /// 由类型标签获取签名算法, 未知标签返回None
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            P256_TAG => Some(SignatureScheme::EcdsaP256),
+            ED25519_TAG => Some(SignatureScheme::Ed25519),
+            SECP256K1_TAG => Some(SignatureScheme::Secp256k1),
+            SCHNORR_TAG => Some(SignatureScheme::Schnorr),
+            _ => None,
+        }
+    }
+
+    /// 由带类型标签的公钥获取签名算法
+    pub fn from_public_key(pub_key: &[u8]) -> Option<Self> {
+        Self::from_tag(*pub_key.first()?)
+    }
+
+    /// P2PKH地址的版本
+    pub fn get_address_version(&self) -> u8 {
+        match self {
+            SignatureScheme::EcdsaP256 => P256_ADDRESS_VERSION,
+            SignatureScheme::Ed25519 => ED25519_ADDRESS_VERSION,
+            SignatureScheme::Secp256k1 => SECP256K1_ADDRESS_VERSION,
+            SignatureScheme::Schnorr => SCHNORR_ADDRESS_VERSION,
+        }
+    }
+
+    /// 由P2PKH地址的版本获取签名算法
+    pub fn from_address_version(version: u8) -> Option<Self> {
+        match version {
+            P256_ADDRESS_VERSION => Some(SignatureScheme::EcdsaP256),
+            ED25519_ADDRESS_VERSION => Some(SignatureScheme::Ed25519),
+            SECP256K1_ADDRESS_VERSION => Some(SignatureScheme::Secp256k1),
+            SCHNORR_ADDRESS_VERSION => Some(SignatureScheme::Schnorr),
+            _ => None,
+        }
+    }
+
+    /// 新建密钥对, 返回(私钥, 带类型标签的公钥).
+    /// P-256和Ed25519的私钥为pkcs8编码, secp256k1的私钥为32字节标量, Schnorr的私钥为32字节种子
+    pub fn new_key_pair(&self) -> (Vec<u8>, Vec<u8>) {
+        match self {
+            SignatureScheme::EcdsaP256 => {
+                let pkcs8 = crate::new_key_pair();
+                let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_slice()).unwrap();
+                let pub_key = key_pair.public_key().as_ref().to_vec();
+                (pkcs8, pub_key)
+            },
+            SignatureScheme::Ed25519 => {
+                let pkcs8 = crate::utils::new_ed25519_key_pair();
+                let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_slice()).unwrap();
+                let pub_key = self.tagged(key_pair.public_key().as_ref());
+                (pkcs8, pub_key)
+            },
+            SignatureScheme::Secp256k1 => {
+                let secret = crate::utils::new_secp256k1_key();
+                let pub_key = self.tagged(crate::utils::secp256k1_public_key(secret.as_slice()).as_slice());
+                (secret, pub_key)
+            },
+            SignatureScheme::Schnorr => {
+                let secret = crate::utils::new_secret();
+                let pub_key = self.tagged(crate::utils::schnorr_public_key(secret.as_slice()).as_slice());
+                (secret, pub_key)
+            },
+        }
+    }
+
+    /// 使用私钥计算消息签名
+    pub fn sign(&self, secret_key: &[u8], message: &[u8]) -> Vec<u8> {
+        match self {
+            SignatureScheme::EcdsaP256 => crate::ecdsa_p256_sha256_sign_digest(secret_key, message),
+            SignatureScheme::Ed25519 => crate::utils::ed25519_sign(secret_key, message),
+            SignatureScheme::Secp256k1 => crate::utils::secp256k1_sign(secret_key, message),
+            SignatureScheme::Schnorr => crate::utils::schnorr_sign(secret_key, message),
+        }
+    }
+
+    /// 按公钥的类型标签选择算法, 验证消息签名是否合法, 未知标签的公钥验证失败
+    pub fn verify(pub_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+        match Self::from_public_key(pub_key) {
+            // P-256的标签是公钥编码的一部分
+            Some(SignatureScheme::EcdsaP256) => crate::ecdsa_p256_sha256_sign_verify(pub_key, signature, message),
+            Some(SignatureScheme::Ed25519) => crate::utils::ed25519_verify(&pub_key[1..], signature, message),
+            Some(SignatureScheme::Secp256k1) => crate::utils::secp256k1_verify(&pub_key[1..], signature, message),
+            Some(SignatureScheme::Schnorr) => crate::utils::schnorr_verify(&pub_key[1..], signature, message),
+            None => false,
+        }
+    }
+
+    /// 在公钥前附加类型标签
+    fn tagged(&self, pub_key: &[u8]) -> Vec<u8> {
+        let mut tagged = vec![self.get_tag()];
+        tagged.extend_from_slice(pub_key);
+        tagged
+    }
+}
+
+impl fmt::Display for SignatureScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureScheme::EcdsaP256 => write!(f, "p256"),
+            SignatureScheme::Ed25519 => write!(f, "ed25519"),
+            SignatureScheme::Secp256k1 => write!(f, "secp256k1"),
+            SignatureScheme::Schnorr => write!(f, "schnorr"),
+        }
+    }
+}
+
+impl FromStr for SignatureScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "p256" => Ok(SignatureScheme::EcdsaP256),
+            "ed25519" => Ok(SignatureScheme::Ed25519),
+            "secp256k1" => Ok(SignatureScheme::Secp256k1),
+            "schnorr" => Ok(SignatureScheme::Schnorr),
+            _ => Err(format!("unknown signature scheme {}, expected p256, ed25519, secp256k1 or schnorr", s)),
+        }
+    }
+}
+
+/// Schnorr签名的批量验证器, 收集签名后一次验证, 比逐个验证更快
+#[derive(Default)]
+pub struct BatchVerifier {
+    items: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)>,    // (不带标签的公钥, 签名, 消息)
+}
+
+impl BatchVerifier {
+    pub fn new() -> Self {
+        BatchVerifier::default()
+    }
+
+    /// 加入一个待验证的签名, pub_key带类型标签, 不是Schnorr公钥时返回false
+    pub fn add(&mut self, pub_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+        if SignatureScheme::from_public_key(pub_key) != Some(SignatureScheme::Schnorr) {
+            return false;
+        }
+        self.items.push((pub_key[1..].to_vec(), signature.to_vec(), message.to_vec()));
+        true
+    }
+
+    /// 加入other中的全部签名
+    pub fn append(&mut self, other: BatchVerifier) {
+        self.items.extend(other.items);
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// 验证加入的全部签名, 全部合法时返回true
+    pub fn verify(&self) -> bool {
+        self.is_empty() || crate::utils::schnorr_verify_batch(self.items.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMES: [SignatureScheme; 4] = [
+        SignatureScheme::EcdsaP256,
+        SignatureScheme::Ed25519,
+        SignatureScheme::Secp256k1,
+        SignatureScheme::Schnorr,
+    ];
+
+    #[test]
+    fn test_sign_and_verify() {
+        for scheme in SCHEMES {
+            let (secret_key, pub_key) = scheme.new_key_pair();
+            assert_eq!(SignatureScheme::from_public_key(pub_key.as_slice()), Some(scheme));
+            let signature = scheme.sign(secret_key.as_slice(), b"message");
+            assert!(SignatureScheme::verify(pub_key.as_slice(), signature.as_slice(), b"message"));
+            assert!(!SignatureScheme::verify(pub_key.as_slice(), signature.as_slice(), b"other"));
+            assert!(!SignatureScheme::verify(&[], signature.as_slice(), b"message"));
+            assert_eq!(scheme.to_string().parse(), Ok(scheme));
+            assert_eq!(SignatureScheme::from_address_version(scheme.get_address_version()), Some(scheme));
+        }
+    }
+
+    #[test]
+    fn test_tag_dispatch() {
+        let (ed_secret, ed_pub_key) = SignatureScheme::Ed25519.new_key_pair();
+        let signature = SignatureScheme::Ed25519.sign(ed_secret.as_slice(), b"message");
+        // 同一公钥换成其他标签后验证失败
+        let mut retagged = ed_pub_key.clone();
+        retagged[0] = SignatureScheme::Secp256k1.get_tag();
+        assert!(!SignatureScheme::verify(retagged.as_slice(), signature.as_slice(), b"message"));
+        retagged[0] = 0xff;
+        assert!(!SignatureScheme::verify(retagged.as_slice(), signature.as_slice(), b"message"));
+    }
+
+    #[test]
+    fn test_batch_verify() {
+        let mut batch = BatchVerifier::new();
+        assert!(batch.verify());
+        let mut signed = vec![];
+        for i in 0..8u8 {
+            let (secret_key, pub_key) = SignatureScheme::Schnorr.new_key_pair();
+            let signature = SignatureScheme::Schnorr.sign(secret_key.as_slice(), &[i]);
+            assert!(batch.add(pub_key.as_slice(), signature.as_slice(), &[i]));
+            signed.push((pub_key, signature));
+        }
+        assert_eq!(batch.len(), 8);
+        assert!(batch.verify());
+
+        // 其他算法的公钥不能加入
+        let (_, pub_key) = SignatureScheme::Ed25519.new_key_pair();
+        assert!(!batch.add(pub_key.as_slice(), &[0; 64], b"message"));
+
+        // 任一签名非法则整批验证失败
+        let (pub_key, signature) = &signed[0];
+        batch.add(pub_key.as_slice(), signature.as_slice(), b"other");
+        assert!(!batch.verify());
+    }
+}
@@ -0,0 +1,28 @@
+// test_utils.rs
+//
+// 单元测试, 集成测试和基准测试共用的辅助函数, 在测试或开启test-utils特性时编译
+
+use crate::{Amount, Blockchain, GLOBAL_CONFIG, Transaction};
+
+/// 新建一个临时db, 测试结束后删除
+pub fn temporary_db() -> sled::Db {
+    sled::Config::new().temporary(true).open().unwrap()
+}
+
+/// 在临时db上新建一条区块链, 创世区块的奖励给address
+pub fn new_blockchain(address: &str) -> Blockchain {
+    Blockchain::create_blockchain_with_db(temporary_db(), address)
+}
+
+/// 挖出count个只有coinbase交易的区块, 奖励给miner
+pub fn mine_blocks(blockchain: &Blockchain, miner: &str, count: usize) {
+    for _ in 0..count {
+        let height = blockchain.get_best_height() + 1;
+        blockchain.mine_block(&[Transaction::new_coinbase_tx(miner, height, Amount::ZERO)]);
+    }
+}
+
+/// 挖出区块直到当前tip的coinbase可以在下一个区块中花费, 奖励给miner
+pub fn mine_to_maturity(blockchain: &Blockchain, miner: &str) {
+    mine_blocks(blockchain, miner, GLOBAL_CONFIG.get_coinbase_maturity() - 1);
+}
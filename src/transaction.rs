@@ -1,24 +1,109 @@
 // transaction.rs
 
 use crate::{
-    wallet::{self, hash_pub_key},
-    Blockchain, 
+    script::{self, Op, Script, SignatureChecker, LOCKTIME_THRESHOLD},
+    wallet::Wallet,
+    Amount,
+    GLOBAL_CONFIG,
+    BatchVerifier,
+    SignatureScheme,
     Wallets,
-    utxo_set::UTXOSet
+    utxo_set::{CoinsView, UTXOSet}
 };
 use data_encoding::HEXLOWER;
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+use std::cell::RefCell;
+
+/// 交易版本
+pub const TX_VERSION: u32 = 1;
+/// coinbase输入引用的空输出: 全零的txid和索引NULL_OUTID
+const NULL_TXID: [u8; 32] = [0; 32];
+const NULL_OUTID: usize = u32::MAX as usize;
+/// 输入的sequence为该值时不启用相对时间锁; 所有输入均为该值时lock_time也不生效
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// sequence设置该位时不启用相对时间锁
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// sequence设置该位时相对时间锁以时间计, 否则以区块数计
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+/// sequence中相对时间锁的数值部分
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+/// 以时间计的相对时间锁的单位, 512秒(毫秒数)
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u64 = 512_000;
+
+/// 签名覆盖所有输入和输出
+pub const SIGHASH_ALL: u8 = 0x01;
+/// 签名覆盖所有输入, 不覆盖输出
+pub const SIGHASH_NONE: u8 = 0x02;
+/// 签名覆盖所有输入和与该输入索引相同的输出
+pub const SIGHASH_SINGLE: u8 = 0x03;
+/// 与以上类型组合, 签名只覆盖当前输入, 其他人可以继续添加输入
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+/// 输入的相对时间锁, 从其引用的输出被打包的区块开始计算
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelativeLock {
+    /// 经过的区块数
+    Blocks(u16),
+    /// 经过的时间, 单位为SEQUENCE_LOCKTIME_GRANULARITY
+    Time(u16),
+}
+
+impl RelativeLock {
+    /// 由sequence解析相对时间锁, 未启用时返回None
+    pub fn from_sequence(sequence: u32) -> Option<Self> {
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = (sequence & SEQUENCE_LOCKTIME_MASK) as u16;
+        if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            Some(RelativeLock::Time(value))
+        } else {
+            Some(RelativeLock::Blocks(value))
+        }
+    }
+
+    /// 编码为sequence
+    pub fn to_sequence(self) -> u32 {
+        match self {
+            RelativeLock::Blocks(blocks) => blocks as u32,
+            RelativeLock::Time(units) => SEQUENCE_LOCKTIME_TYPE_FLAG | units as u32,
+        }
+    }
+
+    /// 引用的输出在高度为prev_height的区块中, 该区块的前一个区块中位时间为prev_time时,
+    /// 高度为height, 前一个区块中位时间为time的区块能否打包该输入
+    pub fn is_satisfied(self, prev_height: usize, prev_time: u64, height: usize, time: u64) -> bool {
+        match self {
+            RelativeLock::Blocks(blocks) => prev_height.saturating_add(blocks as usize) <= height,
+            RelativeLock::Time(units) => {
+                prev_time.saturating_add(units as u64 * SEQUENCE_LOCKTIME_GRANULARITY) <= time
+            },
+        }
+    }
+}
 
-const SUBSIDY: i32 = 10;         //挖矿奖励
+/// 高度为height的区块的挖矿奖励, 每隔halving_interval个区块减半
+fn halving_subsidy(initial: Amount, halving_interval: usize, height: usize) -> Amount {
+    let halvings = u32::try_from(height / halving_interval).unwrap_or(u32::MAX);
+    initial.checked_shr(halvings).unwrap_or(Amount::ZERO)
+}
+
+/// 高度为height的区块的挖矿奖励
+pub fn get_block_subsidy(height: usize) -> Amount {
+    halving_subsidy(
+        GLOBAL_CONFIG.get_initial_subsidy(),
+        GLOBAL_CONFIG.get_halving_interval(),
+        height,
+    )
+}
 
 /// 交易输入
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct TxInput {
     txid: Vec<u8>,          // 交易的id
     outid: usize,           // 该交易输入对应交易输出的索引
-    signature: Vec<u8>,     // 交易签名,
-    pub_key: Vec<u8>,       // 公钥,
+    script_sig: Script,     // 解锁脚本
+    sequence: u32,          // 相对时间锁, 见RelativeLock
 }
 
 impl TxInput {
@@ -27,8 +112,8 @@ impl TxInput {
         TxInput {
             txid: txid.to_vec(),
             outid: vout,
-            signature: vec![],
-            pub_key: vec![],
+            script_sig: Script::default(),
+            sequence: SEQUENCE_FINAL,
         }
     }
 
@@ -42,55 +127,71 @@ impl TxInput {
         self.outid
     }
 
-    /// 获取交易公钥
-    pub fn get_pub_key(&self) -> &[u8] {
-        return self.pub_key.as_slice();
+    /// 获取解锁脚本
+    pub fn get_script_sig(&self) -> &Script {
+        &self.script_sig
     }
 
-    ///
-    pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
-        let locking_hash = wallet::hash_pub_key(self.pub_key.as_slice());
-        return locking_hash.eq(pub_key_hash);
+    /// 获取sequence, 编码了相对时间锁
+    pub fn get_sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// 获取相对时间锁, 未启用时返回None
+    pub fn get_relative_lock(&self) -> Option<RelativeLock> {
+        RelativeLock::from_sequence(self.sequence)
     }
 }
 
 /// 交易输出
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TxOutput {
-    cost: i32,                  //交易花费币的数量
-    pub_key_hash: Vec<u8>,      //公钥hash
+    cost: Amount,               //交易花费币的数量
+    script_pubkey: Script,      //锁定脚本
 }
 
 impl TxOutput {
-    /// 新建一个交易输出
-    pub fn new(value: i32, address: &str) -> Self {
-        let mut output = TxOutput {
+    /// 新建一个锁定到address的交易输出, 按地址版本生成P2PKH或P2SH锁定脚本
+    pub fn new(value: Amount, address: &str) -> Self {
+        let script_pubkey = Script::from_address(address).expect("ERROR: address is not valid");
+        Self::new_with_script(value, script_pubkey)
+    }
+
+    /// 新建一个由指定脚本锁定的交易输出
+    pub fn new_with_script(value: Amount, script_pubkey: Script) -> Self {
+        TxOutput {
             cost: value,
-            pub_key_hash: vec![],
-        };
-        output.lock(address);
+            script_pubkey,
+        }
+    }
 
-        return output;
+    /// 新建一个携带data的数据输出, 金额为0且不可花费
+    pub fn new_data(data: &[u8]) -> Self {
+        Self::new_with_script(Amount::ZERO, Script::new_data(data))
     }
 
-    /// lock
-    fn lock(&mut self, address: &str) {
-        let payload = crate::base58_decode(address);
-        self.pub_key_hash = payload[1..payload.len()-wallet::ADDRESS_CHECKSUM_LEN].to_vec();
+    /// 是否为不可花费的输出, 不可花费的输出不会进入utxo set
+    pub fn is_unspendable(&self) -> bool {
+        self.script_pubkey.is_unspendable()
     }
 
-    // 检查是否lock
+    /// 是否为key_hash对应的P2PKH输出
     pub fn is_locked_with_key(&self, key_hash: &[u8]) -> bool {
-        self.pub_key_hash.eq(key_hash)
+        self.get_pub_key_hash() == Some(key_hash)
     }
 
-    /// 获取公钥hash
-    pub fn get_pub_key_hash(&self) -> &[u8] {
-        self.pub_key_hash.as_slice()
+    /// 获取P2PKH输出的公钥hash, 其他类型的输出返回None
+    pub fn get_pub_key_hash(&self) -> Option<&[u8]> {
+        self.script_pubkey.get_p2pkh_hash()
+    }
+
+    /// 获取锁定脚本
+    pub fn get_script_pubkey(&self) -> &Script {
+        &self.script_pubkey
     }
 
     /// 获取花费
-    pub fn get_cost(&self) -> i32 {
+    pub fn get_cost(&self) -> Amount {
         self.cost
     }
 }
@@ -99,71 +200,142 @@ impl TxOutput {
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Transaction {
     id: Vec<u8>,            // 本次交易ID
+    version: u32,           // 交易版本
     vin: Vec<TxInput>,      // 本次交易输入
     vout: Vec<TxOutput>,    // 本次交易输出
+    lock_time: u64,         // 绝对时间锁, 小于LOCKTIME_THRESHOLD时为区块高度, 否则为时间戳(毫秒)
 }
 
 impl Transaction {
 
-    /// 新建coinbase交易
-    pub fn new_coinbase_tx(to: &str) -> Self {
-        let tx_out = TxOutput::new(SUBSIDY, to);
-        let mut tx_in = TxInput::default();
-        tx_in.signature = Uuid::new_v4().as_bytes().to_vec();
+    /// 新建高度为height的区块的coinbase交易, 奖励为挖矿奖励加上区块中所有交易的手续费
+    pub fn new_coinbase_tx(to: &str, height: usize, fees: Amount) -> Self {
+        Self::new_coinbase_tx_with_extra(to, height, fees, &[])
+    }
+
+    /// 新建coinbase交易, 并附带extra-nonce或消息
+    /// 输入的解锁脚本为区块高度和extra, 同一条链上的coinbase交易id不会重复, 且相同输入生成相同的交易
+    pub fn new_coinbase_tx_with_extra(to: &str, height: usize, fees: Amount, extra: &[u8]) -> Self {
+        let reward = get_block_subsidy(height).checked_add(fees).expect("coinbase reward overflow");
+        let tx_out = TxOutput::new(reward, to);
+        let tx_in = TxInput {
+            script_sig: Script::new(vec![
+                Op::PushData((height as u64).to_le_bytes().to_vec()),
+                Op::PushData(extra.to_vec()),
+            ]),
+            ..TxInput::new(&NULL_TXID, NULL_OUTID)
+        };
 
         let mut tx = Transaction {
             id: vec![],
+            version: TX_VERSION,
             vin: vec![tx_in],
             vout: vec![tx_out],
+            lock_time: 0,
+        };
+        tx.id = tx.hash();
+
+        tx
+    }
+
+    /// 新建只有输出的交易, 如众筹交易: 出资人各自添加输入, 并以SIGHASH_ALL | SIGHASH_ANYONECANPAY签名
+    pub fn new_with_outputs(outputs: Vec<TxOutput>) -> Self {
+        let mut tx = Transaction {
+            id: vec![],
+            version: TX_VERSION,
+            vin: vec![],
+            vout: outputs,
+            lock_time: 0,
         };
         tx.id = tx.hash();
 
-        return tx;
+        tx
     }
 
-    /// 新建一笔utxo交易
-    pub fn new_utxo_transaction(from: &str, to: &str, amount: i32, utxo_set: &UTXOSet) -> Self {
+    /// 新建一笔utxo交易, 输入总额与输出总额之差fee作为手续费
+    pub fn new_utxo_transaction(from: &str, to: &str, amount: Amount, fee: Amount, utxo_set: &UTXOSet) -> Self {
         let wallets = Wallets::new();
         let wallet = wallets.get_wallet(from).unwrap();
-        let pub_key_hash = hash_pub_key(wallet.get_public_key());
+        Self::new_utxo_transaction_from_wallet(wallet, to, amount, fee, utxo_set)
+    }
 
-        let (accumulated, valid_outputs) = utxo_set.find_spendable_outputs(pub_key_hash.as_slice(), amount);
-        if accumulated < amount {
-            panic!("Error! not enough funds");
-        }
+    /// 使用指定钱包新建一笔utxo交易
+    pub fn new_utxo_transaction_from_wallet(wallet: &Wallet, to: &str, amount: Amount, fee: Amount, utxo_set: &UTXOSet) -> Self {
+        let from = wallet.get_script_pubkey();
+        let mut tx = Self::new_unsigned_transaction(&from, to, amount, fee, utxo_set);
+        // 交易中的 TXInput 签名
+        tx.sign(utxo_set, wallet);
 
-        let mut inputs = vec![];
-        for (txid_hex, outs) in valid_outputs {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-            for out in outs {
-                let input = TxInput {
-                    txid: txid.clone(),
-                    outid: out,
-                    pub_key: wallet.get_public_key().to_vec(),
-                    signature: vec![],
-                };
-                inputs.push(input);
-            }
-        }
+        tx
+    }
 
+    /// 花费由from锁定的输出, 新建一笔未签名的交易, 找零仍由from锁定
+    pub fn new_unsigned_transaction(from: &Script, to: &str, amount: Amount, fee: Amount, utxo_set: &UTXOSet) -> Self {
+        let required = amount.checked_add(fee).expect("Error! amount overflow");
+        let (accumulated, inputs) = Self::select_inputs(from, required, utxo_set);
 
         let mut outputs = vec![TxOutput::new(amount, to)];
         // 如果 UTXO 总数超过所需，则产生找零
-        if accumulated > amount {
-            outputs.push(TxOutput::new(accumulated - amount, from)) // to: 币收入
+        if accumulated > required {
+            let change = accumulated.checked_sub(required).unwrap();
+            outputs.push(TxOutput::new_with_script(change, from.clone()))
         }
-        // 4.生成交易
+        // 生成交易
         let mut tx = Transaction {
             id: vec![],
+            version: TX_VERSION,
             vin: inputs,
             vout: outputs,
+            lock_time: 0,
         };
         // 生成交易ID
         tx.id = tx.hash();
-        // 5.交易中的 TXInput 签名
-        tx.sign(utxo_set.get_blockchain(), wallet.get_pkcs8());
 
-        return tx;
+        tx
+    }
+
+    /// 使用指定钱包新建一笔发布data的交易, 只包含一个数据输出和找零
+    pub fn new_data_transaction(wallet: &Wallet, data: &[u8], fee: Amount, utxo_set: &UTXOSet) -> Self {
+        let from = wallet.get_script_pubkey();
+        // 至少花费一个输出, 使相同data的交易id不会重复
+        let (accumulated, inputs) = Self::select_inputs(&from, fee.max(Amount::from_sat(1)), utxo_set);
+
+        let mut outputs = vec![TxOutput::new_data(data)];
+        let change = accumulated.checked_sub(fee).unwrap();
+        if change > Amount::ZERO {
+            outputs.push(TxOutput::new_with_script(change, from));
+        }
+        let mut tx = Transaction {
+            id: vec![],
+            version: TX_VERSION,
+            vin: inputs,
+            vout: outputs,
+            lock_time: 0,
+        };
+        tx.id = tx.hash();
+        tx.sign(utxo_set, wallet);
+
+        tx
+    }
+
+    /// 选取由from锁定, 总额不少于required的输出作为交易输入, 返回(输出总额, 输入)
+    fn select_inputs(from: &Script, required: Amount, utxo_set: &UTXOSet) -> (Amount, Vec<TxInput>) {
+        let (accumulated, valid_outputs) = utxo_set.find_spendable_outputs(from, required);
+        if accumulated < required {
+            panic!("Error! not enough funds");
+        }
+
+        let mut inputs = vec![];
+        for (txid_hex, outs) in valid_outputs {
+            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
+            for out in outs {
+                inputs.push(TxInput::new(txid.as_slice(), out));
+            }
+        }
+        // 按引用的输出排序, 使相同输入生成相同的交易id
+        inputs.sort_by(|a, b| (&a.txid, a.outid).cmp(&(&b.txid, b.outid)));
+
+        (accumulated, inputs)
     }
 
     /// 序列化该交易为一个字节数组
@@ -171,38 +343,132 @@ impl Transaction {
         bincode::serialize(self).unwrap().to_vec()
     }
 
-    /// 反序列化, byte数组->交易
-    pub fn deserialize(bytes: &[u8]) -> Self {
-        bincode::deserialize(bytes).unwrap()
+    /// 反序列化, byte数组->交易, byte数组不合法时返回None
+    pub fn deserialize(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
     }
 
-    /// 对交易每个输入进行签名
-    pub fn sign(&mut self, blockchain: &Blockchain, pkcs8: &[u8]) {
+    /// 使用钱包私钥对交易每个输入进行签名, 输入引用的输出均为coins中的P2PKH输出
+    pub fn sign(&mut self, coins: &dyn CoinsView, wallet: &Wallet) {
+        for idx in 0..self.vin.len() {
+            self.sign_input(idx, coins, wallet, SIGHASH_ALL);
+        }
+    }
+
+    /// 使用钱包私钥以sighash_type对第idx个输入签名, 输入引用的输出为coins中的P2PKH输出
+    pub fn sign_input(&mut self, idx: usize, coins: &dyn CoinsView, wallet: &Wallet, sighash_type: u8) {
+        // 查找输入引用的未花费输出
+        let coin = coins.get_coin(self.vin[idx].get_txid(), self.vin[idx].outid)
+            .expect("ERROR: Previous output is not unspent");
+        let script_pubkey = coin.get_output().get_script_pubkey();
+
+        // 使用私钥对数据签名
+        let signature = self.create_signature(idx, script_pubkey, wallet, sighash_type);
+        self.vin[idx].script_sig = Script::new_p2pkh_unlock(signature.as_slice(), wallet.get_public_key());
+    }
+
+    /// 使用钱包私钥以sighash_type对第idx个输入的签名消息签名, 签名末尾附加sighash_type
+    pub fn create_signature(&self, idx: usize, script_code: &Script, wallet: &Wallet, sighash_type: u8) -> Vec<u8> {
+        let sighash = self.signature_hash(idx, script_code, sighash_type)
+            .expect("ERROR: invalid sighash type");
+        let mut signature = wallet.sign(sighash.as_slice());
+        signature.push(sighash_type);
+        signature
+    }
+
+    /// 第idx个输入的签名消息: 各输入的解锁脚本置空, 第idx个输入的解锁脚本替换为script_code,
+    /// 再按sighash_type去掉签名不覆盖的输入和输出, 最后附加sighash_type.
+    /// script_code为其引用输出的锁定脚本, P2SH输出则为赎回脚本.
+    /// sighash_type非法, 或为SIGHASH_SINGLE但没有对应的输出时返回None
+    pub fn signature_hash(&self, idx: usize, script_code: &Script, sighash_type: u8) -> Option<Vec<u8>> {
         let mut tx_copy = self.trimmed_copy();
+        tx_copy.vin[idx].script_sig = script_code.clone();
+        match sighash_type & !SIGHASH_ANYONECANPAY {
+            SIGHASH_ALL => {},
+            SIGHASH_NONE => tx_copy.vout.clear(),
+            SIGHASH_SINGLE => {
+                if idx >= tx_copy.vout.len() {
+                    return None;
+                }
+                // 之前的输出只保留位置
+                tx_copy.vout.truncate(idx + 1);
+                for out in tx_copy.vout.iter_mut().take(idx) {
+                    *out = TxOutput::new_with_script(Amount::ZERO, Script::default());
+                }
+            },
+            _ => return None,
+        }
+        if sighash_type & SIGHASH_ANYONECANPAY != 0 {
+            tx_copy.vin = vec![tx_copy.vin.swap_remove(idx)];
+        } else if sighash_type != SIGHASH_ALL {
+            // 不覆盖输出时, 其他输入的sequence也可以修改
+            for (i, vin) in tx_copy.vin.iter_mut().enumerate() {
+                if i != idx {
+                    vin.sequence = 0;
+                }
+            }
+        }
 
-        for (idx, vin) in self.vin.iter_mut().enumerate() {
-            // 查找输入引用的交易
-            let prev_tx_option = blockchain.find_transaction(vin.get_txid());
-            if prev_tx_option.is_none() {
-                panic!("ERROR: Previous transaction is not correct")
+        let mut data = Transaction { id: vec![], ..tx_copy }.serialize();
+        data.push(sighash_type);
+        Some(crate::sha256_digest(data.as_slice()))
+    }
+
+    /// 添加一个输入, 并重新计算交易id.
+    /// 以SIGHASH_ANYONECANPAY签名的输入不受影响, 其他输入需要重新签名
+    pub fn add_input(&mut self, txid: &[u8], vout: usize) -> usize {
+        self.vin.push(TxInput::new(txid, vout));
+        self.id = self.hash();
+        self.vin.len() - 1
+    }
+
+    /// 添加一个输出, 并重新计算交易id
+    pub fn add_output(&mut self, output: TxOutput) {
+        self.vout.push(output);
+        self.id = self.hash();
+    }
+
+    /// 设置第idx个输入的解锁脚本
+    pub fn set_script_sig(&mut self, idx: usize, script_sig: Script) {
+        self.vin[idx].script_sig = script_sig;
+    }
+
+    /// 设置绝对时间锁, sequence为SEQUENCE_FINAL的输入改为SEQUENCE_FINAL - 1, 使lock_time生效.
+    /// 会重新计算交易id, 须在签名前调用
+    pub fn set_lock_time(&mut self, lock_time: u64) {
+        self.lock_time = lock_time;
+        for vin in self.vin.iter_mut().filter(|vin| vin.sequence == SEQUENCE_FINAL) {
+            vin.sequence = SEQUENCE_FINAL - 1;
+        }
+        self.id = self.hash();
+    }
+
+    /// 设置第idx个输入的sequence. 会重新计算交易id, 须在签名前调用
+    pub fn set_sequence(&mut self, idx: usize, sequence: u32) {
+        self.vin[idx].sequence = sequence;
+        self.id = self.hash();
+    }
+
+    /// 计算交易id, 不包含非coinbase输入的解锁脚本, 签名的重新编码不会改变交易id.
+    /// coinbase的解锁脚本记录区块高度, 仍包含在内
+    pub fn hash(&self) -> Vec<u8> {
+        let mut tx_copy = Transaction {
+            id: vec![],
+            ..self.clone()
+        };
+        if !tx_copy.is_coinbase() {
+            for vin in tx_copy.vin.iter_mut() {
+                vin.script_sig = Script::default();
             }
-            let prev_tx = prev_tx_option.unwrap();
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.outid].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[idx].pub_key = vec![];
-
-            // 使用私钥对数据签名
-            vin.signature = crate::ecdsa_p256_sha256_sign_digest(pkcs8, tx_copy.get_id());
         }
+        crate::sha256_digest(tx_copy.serialize().as_slice())
     }
 
-    /// 计算交易hash
-    fn hash(&mut self) -> Vec<u8> {
+    /// 计算见证id(wtxid), 覆盖交易的全部内容, 包括解锁脚本
+    pub fn get_wtxid(&self) -> Vec<u8> {
         let tx_copy = Transaction {
             id: vec![],
-            vin: self.vin.clone(),
-            vout: self.vout.clone(),
+            ..self.clone()
         };
         crate::sha256_digest(tx_copy.serialize().as_slice())
     }
@@ -217,6 +483,16 @@ impl Transaction {
         self.id.clone()
     }
 
+    /// 获取交易版本
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    /// 获取绝对时间锁
+    pub fn get_lock_time(&self) -> u64 {
+        self.lock_time
+    }
+
     /// 获取交易输入
     pub fn get_vin(&self) -> &[TxInput] {
         self.vin.as_slice()
@@ -227,45 +503,355 @@ impl Transaction {
         self.vout.as_slice()
     }
 
-    /// 验证交易的签名是否合法
-    pub fn verify(&self, blockchain: &Blockchain) -> bool {
+    /// coinbase交易附带的extra-nonce或消息, 非coinbase交易返回None
+    pub fn get_coinbase_extra(&self) -> Option<&[u8]> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        self.vin[0].script_sig.get_push_data(1)
+    }
+
+    /// coinbase交易记录的区块高度, 非coinbase交易返回None
+    pub fn get_coinbase_height(&self) -> Option<usize> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let bytes = self.vin[0].script_sig.get_push_data(0)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?) as usize)
+    }
+
+    /// 交易输出总额, 溢出时返回None
+    pub fn get_output_value(&self) -> Option<Amount> {
+        Amount::checked_sum(self.vout.iter().map(|out| out.cost))
+    }
+
+    /// 交易输入总额, 即输入引用的coins中的输出总额, 引用的输出不存在或溢出时返回None
+    pub fn get_input_value(&self, coins: &dyn CoinsView) -> Option<Amount> {
+        let mut value = Amount::ZERO;
+        for vin in &self.vin {
+            let coin = coins.get_coin(vin.get_txid(), vin.outid)?;
+            value = value.checked_add(coin.get_output().cost)?;
+        }
+        Some(value)
+    }
+
+    /// 交易手续费 = 输入总额 - 输出总额, coinbase交易的手续费为0
+    /// 输入不存在, 溢出或输出总额超过输入总额时返回None
+    pub fn get_fee(&self, coins: &dyn CoinsView) -> Option<Amount> {
+        if self.is_coinbase() {
+            return Some(Amount::ZERO);
+        }
+        self.get_input_value(coins)?.checked_sub(self.get_output_value()?)
+    }
+
+    /// 交易序列化后的字节数
+    pub fn get_size(&self) -> usize {
+        bincode::serialized_size(self).unwrap() as usize
+    }
+
+    /// 验证交易: 每个输入引用的输出都在coins中, 且输入的解锁脚本能解锁该输出的锁定脚本
+    pub fn verify(&self, coins: &dyn CoinsView) -> bool {
+        self.verify_inputs(coins, None)
+    }
+
+    /// 同verify, 但花费Schnorr P2PKH输出的签名不立即验证, 交易的其他部分合法时加入batch.
+    /// batch.verify()也通过时交易才合法
+    pub fn verify_with_batch(&self, coins: &dyn CoinsView, batch: &mut BatchVerifier) -> bool {
+        let deferred = RefCell::new(BatchVerifier::new());
+        if !self.verify_inputs(coins, Some(&deferred)) {
+            return false;
+        }
+        batch.append(deferred.into_inner());
+        true
+    }
+
+    /// 验证各输入的脚本, deferred不为None时推迟验证P2PKH输出的Schnorr签名
+    fn verify_inputs(&self, coins: &dyn CoinsView, deferred: Option<&RefCell<BatchVerifier>>) -> bool {
         if self.is_coinbase() {
             return true;
         }
-        let mut tx_copy = self.trimmed_copy();
         for (idx, vin) in self.vin.iter().enumerate() {
-            let prev_tx = blockchain.find_transaction(vin.get_txid()).unwrap();
-
-            tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.outid].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[idx].pub_key = vec![];
-
-            let verify = crate::ecdsa_p256_sha256_sign_verify(
-                vin.pub_key.as_slice(),
-                vin.signature.as_slice(),
-                tx_copy.get_id(),
-            );
-            if !verify {
+            // 输入引用的输出必须未被花费
+            let coin = match coins.get_coin(vin.get_txid(), vin.outid) {
+                Some(coin) => coin,
+                None => return false,
+            };
+            let prev_out = coin.get_output();
+
+            // P2PKH锁定脚本以CheckSig结束, 签名是否合法即脚本的结果, 推迟验证不改变结果
+            let deferred = deferred.filter(|_| prev_out.get_script_pubkey().get_p2pkh().is_some());
+            let checker = TransactionSignatureChecker { tx: self, idx, deferred };
+            if script::verify_script(vin.get_script_sig(), prev_out.get_script_pubkey(), &checker).is_err() {
                 return false;
             }
         }
 
-        return true;
+        true
+    }
+
+    /// 能否被高度为height, 前一个区块中位时间为time的区块打包: 已到达lock_time, 或所有输入的sequence均为SEQUENCE_FINAL
+    pub fn is_final(&self, height: usize, time: u64) -> bool {
+        let reached = if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time <= height as u64
+        } else {
+            self.lock_time <= time
+        };
+        reached || self.vin.iter().all(|vin| vin.sequence == SEQUENCE_FINAL)
     }
 
     /// 是否为coinbase交易.
-    /// coinbase交易的输入为
+    /// coinbase交易只有一个输入, 且该输入引用空输出(全零的txid和索引NULL_OUTID)
     pub fn is_coinbase(&self) -> bool {
-        return self.vin.len() == 1 && self.vin[0].pub_key.len() == 0;
+        self.vin.len() == 1 && self.vin[0].txid == NULL_TXID && self.vin[0].outid == NULL_OUTID
     }
 
-    /// 修剪交易后的副本
+    /// 修剪交易后的副本, 清空所有解锁脚本
     fn trimmed_copy(&self) -> Transaction {
         Transaction {
-            id: self.id.clone(),
-            vin: self.vin.iter().cloned().map(|input|TxInput::new(input.get_txid(), input.get_outid())).collect(),
-            vout: self.vout.iter().cloned().collect(),
+            vin: self.vin.iter().map(|input| TxInput { script_sig: Script::default(), ..input.clone() }).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+/// 交易第idx个输入的签名校验器, 签名消息为该输入的signature_hash.
+/// deferred不为None时Schnorr签名只加入批量验证器, 并视为合法
+struct TransactionSignatureChecker<'a> {
+    tx: &'a Transaction,
+    idx: usize,
+    deferred: Option<&'a RefCell<BatchVerifier>>,
+}
+
+impl SignatureChecker for TransactionSignatureChecker<'_> {
+    /// 签名的最后一个字节为sighash_type
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8], script_code: &Script) -> bool {
+        let (sighash_type, signature) = match signature.split_last() {
+            Some(split) => split,
+            None => return false,
+        };
+        match self.tx.signature_hash(self.idx, script_code, *sighash_type) {
+            Some(sighash) => match self.deferred {
+                Some(batch) if SignatureScheme::from_public_key(pub_key) == Some(SignatureScheme::Schnorr) => {
+                    batch.borrow_mut().add(pub_key, signature, sighash.as_slice())
+                },
+                _ => SignatureScheme::verify(pub_key, signature, sighash.as_slice()),
+            },
+            None => false,
+        }
+    }
+
+    /// 交易的lock_time与lock_time同为高度或同为时间且不小于lock_time, 并且该输入不是SEQUENCE_FINAL
+    fn check_lock_time(&self, lock_time: u64) -> bool {
+        let tx_lock_time = self.tx.lock_time;
+        (lock_time < LOCKTIME_THRESHOLD) == (tx_lock_time < LOCKTIME_THRESHOLD)
+            && lock_time <= tx_lock_time
+            && self.tx.vin[self.idx].sequence != SEQUENCE_FINAL
+    }
+
+    /// sequence未启用相对时间锁时总是满足, 否则该输入的相对时间锁与之类型相同且不小于它
+    fn check_sequence(&self, sequence: u32) -> bool {
+        let lock = match RelativeLock::from_sequence(sequence) {
+            Some(lock) => lock,
+            None => return true,
+        };
+        match (lock, self.tx.vin[self.idx].get_relative_lock()) {
+            (RelativeLock::Blocks(a), Some(RelativeLock::Blocks(b))) => a <= b,
+            (RelativeLock::Time(a), Some(RelativeLock::Time(b))) => a <= b,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{mine_blocks, new_blockchain};
+
+    #[test]
+    fn test_halving_subsidy() {
+        let subsidy = |height| halving_subsidy(Amount::from_sat(50), 100, height).as_sat();
+        assert_eq!(subsidy(0), 50);
+        assert_eq!(subsidy(99), 50);
+        assert_eq!(subsidy(100), 25);
+        assert_eq!(subsidy(250), 12);
+        assert_eq!(subsidy(600), 0);
+        assert_eq!(halving_subsidy(Amount::from_sat(50), 1, usize::MAX), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_coinbase_height() {
+        let address = crate::wallet::Wallet::new().get_address();
+        let fees = Amount::from_sat(2);
+        let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), 7, fees);
+        assert_eq!(coinbase_tx.get_coinbase_height(), Some(7));
+        assert_eq!(coinbase_tx.get_output_value(), get_block_subsidy(7).checked_add(fees));
+        assert_eq!(coinbase_tx.get_coinbase_extra(), Some(&[][..]));
+
+        // 只有一个输入但引用的不是空输出, 不是coinbase交易
+        let mut tx = coinbase_tx.clone();
+        tx.vin[0].txid = vec![];
+        assert!(!tx.is_coinbase());
+        let mut tx = coinbase_tx;
+        tx.vin[0].outid = 0;
+        assert!(!tx.is_coinbase());
+        assert_eq!(tx.get_coinbase_height(), None);
+    }
+
+    #[test]
+    fn test_coinbase_deterministic() {
+        let address = crate::wallet::Wallet::new().get_address();
+        let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), 7, Amount::ZERO);
+        assert_eq!(coinbase_tx.get_id(), Transaction::new_coinbase_tx(address.as_str(), 7, Amount::ZERO).get_id());
+        assert_ne!(coinbase_tx.get_id(), Transaction::new_coinbase_tx(address.as_str(), 8, Amount::ZERO).get_id());
+
+        let extra = Transaction::new_coinbase_tx_with_extra(address.as_str(), 7, Amount::ZERO, b"hello");
+        assert_ne!(coinbase_tx.get_id(), extra.get_id());
+        assert_eq!(extra.get_coinbase_height(), Some(7));
+        assert_eq!(extra.get_coinbase_extra(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let address = crate::wallet::Wallet::new().get_address();
+        let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), 7, Amount::ZERO);
+        let decoded = Transaction::deserialize(coinbase_tx.serialize().as_slice());
+        assert_eq!(decoded.map(|tx| tx.get_id().to_vec()), Some(coinbase_tx.get_id().to_vec()));
+        assert!(Transaction::deserialize(&[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn test_lock_time_checker() {
+        let mut tx = Transaction {
+            version: TX_VERSION,
+            vin: vec![TxInput::new(b"txid", 0)],
+            ..Default::default()
+        };
+        let checker = |tx: &Transaction| TransactionSignatureChecker { tx, idx: 0, deferred: None }.check_lock_time(100);
+        assert!(tx.is_final(0, 0));
+
+        // lock_time须与脚本中的时间锁同类型且不小于它, 输入不能是SEQUENCE_FINAL
+        tx.lock_time = 100;
+        assert!(!checker(&tx));
+        assert!(tx.is_final(0, 0));
+        tx.set_lock_time(100);
+        assert!(checker(&tx));
+        assert!(!tx.is_final(99, u64::MAX));
+        assert!(tx.is_final(100, 0));
+        tx.set_lock_time(99);
+        assert!(!checker(&tx));
+        tx.set_lock_time(LOCKTIME_THRESHOLD);
+        assert!(!checker(&tx));
+        assert!(!tx.is_final(usize::MAX, LOCKTIME_THRESHOLD - 1));
+
+        // 相对时间锁须同类型且不小于脚本中的时间锁
+        let check_sequence = |tx: &Transaction, lock: RelativeLock| TransactionSignatureChecker { tx, idx: 0, deferred: None }.check_sequence(lock.to_sequence());
+        tx.set_sequence(0, RelativeLock::Blocks(10).to_sequence());
+        assert_eq!(tx.vin[0].get_relative_lock(), Some(RelativeLock::Blocks(10)));
+        assert!(check_sequence(&tx, RelativeLock::Blocks(10)));
+        assert!(!check_sequence(&tx, RelativeLock::Blocks(11)));
+        assert!(!check_sequence(&tx, RelativeLock::Time(1)));
+        tx.set_sequence(0, SEQUENCE_FINAL);
+        assert!(!check_sequence(&tx, RelativeLock::Blocks(0)));
+        assert!(RelativeLock::Time(1).is_satisfied(0, 1, 0, 1 + SEQUENCE_LOCKTIME_GRANULARITY));
+        assert!(!RelativeLock::Time(1).is_satisfied(0, 1, 0, SEQUENCE_LOCKTIME_GRANULARITY));
+    }
+
+    #[test]
+    fn test_sighash_types() {
+        let (wallet_a, wallet_b) = (crate::wallet::Wallet::new(), crate::wallet::Wallet::new());
+        let blockchain = new_blockchain(wallet_a.get_address().as_str());
+        let coinbase_a = Transaction::new_coinbase_tx(wallet_a.get_address().as_str(), 1, Amount::ZERO);
+        let coinbase_b = Transaction::new_coinbase_tx(wallet_b.get_address().as_str(), 2, Amount::ZERO);
+        blockchain.mine_block(std::slice::from_ref(&coinbase_a));
+        blockchain.mine_block(std::slice::from_ref(&coinbase_b));
+        let goal = get_block_subsidy(1).checked_add(get_block_subsidy(2)).unwrap();
+        let organizer = crate::wallet::Wallet::new().get_address();
+
+        // 众筹: 每个出资人添加输入后只对自己的输入签名
+        let mut tx = Transaction::new_with_outputs(vec![TxOutput::new(goal, organizer.as_str())]);
+        let idx = tx.add_input(coinbase_a.get_id(), 0);
+        tx.sign_input(idx, &blockchain, &wallet_a, SIGHASH_ALL | SIGHASH_ANYONECANPAY);
+        assert!(tx.verify(&blockchain));
+        let idx = tx.add_input(coinbase_b.get_id(), 0);
+        tx.sign_input(idx, &blockchain, &wallet_b, SIGHASH_ALL | SIGHASH_ANYONECANPAY);
+        assert!(tx.verify(&blockchain));
+        // 输出仍被签名覆盖
+        let mut changed = tx.clone();
+        changed.add_output(TxOutput::new(Amount::from_sat(1), organizer.as_str()));
+        assert!(!changed.verify(&blockchain));
+
+        // SIGHASH_ALL签名在添加输入后失效
+        let mut tx = Transaction::new_with_outputs(vec![TxOutput::new(goal, organizer.as_str())]);
+        let idx = tx.add_input(coinbase_a.get_id(), 0);
+        tx.sign_input(idx, &blockchain, &wallet_a, SIGHASH_ALL);
+        tx.add_input(coinbase_b.get_id(), 0);
+        tx.sign_input(1, &blockchain, &wallet_b, SIGHASH_ALL);
+        assert!(!tx.verify(&blockchain));
+
+        // SIGHASH_SINGLE只覆盖相同索引的输出, SIGHASH_NONE不覆盖输出
+        let mut tx = Transaction::new_with_outputs(vec![TxOutput::new(Amount::from_sat(1), organizer.as_str())]);
+        tx.add_input(coinbase_a.get_id(), 0);
+        tx.add_input(coinbase_b.get_id(), 0);
+        tx.sign_input(0, &blockchain, &wallet_a, SIGHASH_SINGLE);
+        tx.sign_input(1, &blockchain, &wallet_b, SIGHASH_NONE);
+        tx.add_output(TxOutput::new(Amount::from_sat(2), organizer.as_str()));
+        assert!(tx.verify(&blockchain));
+        tx.vout[0] = TxOutput::new(Amount::from_sat(3), organizer.as_str());
+        assert!(!tx.verify(&blockchain));
+
+        // 非法的sighash类型, 或SIGHASH_SINGLE没有对应的输出
+        assert_eq!(tx.signature_hash(0, &Script::default(), 0x04), None);
+        assert_eq!(tx.signature_hash(0, &Script::default(), 0), None);
+        let single = Transaction::new_with_outputs(vec![]);
+        let mut single = Transaction { vin: vec![TxInput::new(b"txid", 0)], ..single };
+        assert_eq!(single.signature_hash(0, &Script::default(), SIGHASH_SINGLE), None);
+        single.add_output(TxOutput::new(Amount::ZERO, organizer.as_str()));
+        assert!(single.signature_hash(0, &Script::default(), SIGHASH_SINGLE).is_some());
+    }
+
+    #[test]
+    fn test_malleability() {
+        let wallet = crate::wallet::Wallet::new();
+        let blockchain = new_blockchain(wallet.get_address().as_str());
+        let coinbase = Transaction::new_coinbase_tx(wallet.get_address().as_str(), 1, Amount::ZERO);
+        blockchain.mine_block(std::slice::from_ref(&coinbase));
+
+        let mut tx = Transaction::new_with_outputs(vec![TxOutput::new(Amount::from_sat(1), wallet.get_address().as_str())]);
+        tx.add_input(coinbase.get_id(), 0);
+        tx.sign(&blockchain, &wallet);
+        let (txid, wtxid) = (tx.get_id().to_vec(), tx.get_wtxid());
+        // 重新签名: 交易id不变, 见证id改变
+        tx.sign(&blockchain, &wallet);
+        assert!(tx.verify(&blockchain));
+        assert_eq!(tx.get_id(), txid.as_slice());
+        assert_eq!(tx.hash(), txid);
+        assert_ne!(tx.get_wtxid(), wtxid);
+    }
+
+    #[test]
+    fn test_signature_schemes() {
+        let schemes = [SignatureScheme::EcdsaP256, SignatureScheme::Ed25519, SignatureScheme::Secp256k1, SignatureScheme::Schnorr];
+        let wallets: Vec<Wallet> = schemes.iter().map(|scheme| Wallet::new_with_scheme(*scheme)).collect();
+        let blockchain = new_blockchain(wallets[0].get_address().as_str());
+        mine_blocks(&blockchain, Wallet::new().get_address().as_str(), GLOBAL_CONFIG.get_coinbase_maturity());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+
+        // 资金依次转给下一种签名算法的钱包, 每一笔交易由上一种算法签名
+        let amount = Amount::from_sat(1000);
+        for (from, to) in wallets.iter().zip(wallets.iter().skip(1)) {
+            assert_eq!(Script::from_address(to.get_address().as_str()), Some(to.get_script_pubkey()));
+            let tx = Transaction::new_utxo_transaction_from_wallet(from, to.get_address().as_str(), amount, Amount::ZERO, &utxo_set);
+            assert!(tx.verify(&blockchain));
+            blockchain.mine_block(std::slice::from_ref(&tx));
         }
+        let last = wallets.last().unwrap();
+        let tx = Transaction::new_utxo_transaction_from_wallet(last, wallets[0].get_address().as_str(), amount, Amount::ZERO, &utxo_set);
+        assert!(tx.verify(&blockchain));
+
+        // 替换为其他算法的签名后验证失败
+        let mut forged = tx.clone();
+        let signature = forged.create_signature(0, &last.get_script_pubkey(), &wallets[1], SIGHASH_ALL);
+        forged.set_script_sig(0, Script::new_p2pkh_unlock(signature.as_slice(), last.get_public_key()));
+        assert!(!forged.verify(&blockchain));
     }
 }
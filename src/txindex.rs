@@ -0,0 +1,186 @@
+// txindex.rs
+//
+
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree, UnabortableTransactionError};
+use crate::{
+    Blockchain,
+    block::Block
+};
+
+pub(crate) const TXINDEX_TREE: &str = "txindex";
+/// 索引已同步到的区块hash
+const BEST_BLOCK_HASH_KEY: &str = "best_block_hash";
+
+/// 交易索引: txid -> (区块hash, 交易在区块中的位置)
+/// 区块链开启交易索引时随主链的连接和断开维护, 只有同步到主链最新区块时才用于查找交易
+pub struct TxIndex {
+    blockchain: Blockchain,
+}
+
+impl TxIndex {
+
+    /// 新建一个交易索引
+    pub fn new(blockchain: Blockchain) -> Self {
+        TxIndex{blockchain}
+    }
+
+    fn get_txindex_tree(&self) -> sled::Tree {
+        self.blockchain.get_db().open_tree(TXINDEX_TREE).unwrap()
+    }
+
+    /// 索引是否已同步到主链最新区块
+    pub fn is_synced(&self) -> bool {
+        get_best_block_hash(&self.get_txindex_tree()) == Some(self.blockchain.get_tip_hash())
+    }
+
+    /// 查找交易所在的(区块hash, 位置)
+    pub fn get_location(&self, txid: &[u8]) -> Option<(String, usize)> {
+        get_location(&self.get_txindex_tree(), txid)
+    }
+
+    /// 连接区块: 索引区块中的交易, 索引不在该区块的前一个区块时不再维护
+    pub fn connect_block(&self, block: &Block) {
+        if !self.blockchain.is_txindex_enabled() {
+            return;
+        }
+        self.get_txindex_tree()
+            .transaction(|txindex_tree| -> ConflictableTransactionResult<()> {
+                Ok(Self::connect(txindex_tree, block)?)
+            })
+            .expect("unable to connect the block to the transaction index");
+    }
+
+    /// 断开区块: 删除区块中交易的索引, 索引不在该区块时不再维护
+    pub fn disconnect_block(&self, block: &Block) {
+        if !self.blockchain.is_txindex_enabled() {
+            return;
+        }
+        self.get_txindex_tree()
+            .transaction(|txindex_tree| -> ConflictableTransactionResult<()> {
+                Ok(Self::disconnect(txindex_tree, block)?)
+            })
+            .expect("unable to disconnect the block from the transaction index");
+    }
+
+    /// 在事务中连接区块
+    pub(crate) fn connect(txindex_tree: &TransactionalTree, block: &Block) -> Result<(), UnabortableTransactionError> {
+        let synced = match txindex_tree.get(BEST_BLOCK_HASH_KEY)? {
+            Some(best) => best == block.get_pre_block_hash().as_bytes(),
+            None => block.get_height() == 0,
+        };
+        if !synced {
+            return Ok(());
+        }
+        for (position, tx) in block.get_transactions().iter().enumerate() {
+            let location = bincode::serialize(&(block.get_hash(), position)).unwrap();
+            txindex_tree.insert(tx.get_id(), location)?;
+        }
+        txindex_tree.insert(BEST_BLOCK_HASH_KEY, block.get_hash())?;
+        Ok(())
+    }
+
+    /// 在事务中断开区块
+    pub(crate) fn disconnect(txindex_tree: &TransactionalTree, block: &Block) -> Result<(), UnabortableTransactionError> {
+        if txindex_tree.get(BEST_BLOCK_HASH_KEY)?.as_deref() != Some(block.get_hash().as_bytes()) {
+            return Ok(());
+        }
+        for tx in block.get_transactions() {
+            txindex_tree.remove(tx.get_id())?;
+        }
+        txindex_tree.insert(BEST_BLOCK_HASH_KEY, block.get_pre_block_hash().as_str())?;
+        Ok(())
+    }
+
+    /// 重建索引: 从创世区块开始依次索引主链上的区块.
+    /// 先删除索引已同步到的区块hash, 重建中断时索引与tip不一致, 下次启动重新重建
+    pub fn reindex(&self) -> sled::Result<()> {
+        let txindex_tree = self.get_txindex_tree();
+        txindex_tree.remove(BEST_BLOCK_HASH_KEY)?;
+        txindex_tree.clear()?;
+
+        let mut blocks = vec![];
+        let mut iterator = self.blockchain.iterator();
+        while let Some(block) = iterator.next() {
+            blocks.push(block);
+        }
+        for block in blocks.iter().rev() {
+            self.index_block(block)?;
+        }
+        Ok(())
+    }
+
+    /// 索引的交易数
+    pub fn count_transactions(&self) -> usize {
+        let txindex_tree = self.get_txindex_tree();
+        txindex_tree.len() - usize::from(txindex_tree.contains_key(BEST_BLOCK_HASH_KEY).unwrap())
+    }
+
+    fn index_block(&self, block: &Block) -> sled::Result<()> {
+        let txindex_tree = self.get_txindex_tree();
+        for (position, tx) in block.get_transactions().iter().enumerate() {
+            let location = bincode::serialize(&(block.get_hash(), position)).unwrap();
+            txindex_tree.insert(tx.get_id(), location)?;
+        }
+        txindex_tree.insert(BEST_BLOCK_HASH_KEY, block.get_hash())?;
+        Ok(())
+    }
+}
+
+/// 获取索引已同步到的区块hash
+pub(crate) fn get_best_block_hash(txindex_tree: &sled::Tree) -> Option<String> {
+    txindex_tree.get(BEST_BLOCK_HASH_KEY)
+        .unwrap()
+        .map(|bytes| String::from_utf8(bytes.to_vec()).unwrap())
+}
+
+/// 查找交易所在的(区块hash, 位置)
+pub(crate) fn get_location(txindex_tree: &sled::Tree, txid: &[u8]) -> Option<(String, usize)> {
+    txindex_tree.get(txid)
+        .unwrap()
+        .map(|bytes| bincode::deserialize(bytes.as_ref()).expect("unable to deserialize tx location"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, ProofOfWork, Transaction, test_utils::temporary_db, wallet::Wallet};
+
+    #[test]
+    fn test_txindex() {
+        let address = Wallet::new().get_address();
+        let blockchain = Blockchain::create_blockchain_with_indexes(temporary_db(), address.as_str(), true, false);
+        let txindex = TxIndex::new(blockchain.clone());
+        assert!(txindex.is_synced());
+        assert_eq!(txindex.count_transactions(), 1);
+
+        let genesis_hash = blockchain.get_tip_hash();
+        let a1 = blockchain.mine_block(&[Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO)]);
+        let a1_txid = a1.get_transactions()[0].get_id();
+        assert!(txindex.is_synced());
+        assert_eq!(txindex.get_location(a1_txid), Some((String::from(a1.get_hash()), 0)));
+        assert_eq!(blockchain.find_transaction_block(a1_txid).map(|block| block.get_hash_bytes()), Some(a1.get_hash_bytes()));
+
+        // 切换到更长的分支, 断开a1
+        let bits = ProofOfWork::initial_bits();
+        let b1_coinbase = Transaction::new_coinbase_tx_with_extra(address.as_str(), 1, Amount::ZERO, b"b");
+        let b1 = Block::new(genesis_hash, &[b1_coinbase], 1, bits);
+        blockchain.add_block(&b1).unwrap();
+        let b2 = Block::new(String::from(b1.get_hash()), &[Transaction::new_coinbase_tx(address.as_str(), 2, Amount::ZERO)], 2, bits);
+        blockchain.add_block(&b2).unwrap();
+        let b2_txid = b2.get_transactions()[0].get_id();
+        assert!(txindex.is_synced());
+        assert_eq!(txindex.get_location(a1_txid), None);
+        assert!(blockchain.find_transaction(a1_txid).is_none());
+        assert_eq!(txindex.get_location(b2_txid), Some((String::from(b2.get_hash()), 0)));
+        assert_eq!(txindex.count_transactions(), 3);
+
+        // 索引未同步时遍历链查找, 重建后恢复
+        blockchain.get_db().open_tree(TXINDEX_TREE).unwrap().clear().unwrap();
+        assert!(!txindex.is_synced());
+        assert!(blockchain.find_transaction(b2_txid).is_some());
+        txindex.reindex().unwrap();
+        assert!(txindex.is_synced());
+        assert_eq!(txindex.count_transactions(), 3);
+        assert_eq!(txindex.get_location(b2_txid), Some((String::from(b2.get_hash()), 0)));
+    }
+}
@@ -4,14 +4,26 @@
 use crypto::digest::Digest;
 use ring::{
     digest::{Context, SHA256},
-    rand::SystemRandom,
+    rand::{SecureRandom, SystemRandom},
     signature::{
         EcdsaKeyPair,
+        Ed25519KeyPair,
         ECDSA_P256_SHA256_FIXED_SIGNING,
-        ECDSA_P256_SHA256_FIXED
+        ECDSA_P256_SHA256_FIXED,
+        ED25519
     }
 };
-use std::{time::{SystemTime, UNIX_EPOCH}, iter::repeat};
+use k256::ecdsa::{
+    signature::{Signer, Verifier},
+    Signature,
+    SigningKey,
+    VerifyingKey
+};
+use schnorrkel::{signing_context, MiniSecretKey, PublicKey, ExpansionMode};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Schnorr签名的上下文, 区分其他用途的签名
+const SCHNORR_CONTEXT: &[u8] = b"blockchain transaction";
 
 /// 当前时间戳
 pub fn current_timestamp() -> u64 {
@@ -34,7 +46,7 @@ pub fn ripemd160_digest(data: &[u8]) -> Vec<u8> {
     let mut ripemd160 = crypto::ripemd160::Ripemd160::new();
     ripemd160.input(data);
 
-    let mut buf: Vec<u8> = repeat(0).take(ripemd160.output_bytes()).collect();
+    let mut buf: Vec<u8> = std::iter::repeat_n(0, ripemd160.output_bytes()).collect();
     ripemd160.result(&mut buf);
 
     buf
@@ -57,6 +69,13 @@ pub fn new_key_pair() -> Vec<u8> {
     pkcs8.as_ref().to_vec()
 }
 
+/// 生成32字节的随机秘密值
+pub fn new_secret() -> Vec<u8> {
+    let mut secret = vec![0; 32];
+    SystemRandom::new().fill(secret.as_mut_slice()).unwrap();
+    secret
+}
+
 /// 使用私钥,计算消息签名摘要
 pub fn ecdsa_p256_sha256_sign_digest(pkcs8: &[u8], message: &[u8]) -> Vec<u8>  {
     let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8).unwrap();
@@ -69,3 +88,91 @@ pub fn ecdsa_p256_sha256_sign_verify(pub_key: &[u8], signature: &[u8], message:
     let peer_pub_key = ring::signature::UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, pub_key);
     peer_pub_key.verify(message, signature.as_ref()).is_ok()
 }
+
+/// 新建Ed25519的pkcs8密钥对
+pub fn new_ed25519_key_pair() -> Vec<u8> {
+    let rng = SystemRandom::new();
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    pkcs8.as_ref().to_vec()
+}
+
+/// 使用Ed25519私钥, 计算消息签名
+pub fn ed25519_sign(pkcs8: &[u8], message: &[u8]) -> Vec<u8> {
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).unwrap();
+    key_pair.sign(message).as_ref().to_vec()
+}
+
+/// 使用Ed25519公钥, 验证消息签名是否合法
+pub fn ed25519_verify(pub_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+    let peer_pub_key = ring::signature::UnparsedPublicKey::new(&ED25519, pub_key);
+    peer_pub_key.verify(message, signature).is_ok()
+}
+
+/// 新建secp256k1私钥, 即32字节的标量
+pub fn new_secp256k1_key() -> Vec<u8> {
+    loop {
+        let secret = new_secret();
+        if SigningKey::from_slice(secret.as_slice()).is_ok() {
+            return secret;
+        }
+    }
+}
+
+/// secp256k1私钥对应的压缩公钥
+pub fn secp256k1_public_key(secret: &[u8]) -> Vec<u8> {
+    let signing_key = SigningKey::from_slice(secret).unwrap();
+    signing_key.verifying_key().to_sec1_bytes().to_vec()
+}
+
+/// 使用secp256k1私钥, 计算消息的ecdsa-sha256签名
+pub fn secp256k1_sign(secret: &[u8], message: &[u8]) -> Vec<u8> {
+    let signing_key = SigningKey::from_slice(secret).unwrap();
+    let signature: Signature = signing_key.sign(message);
+    signature.to_bytes().to_vec()
+}
+
+/// 使用secp256k1公钥, 验证消息签名是否合法, 只接受low-S签名
+pub fn secp256k1_verify(pub_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+    match (VerifyingKey::from_sec1_bytes(pub_key), Signature::from_slice(signature)) {
+        (Ok(verifying_key), Ok(signature)) => verifying_key.verify(message, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+/// Schnorr(sr25519)私钥对应的公钥
+pub fn schnorr_public_key(secret: &[u8]) -> Vec<u8> {
+    let mini_secret = MiniSecretKey::from_bytes(secret).unwrap();
+    mini_secret.expand_to_public(ExpansionMode::Ed25519).to_bytes().to_vec()
+}
+
+/// 使用Schnorr(sr25519)私钥, 计算消息签名
+pub fn schnorr_sign(secret: &[u8], message: &[u8]) -> Vec<u8> {
+    let key_pair = MiniSecretKey::from_bytes(secret).unwrap().expand_to_keypair(ExpansionMode::Ed25519);
+    key_pair.sign(signing_context(SCHNORR_CONTEXT).bytes(message)).to_bytes().to_vec()
+}
+
+/// 使用Schnorr(sr25519)公钥, 验证消息签名是否合法
+pub fn schnorr_verify(pub_key: &[u8], signature: &[u8], message: &[u8]) -> bool {
+    match (PublicKey::from_bytes(pub_key), schnorrkel::Signature::from_bytes(signature)) {
+        (Ok(pub_key), Ok(signature)) => pub_key.verify_simple(SCHNORR_CONTEXT, message, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+/// 批量验证(公钥, 签名, 消息)形式的Schnorr签名, 全部合法时返回true
+pub fn schnorr_verify_batch(items: &[(Vec<u8>, Vec<u8>, Vec<u8>)]) -> bool {
+    let mut pub_keys = Vec::with_capacity(items.len());
+    let mut signatures = Vec::with_capacity(items.len());
+    for (pub_key, signature, _) in items {
+        match (PublicKey::from_bytes(pub_key), schnorrkel::Signature::from_bytes(signature)) {
+            (Ok(pub_key), Ok(signature)) => {
+                pub_keys.push(pub_key);
+                signatures.push(signature);
+            },
+            _ => return false,
+        }
+    }
+    let context = signing_context(SCHNORR_CONTEXT);
+    let transcripts = items.iter().map(|(_, _, message)| context.bytes(message));
+    schnorrkel::verify_batch(transcripts, signatures.as_slice(), pub_keys.as_slice(), false).is_ok()
+}
@@ -2,14 +2,180 @@
 //
 
 use crate::{
+    Amount,
     Blockchain, 
+    ChainError,
+    GLOBAL_CONFIG,
+    Script,
+    Transaction,
     block::Block, 
-    transaction::TxOutput
+    transaction::TxOutput,
+    validation::ValidationError
 };
 use data_encoding::HEXLOWER;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError,
+    ConflictableTransactionResult,
+    TransactionalTree,
+    Transactional,
+    UnabortableTransactionError
+};
+use std::collections::{HashMap, HashSet};
+
+pub(crate) const UTXO_TREE: &str = "chainstate";
+pub(crate) const UNDO_TREE: &str = "undo";
+/// utxo set所在的区块hash, 与txid + 输出索引的键长度不同
+const BEST_BLOCK_HASH_KEY: &str = "best_block_hash";
+/// utxo set格式版本所在的键
+const VERSION_KEY: &str = "version";
+/// utxo set及undo记录的格式版本, 修改格式时递增, 启动时版本不符则重建
+const CHAINSTATE_VERSION: u32 = 2;
+
+/// utxo set中的未花费输出, 以(txid, 输出索引)为键
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Coin {
+    output: TxOutput,       // 未花费的输出
+    height: usize,          // 输出所在区块的高度
+    is_coinbase: bool,      // 是否为coinbase交易的输出
+}
+
+impl Coin {
+    pub fn get_output(&self) -> &TxOutput {
+        &self.output
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.is_coinbase
+    }
+
+    /// 能否被高度为height的区块中的交易花费
+    pub fn is_mature(&self, height: usize) -> bool {
+        !self.is_coinbase || height >= self.height + GLOBAL_CONFIG.get_coinbase_maturity()
+    }
+}
+
+/// 被交易输入花费的输出
+#[derive(Serialize, Deserialize)]
+struct SpentOutput {
+    txid: Vec<u8>,          // 输出所在交易的id
+    outid: usize,           // 输出在交易中的索引
+    coin: Coin,             // 被花费的输出
+}
+
+/// 交易的undo记录
+#[derive(Serialize, Deserialize)]
+struct TxUndo {
+    txid: Vec<u8>,              // 交易id
+    spent: Vec<SpentOutput>,    // 交易花费的输出
+}
+
+/// 区块的undo记录, 用于分叉切换时从utxo set中断开该区块
+#[derive(Default, Serialize, Deserialize)]
+struct BlockUndo {
+    txs: Vec<TxUndo>,
+}
+
+/// utxo set的键: txid + 输出索引(u32大端序), 同一交易的输出相邻且按索引排序
+fn outpoint_key(txid: &[u8], outid: usize) -> Vec<u8> {
+    [txid, &(outid as u32).to_be_bytes()].concat()
+}
+
+/// 未花费输出的视图, 交易的输入只能花费视图中的输出
+pub trait CoinsView {
+    /// 查找交易txid中索引为outid的未花费输出
+    fn get_coin(&self, txid: &[u8], outid: usize) -> Option<Coin>;
+}
+
+/// 主链的utxo set
+impl CoinsView for Blockchain {
+    fn get_coin(&self, txid: &[u8], outid: usize) -> Option<Coin> {
+        UTXOSet::new(self.clone()).get_coin(txid, outid)
+    }
+}
+
+impl CoinsView for UTXOSet {
+    fn get_coin(&self, txid: &[u8], outid: usize) -> Option<Coin> {
+        UTXOSet::get_coin(self, txid, outid)
+    }
+}
+
+/// 叠加在base上的视图, 记录尚未写入base的交易新增和花费的输出, 用于校验花费同一区块或内存池中交易输出的交易
+pub struct CoinsViewCache<'a> {
+    base: &'a dyn CoinsView,
+    added: HashMap<Vec<u8>, Coin>,  // 新增的输出
+    spent: HashSet<Vec<u8>>,        // 花费的输出
+}
 
-const UTXO_TREE: &str = "chainstate";
+impl<'a> CoinsViewCache<'a> {
+    /// 新建一个叠加在base上的视图
+    pub fn new(base: &'a dyn CoinsView) -> Self {
+        CoinsViewCache { base, added: HashMap::new(), spent: HashSet::new() }
+    }
+
+    /// 执行交易: 花费交易输入引用的输出, 再加入交易的输出, height为交易所在区块的高度
+    pub fn apply(&mut self, tx: &Transaction, height: usize) {
+        if !tx.is_coinbase() {
+            for txin in tx.get_vin() {
+                let key = outpoint_key(txin.get_txid(), txin.get_outid());
+                self.added.remove(&key);
+                self.spent.insert(key);
+            }
+        }
+        self.add_outputs(tx, height);
+    }
+
+    /// 只加入交易的输出, 不花费其输入, 不可花费的数据输出除外
+    pub fn add_outputs(&mut self, tx: &Transaction, height: usize) {
+        for (outid, out) in tx.get_vout().iter().enumerate() {
+            if out.is_unspendable() {
+                continue;
+            }
+            let coin = Coin {
+                output: out.clone(),
+                height,
+                is_coinbase: tx.is_coinbase(),
+            };
+            self.added.insert(outpoint_key(tx.get_id(), outid), coin);
+        }
+    }
+
+    /// 断开区块: 删除区块上交易创建的输出, 按undo记录恢复交易花费的输出.
+    /// 须从高到低依次断开且不与apply混用, 之后执行的交易放在叠加于其上的视图中
+    pub(crate) fn disconnect_block(&mut self, utxo_set: &UTXOSet, block: &Block) {
+        let block_undo = utxo_set.get_block_undo(block).expect("the undo data of block is not found");
+        for (tx, tx_undo) in block.get_transactions().iter().zip(block_undo.txs).rev() {
+            for outid in 0..tx.get_vout().len() {
+                let key = outpoint_key(tx.get_id(), outid);
+                self.added.remove(&key);
+                self.spent.insert(key);
+            }
+            for spent in tx_undo.spent.into_iter().rev() {
+                let key = outpoint_key(spent.txid.as_slice(), spent.outid);
+                self.spent.remove(&key);
+                self.added.insert(key, spent.coin);
+            }
+        }
+    }
+}
+
+/// 已花费的输出优先, 因此执行交易的顺序不影响结果
+impl CoinsView for CoinsViewCache<'_> {
+    fn get_coin(&self, txid: &[u8], outid: usize) -> Option<Coin> {
+        let key = outpoint_key(txid, outid);
+        if self.spent.contains(&key) {
+            return None;
+        }
+        match self.added.get(&key) {
+            Some(coin) => Some(coin.clone()),
+            None => self.base.get_coin(txid, outid),
+        }
+    }
+}
 
 /// UTXO(Unspent Transaction Output)集合
 pub struct UTXOSet {
@@ -28,44 +194,154 @@ impl UTXOSet {
         &self.blockchain
     }
 
-    /// 通过block上的交易,更新utxo set
-    pub fn update(&self, block: Block) {
-        let utxo_tree = self.get_utxo_tree();
+    /// 通过block上的交易,更新utxo set, 并保存该block的undo记录
+    pub fn update(&self, block: &Block) -> Result<(), ChainError> {
+        (&self.get_utxo_tree(), &self.get_undo_tree())
+            .transaction(|(utxo_tree, undo_tree)| Self::connect(utxo_tree, undo_tree, block))
+            .map_err(ChainError::from)
+    }
+
+    /// 根据block的undo记录, 从utxo set中撤销该block上的交易
+    pub fn disconnect_block(&self, block: &Block) {
+        (&self.get_utxo_tree(), &self.get_undo_tree())
+            .transaction(|(utxo_tree, undo_tree)| -> ConflictableTransactionResult<()> {
+                Ok(Self::disconnect(utxo_tree, undo_tree, block)?)
+            })
+            .expect("unable to disconnect the block from the utxo set");
+    }
+
+    /// 在事务中连接区块: 删除被花费的输出, 加入新的输出, 保存undo记录并更新utxo set所在的区块.
+    /// 被花费的输出不在utxo set中时中止事务
+    pub(crate) fn connect(utxo_tree: &TransactionalTree, undo_tree: &TransactionalTree, block: &Block) -> ConflictableTransactionResult<(), ValidationError> {
+        let mut block_undo = BlockUndo::default();
         for tx in block.get_transactions() {
-            // 处理非coinbase tx 交易输入
+            let mut tx_undo = TxUndo {
+                txid: tx.get_id_bytes(),
+                spent: vec![],
+            };
+            // 处理非coinbase tx 交易输入, 从utxo set中删除被花费的输出
             if !tx.is_coinbase() { 
                 for txin in tx.get_vin() {
-                    let mut updated_outs = vec![];
-                    let out_bytes = utxo_tree.get(txin.get_txid()).unwrap().unwrap();
-                    let outs: Vec<TxOutput> = bincode::deserialize(out_bytes.as_ref()).expect("");
-                    for (idx, out) in outs.iter().enumerate() {
-                        if idx != txin.get_outid() {
-                            updated_outs.push(out.clone());
-                        }
-                    }
-
-                    if updated_outs.len() == 0 { //如果输入对应的输出个数为0, 则从db中删除
-                        let _ = utxo_tree.remove(txin.get_txid());
-                    } else { //否则更新交易对应的output
-                        let out_bytes = bincode::serialize(&updated_outs).expect("serialize updated outs failed");
-                        let _ = utxo_tree.insert(txin.get_txid(), out_bytes);
-                    }
+                    let key = outpoint_key(txin.get_txid(), txin.get_outid());
+                    let coin_bytes = match utxo_tree.remove(key)? {
+                        Some(coin_bytes) => coin_bytes,
+                        None => return Err(ConflictableTransactionError::Abort(ValidationError::MissingInput {
+                            txid: HEXLOWER.encode(txin.get_txid()),
+                            outid: txin.get_outid(),
+                        })),
+                    };
+                    tx_undo.spent.push(SpentOutput {
+                        txid: txin.get_txid().to_vec(),
+                        outid: txin.get_outid(),
+                        coin: bincode::deserialize(coin_bytes.as_ref()).expect("unable to deserialize Coin"),
+                    });
                 }
             }
-            // 处理output
-            let mut new_outputs = vec![];
-            for out in tx.get_vout() {
-                new_outputs.push(out.clone())
+            // 处理output, 不可花费的数据输出不进入utxo set
+            for (outid, out) in tx.get_vout().iter().enumerate() {
+                if out.is_unspendable() {
+                    continue;
+                }
+                let coin = Coin {
+                    output: out.clone(),
+                    height: block.get_height(),
+                    is_coinbase: tx.is_coinbase(),
+                };
+                let coin_bytes = bincode::serialize(&coin).expect("unable to serialize Coin");
+                utxo_tree.insert(outpoint_key(tx.get_id(), outid), coin_bytes)?;
             }
-            let outs_bytes =
-                bincode::serialize(&new_outputs).expect("unable to serialize TXOutput");
-            let _ = utxo_tree.insert(tx.get_id(), outs_bytes).unwrap();
+            block_undo.txs.push(tx_undo);
         }
+
+        let undo_bytes = bincode::serialize(&block_undo).expect("unable to serialize BlockUndo");
+        undo_tree.insert(block.get_hash(), undo_bytes)?;
+        utxo_tree.insert(BEST_BLOCK_HASH_KEY, block.get_hash())?;
+        utxo_tree.insert(VERSION_KEY, &CHAINSTATE_VERSION.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// 在事务中断开区块: 根据undo记录撤销区块上的交易, utxo set回到前一个区块
+    pub(crate) fn disconnect(utxo_tree: &TransactionalTree, undo_tree: &TransactionalTree, block: &Block) -> Result<(), UnabortableTransactionError> {
+        let undo_bytes = undo_tree.get(block.get_hash())?
+            .expect("the undo data of block is not found");
+        let block_undo: BlockUndo = bincode::deserialize(undo_bytes.as_ref()).expect("unable to deserialize BlockUndo");
+
+        // 按与连接时相反的顺序撤销: 删除交易创建的输出, 恢复交易花费的输出
+        for (tx, tx_undo) in block.get_transactions().iter().zip(block_undo.txs.iter()).rev() {
+            for outid in 0..tx.get_vout().len() {
+                utxo_tree.remove(outpoint_key(tx.get_id(), outid))?;
+            }
+            for spent in tx_undo.spent.iter().rev() {
+                let coin_bytes = bincode::serialize(&spent.coin).expect("unable to serialize Coin");
+                utxo_tree.insert(outpoint_key(spent.txid.as_slice(), spent.outid), coin_bytes)?;
+            }
+        }
+        undo_tree.remove(block.get_hash())?;
+        utxo_tree.insert(BEST_BLOCK_HASH_KEY, block.get_pre_block_hash().as_str())?;
+        Ok(())
+    }
+
+    /// 读取block的undo记录, 返回各交易花费的输出, 按交易在区块中的顺序排列
+    pub fn get_spent_outputs(&self, block: &Block) -> Option<Vec<Vec<TxOutput>>> {
+        let undo_bytes = self.get_undo_tree().get(block.get_hash()).unwrap()?;
+        Some(Self::decode_spent_outputs(undo_bytes.as_ref()))
+    }
+
+    /// 读取block的undo记录
+    fn get_block_undo(&self, block: &Block) -> Option<BlockUndo> {
+        let undo_bytes = self.get_undo_tree().get(block.get_hash()).unwrap()?;
+        Some(bincode::deserialize(undo_bytes.as_ref()).expect("unable to deserialize BlockUndo"))
+    }
+
+    /// 从区块的undo记录中取出各交易花费的输出
+    pub(crate) fn decode_spent_outputs(undo_bytes: &[u8]) -> Vec<Vec<TxOutput>> {
+        let block_undo: BlockUndo = bincode::deserialize(undo_bytes).expect("unable to deserialize BlockUndo");
+        block_undo.txs.into_iter()
+            .map(|tx_undo| tx_undo.spent.into_iter().map(|spent| spent.coin.output).collect())
+            .collect()
+    }
+
+    /// 获取utxo set所在的区块hash
+    fn get_best_block_hash(&self) -> Option<String> {
+        self.get_utxo_tree()
+            .get(BEST_BLOCK_HASH_KEY)
+            .unwrap()
+            .map(|bytes| String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    /// 获取utxo set的格式版本, 旧版本没有记录版本
+    fn get_version(&self) -> Option<u32> {
+        self.get_utxo_tree()
+            .get(VERSION_KEY)
+            .unwrap()
+            .map(|bytes| u32::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    /// utxo set是否为当前格式且与主链最新区块一致
+    pub fn is_synced(&self) -> bool {
+        self.get_version() == Some(CHAINSTATE_VERSION)
+            && self.get_best_block_hash() == Some(self.blockchain.get_tip_hash())
+    }
+
+    /// 查找交易txid中索引为outid的未花费输出
+    pub fn get_coin(&self, txid: &[u8], outid: usize) -> Option<Coin> {
+        self.get_utxo_tree()
+            .get(outpoint_key(txid, outid))
+            .unwrap()
+            .map(|coin_bytes| bincode::deserialize(coin_bytes.as_ref()).expect("unable to deserialize Coin"))
     }
 
     /// 统计UTXO集中tx数量
     pub fn count_transactions(&self) -> i32 {
-        self.get_utxo_tree().iter().count() as u32 as i32
+        let mut count = 0;
+        let mut last_txid: Option<Vec<u8>> = None;
+        for (txid, _, _) in self.iter_coins() {
+            if last_txid.as_ref() != Some(&txid) {
+                count += 1;
+                last_txid = Some(txid);
+            }
+        }
+        count
     }
 
     fn get_utxo_tree(&self) -> sled::Tree {
@@ -75,59 +351,226 @@ impl UTXOSet {
             .unwrap()
     }
 
+    fn get_undo_tree(&self) -> sled::Tree {
+        self.blockchain
+            .get_db()
+            .open_tree(UNDO_TREE)
+            .unwrap()
+    }
+
+    /// 遍历utxo set, 返回(交易id, 输出索引, 未花费输出), 按txid和输出索引排序
+    fn iter_coins(&self) -> impl Iterator<Item = (Vec<u8>, usize, Coin)> {
+        self.get_utxo_tree().iter().filter_map(|item| {
+            let (k, v) = item.unwrap();
+            if k == BEST_BLOCK_HASH_KEY || k == VERSION_KEY {
+                return None;
+            }
+            let (txid, outid) = k.split_at(k.len() - 4);
+            let outid = u32::from_be_bytes(outid.try_into().unwrap()) as usize;
+            let coin = bincode::deserialize(v.as_ref()).expect("unable to deserialize Coin");
+            Some((txid.to_vec(), outid, coin))
+        })
+    }
+
     /// 查找pub_key_hash对应的所有utxo
     pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TxOutput> {
-        let mut utxos = vec![];
-        let utxo_tree = self.get_utxo_tree();
-        for item in utxo_tree.iter() {
-            let (_, v) = item.unwrap();
-            let outs: Vec<TxOutput> = bincode::deserialize(v.to_vec().as_slice()).expect("unable to deserialize TxOuput");
-            for out in outs.iter() {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out.clone());
-                }
+        self.iter_coins()
+            .filter(|(_, _, coin)| coin.output.is_locked_with_key(pub_key_hash))
+            .map(|(_, _, coin)| coin.output)
+            .collect()
+    }
+
+    /// 查询由script_pubkey锁定的余额, 返回(可花费余额, 未成熟的coinbase余额)
+    pub fn get_balance(&self, script_pubkey: &Script) -> (Amount, Amount) {
+        let height = self.blockchain.get_best_height() + 1;
+        let mut spendable = Amount::ZERO;
+        let mut immature = Amount::ZERO;
+        for (_, _, coin) in self.iter_coins() {
+            if coin.output.get_script_pubkey() != script_pubkey {
+                continue;
             }
+            let balance = if coin.is_mature(height) {
+                &mut spendable
+            } else {
+                &mut immature
+            };
+            *balance = balance.checked_add(coin.output.get_cost()).expect("balance overflow");
         }
 
-        utxos
+        (spendable, immature)
     }
 
-    /// 重建utxo
-    pub fn reindex(&self) {
+    /// 重建utxo: 从创世区块开始依次连接主链上的区块, 同时重建undo记录.
+    /// 先删除utxo set所在的区块hash, 重建中断时utxo set与tip不一致, 下次启动重新重建
+    pub fn reindex(&self) -> Result<(), ChainError> {
         let utxo_tree = self.get_utxo_tree();
-        let _ = utxo_tree.clear();
+        utxo_tree.remove(BEST_BLOCK_HASH_KEY)?;
+        utxo_tree.clear()?;
+        self.get_undo_tree().clear()?;
 
-        let utxo_map = self.blockchain.find_utxo();
-        for (txid_hex, outs) in &utxo_map {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-            let value = bincode::serialize(outs).unwrap();
-            let _ = utxo_tree.insert(txid.as_slice(), value);
+        let mut blocks = vec![];
+        let mut iterator = self.blockchain.iterator();
+        while let Some(block) = iterator.next() {
+            blocks.push(block);
+        }
+        for block in blocks.iter().rev() {
+            self.update(block)?;
         }
+        Ok(())
     }
 
-    /// 查找所有可消费的output
-    pub fn find_spendable_outputs(&self, pub_key_hash: &[u8], amount: i32) -> (i32, HashMap<String, Vec<usize>>) {
-        let mut accmulated = 0;
+    /// 查找由script_pubkey锁定的可消费的output, 跳过未成熟的coinbase输出
+    pub fn find_spendable_outputs(&self, script_pubkey: &Script, amount: Amount) -> (Amount, HashMap<String, Vec<usize>>) {
+        let height = self.blockchain.get_best_height() + 1;
+        let mut accmulated = Amount::ZERO;
         let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
-        let utxo_tree = self.get_utxo_tree();
-        for item in utxo_tree.iter() {
-            let (k, v) = item.unwrap();
-            let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
-            let outs: Vec<TxOutput> = bincode::deserialize(v.to_vec().as_slice()).expect("unable to deserialize TxOutput");
-            for (idx, out) in outs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accmulated < amount {
-                    accmulated += out.get_cost();
-                    if unspent_outputs.contains_key(txid_hex.as_str()) {
-                        unspent_outputs.get_mut(txid_hex.as_str())
-                            .unwrap()
-                            .push(idx)
-                    } else {
-                        unspent_outputs.insert(txid_hex.clone(), vec![idx]);
-                    }
-                }
+        for (txid, outid, coin) in self.iter_coins() {
+            if accmulated >= amount {
+                break;
+            }
+            if coin.output.get_script_pubkey() != script_pubkey || !coin.is_mature(height) {
+                continue;
             }
+            accmulated = accmulated.checked_add(coin.output.get_cost()).expect("balance overflow");
+            unspent_outputs.entry(HEXLOWER.encode(txid.as_slice()))
+                .or_default()
+                .push(outid);
         }
 
         (accmulated, unspent_outputs)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ProofOfWork,
+        Transaction,
+        test_utils::{mine_to_maturity, new_blockchain, temporary_db},
+        transaction::get_block_subsidy,
+        wallet::Wallet
+    };
+
+    fn balance(utxo_set: &UTXOSet, address: &str) -> Amount {
+        let payload = crate::base58_decode(address);
+        let pub_key_hash = &payload[1..payload.len() - crate::ADDRESS_CHECKSUM_LEN];
+        Amount::checked_sum(utxo_set.find_utxo(pub_key_hash).iter().map(|out| out.get_cost())).unwrap()
+    }
+
+    #[test]
+    fn test_reorganize() {
+        let bits = ProofOfWork::initial_bits();
+        let genesis_address = Wallet::new().get_address();
+        let address_a = Wallet::new().get_address();
+        let address_b = Wallet::new().get_address();
+        let blockchain = new_blockchain(genesis_address.as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let genesis_hash = blockchain.get_tip_hash();
+
+        // 分支a: genesis <- a1
+        let a1 = blockchain.mine_block(&[Transaction::new_coinbase_tx(address_a.as_str(), 1, Amount::ZERO)]);
+        assert_eq!(utxo_set.count_transactions(), 2);
+
+        // 分支b: genesis <- b1 <- b2, 连接b2时切换到分支b
+        let b1 = Block::new(genesis_hash, &[Transaction::new_coinbase_tx(address_b.as_str(), 1, Amount::ZERO)], 1, bits);
+        blockchain.add_block(&b1).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), a1.get_hash());
+        let b2 = Block::new(String::from(b1.get_hash()), &[Transaction::new_coinbase_tx(address_b.as_str(), 2, Amount::ZERO)], 2, bits);
+        blockchain.add_block(&b2).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), b2.get_hash());
+
+        let reward = Amount::checked_sum([get_block_subsidy(1), get_block_subsidy(2)]);
+        assert_eq!(utxo_set.count_transactions(), 3);
+        assert_eq!(balance(&utxo_set, address_a.as_str()), Amount::ZERO);
+        assert_eq!(Some(balance(&utxo_set, address_b.as_str())), reward);
+        assert!(utxo_set.get_undo_tree().get(a1.get_hash()).unwrap().is_none());
+
+        // 增量更新的结果与全量重建一致
+        utxo_set.reindex().unwrap();
+        assert_eq!(utxo_set.count_transactions(), 3);
+        assert_eq!(Some(balance(&utxo_set, address_b.as_str())), reward);
+    }
+
+    #[test]
+    fn test_chainstate_version() {
+        let address = Wallet::new().get_address();
+        let db = temporary_db();
+        let blockchain = Blockchain::create_blockchain_with_db(db.clone(), address.as_str());
+        let utxo_set = UTXOSet::new(blockchain);
+        assert!(utxo_set.is_synced());
+
+        // 模拟旧版本的utxo set: 没有版本号, 值不是Coin, 重新打开时重建
+        let utxo_tree = utxo_set.get_utxo_tree();
+        utxo_tree.remove(VERSION_KEY).unwrap();
+        utxo_tree.insert(outpoint_key(&[1; 32], 0), vec![0u8; 3]).unwrap();
+        assert!(!utxo_set.is_synced());
+        let blockchain = Blockchain::create_blockchain_with_db(db, address.as_str());
+        let utxo_set = UTXOSet::new(blockchain);
+        assert!(utxo_set.is_synced());
+        assert!(utxo_set.get_coin(&[1; 32], 0).is_none());
+        assert_eq!(utxo_set.count_transactions(), 1);
+    }
+
+    #[test]
+    fn test_data_output() {
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let blockchain = new_blockchain(address.as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        mine_to_maturity(&blockchain, Wallet::new().get_address().as_str());
+
+        // 数据输出在索引0, 只有找零进入utxo set
+        let tx = Transaction::new_data_transaction(&wallet, b"document hash", Amount::from_sat(1), &utxo_set);
+        let height = blockchain.get_best_height() + 1;
+        let block = blockchain.mine_block(&[tx.clone(), Transaction::new_coinbase_tx(address.as_str(), height, Amount::from_sat(1))]);
+        assert!(utxo_set.get_coin(tx.get_id(), 0).is_none());
+        let change = utxo_set.get_coin(tx.get_id(), 1).unwrap();
+        assert_eq!((change.get_height(), change.is_coinbase()), (block.get_height(), false));
+        let found = blockchain.find_data(b"document hash").unwrap();
+        assert_eq!((found.0.get_hash(), found.1.get_id()), (block.get_hash(), tx.get_id()));
+
+        // 找零按其在交易中的索引花费
+        let spend = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(5), Amount::ZERO, &utxo_set);
+        assert_eq!(spend.get_vin()[0].get_outid(), 1);
+        let height = blockchain.get_best_height() + 1;
+        blockchain.mine_block(&[spend, Transaction::new_coinbase_tx(Wallet::new().get_address().as_str(), height, Amount::ZERO)]);
+
+        let count = utxo_set.count_transactions();
+        utxo_set.reindex().unwrap();
+        assert_eq!(utxo_set.count_transactions(), count);
+        assert!(utxo_set.get_coin(tx.get_id(), 1).is_none());
+    }
+
+    #[test]
+    fn test_spend_one_output() {
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let to = Wallet::new().get_address();
+        let blockchain = new_blockchain(address.as_str());
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let maturity = crate::GLOBAL_CONFIG.get_coinbase_maturity();
+        mine_to_maturity(&blockchain, Wallet::new().get_address().as_str());
+        let genesis_coinbase = blockchain.get_block_by_height(0).unwrap().get_transactions()[0].clone();
+        let coin = utxo_set.get_coin(genesis_coinbase.get_id(), 0).unwrap();
+        assert!(coin.is_coinbase() && !coin.is_mature(maturity - 1) && coin.is_mature(maturity));
+
+        // 支付给to的输出在索引0, 找零在索引1, 只花费找零后输出0的索引不变
+        let tx = Transaction::new_utxo_transaction_from_wallet(&wallet, to.as_str(), Amount::from_sat(3), Amount::ZERO, &utxo_set);
+        blockchain.mine_block(std::slice::from_ref(&tx));
+        let spend = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(5), Amount::ZERO, &utxo_set);
+        assert_eq!(spend.get_vin()[0].get_outid(), 1);
+        let block = blockchain.mine_block(std::slice::from_ref(&spend));
+        assert!(utxo_set.get_coin(tx.get_id(), 1).is_none());
+        let coin = utxo_set.get_coin(tx.get_id(), 0).unwrap();
+        assert_eq!(coin.get_output().get_cost(), Amount::from_sat(3));
+        assert!(!coin.is_coinbase());
+        assert_eq!(balance(&utxo_set, to.as_str()), Amount::from_sat(3));
+
+        // 断开区块后恢复被花费的找零
+        utxo_set.disconnect_block(&block);
+        assert!(utxo_set.get_coin(spend.get_id(), 0).is_none());
+        assert_eq!(utxo_set.get_coin(tx.get_id(), 1).unwrap().get_height(), block.get_height() - 1);
+        assert!(utxo_set.get_coin(tx.get_id(), 0).is_some());
+    }
+}
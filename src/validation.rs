@@ -0,0 +1,743 @@
+// validation.rs
+//
+
+use std::{
+    collections::HashSet,
+    error::Error,
+    fmt
+};
+use data_encoding::HEXLOWER;
+
+use crate::{
+    Amount,
+    BatchVerifier,
+    Blockchain,
+    ProofOfWork,
+    Transaction,
+    block::{Block, BlockHeader, MAX_BLOCK_SIZE},
+    utxo_set::{CoinsView, CoinsViewCache},
+    current_timestamp,
+    transaction::get_block_subsidy
+};
+
+/// 区块时间戳最多比本地时间晚多少毫秒
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60 * 1000;
+
+/// 区块校验失败的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// nonce与hash不满足工作量证明, 或hash与区块内容不符
+    InvalidProofOfWork,
+    /// 区块头中的Merkle根与区块中的交易不符
+    InvalidMerkleRoot,
+    /// 区块头中的见证Merkle根与区块中交易的解锁脚本不符
+    InvalidWitnessRoot,
+    /// 前一个区块不存在
+    MissingParent(String),
+    /// 区块高度不等于前一个区块高度+1
+    InvalidHeight { expected: usize, actual: usize },
+    /// 区块的bits与难度调整计算的结果不符
+    InvalidBits { expected: u32, actual: u32 },
+    /// 区块中没有coinbase交易
+    MissingCoinbase,
+    /// 区块中有多个coinbase交易
+    MultipleCoinbase,
+    /// 交易签名校验失败(txid_hex)
+    InvalidTransaction(String),
+    /// 区块内多个交易输入花费同一个输出
+    DoubleSpend { txid: String, outid: usize },
+    /// 交易输出总额超过输入总额(txid_hex)
+    InsufficientInputs(String),
+    /// 交易输出金额或总额超过币的总量上限(txid_hex)
+    InvalidAmount(String),
+    /// 交易花费了未成熟的coinbase输出
+    ImmatureCoinbase { txid: String, height: usize },
+    /// coinbase交易记录的高度与区块高度不符
+    InvalidCoinbaseHeight(usize),
+    /// coinbase交易的输出超过挖矿奖励与手续费之和
+    ExcessiveCoinbase { allowed: Amount, actual: Amount },
+    /// 区块序列化后超过最大字节数
+    BlockTooLarge(usize),
+    /// 交易未到达lock_time(txid_hex)
+    NonFinalTransaction(String),
+    /// 交易输入未满足相对时间锁
+    SequenceLocked { txid: String, input: usize },
+    /// 不可花费的输出不是数据输出(txid_hex)
+    InvalidDataOutput(String),
+    /// 交易id与交易内容不符(txid_hex)
+    InvalidTxid(String),
+    /// 区块内的Schnorr签名批量验证失败
+    InvalidSignatureBatch,
+    /// 区块内有重复的交易(txid_hex)
+    DuplicateTransaction(String),
+    /// 区块时间戳早于前面区块的中位时间, 或晚于本地时间超过MAX_FUTURE_BLOCK_TIME
+    InvalidTimestamp(u64),
+    /// 交易输入引用的输出不存在或已被花费
+    MissingInput { txid: String, outid: usize },
+    /// 区块已被标记为非法(block_hash_hex)
+    KnownInvalid(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::InvalidProofOfWork => write!(f, "invalid proof of work"),
+            ValidationError::InvalidMerkleRoot => write!(f, "merkle root mismatch"),
+            ValidationError::InvalidWitnessRoot => write!(f, "witness root mismatch"),
+            ValidationError::MissingParent(hash) => write!(f, "parent block {} not found", hash),
+            ValidationError::InvalidHeight { expected, actual } => {
+                write!(f, "invalid height {}, expected {}", actual, expected)
+            },
+            ValidationError::InvalidBits { expected, actual } => {
+                write!(f, "invalid bits {:#010x}, expected {:#010x}", actual, expected)
+            },
+            ValidationError::MissingCoinbase => write!(f, "missing coinbase transaction"),
+            ValidationError::MultipleCoinbase => write!(f, "more than one coinbase transaction"),
+            ValidationError::InvalidTransaction(txid) => write!(f, "invalid transaction {}", txid),
+            ValidationError::DoubleSpend { txid, outid } => {
+                write!(f, "output {}:{} is spent more than once", txid, outid)
+            },
+            ValidationError::InsufficientInputs(txid) => {
+                write!(f, "transaction {} spends more than its inputs", txid)
+            },
+            ValidationError::InvalidAmount(txid) => {
+                write!(f, "transaction {} has an output value out of range", txid)
+            },
+            ValidationError::ImmatureCoinbase { txid, height } => {
+                write!(f, "coinbase {} cannot be spent at height {}", txid, height)
+            },
+            ValidationError::InvalidCoinbaseHeight(height) => {
+                write!(f, "coinbase does not commit to height {}", height)
+            },
+            ValidationError::ExcessiveCoinbase { allowed, actual } => {
+                write!(f, "coinbase pays {}, allowed {}", actual, allowed)
+            },
+            ValidationError::BlockTooLarge(size) => {
+                write!(f, "block size {} exceeds {}", size, MAX_BLOCK_SIZE)
+            },
+            ValidationError::NonFinalTransaction(txid) => {
+                write!(f, "transaction {} is not final", txid)
+            },
+            ValidationError::SequenceLocked { txid, input } => {
+                write!(f, "input {} of transaction {} is locked by sequence", input, txid)
+            },
+            ValidationError::InvalidDataOutput(txid) => {
+                write!(f, "transaction {} has an invalid data output", txid)
+            },
+            ValidationError::InvalidTxid(txid) => {
+                write!(f, "transaction id {} does not match its content", txid)
+            },
+            ValidationError::InvalidSignatureBatch => write!(f, "schnorr signature batch verification failed"),
+            ValidationError::DuplicateTransaction(txid) => write!(f, "duplicate transaction {}", txid),
+            ValidationError::InvalidTimestamp(timestamp) => write!(f, "block timestamp {} is out of range", timestamp),
+            ValidationError::MissingInput { txid, outid } => {
+                write!(f, "output {}:{} is missing or spent", txid, outid)
+            },
+            ValidationError::KnownInvalid(hash) => write!(f, "block {} is known to be invalid", hash),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// 校验区块头: 工作量证明, 前一个区块头及难度, 时间戳, 返回该区块头的高度.
+/// 时间戳不早于前面区块的中位时间, 且不晚于本地时间MAX_FUTURE_BLOCK_TIME以上
+pub fn validate_header(blockchain: &Blockchain, header: &BlockHeader) -> Result<usize, ValidationError> {
+    if !ProofOfWork::new(header.clone()).validate() {
+        return Err(ValidationError::InvalidProofOfWork);
+    }
+
+    let pre_block_hash = header.get_pre_block_hash();
+    let (parent, parent_height) = blockchain.get_header(pre_block_hash.as_bytes())
+        .ok_or_else(|| ValidationError::MissingParent(pre_block_hash.clone()))?;
+    let expected_bits = blockchain.get_next_bits(&parent, parent_height);
+    if header.get_bits() != expected_bits {
+        return Err(ValidationError::InvalidBits {
+            expected: expected_bits,
+            actual: header.get_bits(),
+        });
+    }
+    let timestamp = header.get_timestamp();
+    if timestamp < blockchain.get_median_time_past(pre_block_hash.as_bytes())
+        || timestamp > current_timestamp() + MAX_FUTURE_BLOCK_TIME {
+        return Err(ValidationError::InvalidTimestamp(timestamp));
+    }
+
+    Ok(parent_height + 1)
+}
+
+/// 交易的每个输出及输出总额都不超过币的总量上限, 返回输出总额
+fn check_output_value(tx: &Transaction) -> Result<Amount, ValidationError> {
+    let invalid = || ValidationError::InvalidAmount(HEXLOWER.encode(tx.get_id()));
+    if tx.get_vout().iter().any(|out| !out.get_cost().is_valid()) {
+        return Err(invalid());
+    }
+    tx.get_output_value()
+        .filter(Amount::is_valid)
+        .ok_or_else(invalid)
+}
+
+/// 交易id须由交易内容计算得到
+fn check_txid(tx: &Transaction) -> Result<(), ValidationError> {
+    if tx.get_id() != tx.hash().as_slice() {
+        return Err(ValidationError::InvalidTxid(HEXLOWER.encode(tx.get_id())));
+    }
+    Ok(())
+}
+
+/// 不可花费的输出只能是数据输出, 数据大小只受区块大小限制, 转发时的上限见内存池策略
+fn check_data_outputs(tx: &Transaction) -> Result<(), ValidationError> {
+    let valid = tx.get_vout().iter()
+        .filter(|out| out.is_unspendable())
+        .all(|out| out.get_script_pubkey().get_data().is_some());
+    if !valid {
+        return Err(ValidationError::InvalidDataOutput(HEXLOWER.encode(tx.get_id())));
+    }
+    Ok(())
+}
+
+/// 校验将被高度为height的区块打包的非coinbase交易: 交易id, 输入引用的输出在utxo set中, 签名, 绝对时间锁,
+/// 输出金额, 数据输出, 输入的coinbase成熟度, 相对时间锁和手续费, 返回手续费.
+/// time为前一个区块的中位时间, 矿工无法通过调快单个区块的时间戳提前解锁
+pub fn validate_transaction(blockchain: &Blockchain, tx: &Transaction, height: usize, time: u64) -> Result<Amount, ValidationError> {
+    check_transaction(blockchain, blockchain, tx, blockchain.get_tip_hash().as_str(), height, time, None)
+}
+
+/// 同validate_transaction, 但输入引用的输出从coins中查找, 如叠加了内存池交易的视图
+pub fn validate_transaction_with_coins(
+    blockchain: &Blockchain,
+    coins: &dyn CoinsView,
+    tx: &Transaction,
+    height: usize,
+    time: u64,
+) -> Result<Amount, ValidationError> {
+    check_transaction(blockchain, coins, tx, blockchain.get_tip_hash().as_str(), height, time, None)
+}
+
+/// 同validate_transaction_with_coins, 交易被接在pre_block_hash之后的区块打包, batch不为None时Schnorr签名加入batch批量验证
+fn check_transaction(
+    blockchain: &Blockchain,
+    coins: &dyn CoinsView,
+    tx: &Transaction,
+    pre_block_hash: &str,
+    height: usize,
+    time: u64,
+    batch: Option<&mut BatchVerifier>,
+) -> Result<Amount, ValidationError> {
+    check_txid(tx)?;
+    let mut prev_coins = vec![];
+    for txin in tx.get_vin() {
+        match coins.get_coin(txin.get_txid(), txin.get_outid()) {
+            Some(coin) => prev_coins.push(coin),
+            None => return Err(ValidationError::MissingInput {
+                txid: HEXLOWER.encode(txin.get_txid()),
+                outid: txin.get_outid(),
+            }),
+        }
+    }
+    let verified = match batch {
+        Some(batch) => tx.verify_with_batch(coins, batch),
+        None => tx.verify(coins),
+    };
+    if !verified {
+        return Err(ValidationError::InvalidTransaction(HEXLOWER.encode(tx.get_id())));
+    }
+    if !tx.is_final(height, time) {
+        return Err(ValidationError::NonFinalTransaction(HEXLOWER.encode(tx.get_id())));
+    }
+    check_output_value(tx)?;
+    check_data_outputs(tx)?;
+    for (input, (txin, coin)) in tx.get_vin().iter().zip(prev_coins).enumerate() {
+        if !coin.is_mature(height) {
+            return Err(ValidationError::ImmatureCoinbase {
+                txid: HEXLOWER.encode(txin.get_txid()),
+                height,
+            });
+        }
+        // 输出所在区块的前一个区块的中位时间, 输出在同一区块或内存池中时为time
+        let coin_time = blockchain.get_ancestor_hash(pre_block_hash, coin.get_height().saturating_sub(1))
+            .filter(|_| coin.get_height() < height)
+            .map_or(time, |hash| blockchain.get_median_time_past(hash.as_bytes()));
+        let locked = txin.get_relative_lock().is_some_and(|lock| {
+            !lock.is_satisfied(coin.get_height(), coin_time, height, time)
+        });
+        if locked {
+            return Err(ValidationError::SequenceLocked {
+                txid: HEXLOWER.encode(tx.get_id()),
+                input,
+            });
+        }
+    }
+    tx.get_fee(coins)
+        .ok_or_else(|| ValidationError::InsufficientInputs(HEXLOWER.encode(tx.get_id())))
+}
+
+/// 校验区块中不依赖utxo set的部分: 区块大小, 区块头, 前一个区块及高度, Merkle根及见证Merkle根, 重复交易,
+/// coinbase个数及高度. 分叉区块的交易依赖其所在分支的utxo set, 在连接区块时由validate_block校验
+pub fn check_block(blockchain: &Blockchain, block: &Block) -> Result<(), ValidationError> {
+    let size = block.get_size();
+    if size > MAX_BLOCK_SIZE {
+        return Err(ValidationError::BlockTooLarge(size));
+    }
+    if block.get_header().hash() != block.get_hash() {
+        return Err(ValidationError::InvalidProofOfWork);
+    }
+    if !block.check_merkle_root() {
+        return Err(ValidationError::InvalidMerkleRoot);
+    }
+    // 奇数层复制最后一个节点, 末尾重复交易的区块与原区块的Merkle根相同, 须拒绝重复交易
+    let mut txids = HashSet::new();
+    if let Some(tx) = block.get_transactions().iter().find(|tx| !txids.insert(tx.get_id())) {
+        return Err(ValidationError::DuplicateTransaction(HEXLOWER.encode(tx.get_id())));
+    }
+    if !block.check_witness_root() {
+        return Err(ValidationError::InvalidWitnessRoot);
+    }
+
+    let pre_block_hash = block.get_pre_block_hash();
+    if blockchain.get_block(pre_block_hash.as_bytes()).is_none() {
+        return Err(ValidationError::MissingParent(pre_block_hash));
+    }
+    let height = validate_header(blockchain, block.get_header())?;
+    if block.get_height() != height {
+        return Err(ValidationError::InvalidHeight {
+            expected: height,
+            actual: block.get_height(),
+        });
+    }
+
+    let coinbase: Vec<&Transaction> = block.get_transactions().iter().filter(|tx| tx.is_coinbase()).collect();
+    let coinbase = match coinbase.as_slice() {
+        [] => return Err(ValidationError::MissingCoinbase),
+        [coinbase] => coinbase,
+        _ => return Err(ValidationError::MultipleCoinbase),
+    };
+    if coinbase.get_coinbase_height() != Some(height) {
+        return Err(ValidationError::InvalidCoinbaseHeight(height));
+    }
+    check_txid(coinbase)?;
+    check_data_outputs(coinbase)
+}
+
+/// 校验接在tip之后的区块: 在check_block的基础上按utxo set校验各交易, coinbase金额和块内双花.
+/// 交易可以花费区块中之前交易的输出, 时间锁按前一个区块的中位时间校验, 各交易中的Schnorr签名最后一起批量验证
+pub fn validate_block(blockchain: &Blockchain, block: &Block) -> Result<(), ValidationError> {
+    validate_block_with_coins(blockchain, blockchain, block)
+}
+
+/// 同validate_block, 但输入引用的输出从coins中查找, 如分支切换事务中的utxo set
+pub fn validate_block_with_coins(blockchain: &Blockchain, coins: &dyn CoinsView, block: &Block) -> Result<(), ValidationError> {
+    check_block(blockchain, block)?;
+    let height = block.get_height();
+    let pre_block_hash = block.get_pre_block_hash();
+    let time = blockchain.get_median_time_past(pre_block_hash.as_bytes());
+
+    let mut coinbase = None;
+    let mut fees = Amount::ZERO;
+    let mut spent = HashSet::new();
+    let mut batch = BatchVerifier::new();
+    let mut view = CoinsViewCache::new(coins);
+    for tx in block.get_transactions() {
+        if tx.is_coinbase() {
+            coinbase = Some(tx);
+            view.apply(tx, height);
+            continue;
+        }
+        for txin in tx.get_vin() {
+            if !spent.insert((txin.get_txid().to_vec(), txin.get_outid())) {
+                return Err(ValidationError::DoubleSpend {
+                    txid: HEXLOWER.encode(txin.get_txid()),
+                    outid: txin.get_outid(),
+                });
+            }
+        }
+        let fee = check_transaction(blockchain, &view, tx, pre_block_hash.as_str(), height, time, Some(&mut batch))?;
+        fees = fees.checked_add(fee)
+            .filter(Amount::is_valid)
+            .ok_or_else(|| ValidationError::InvalidAmount(HEXLOWER.encode(tx.get_id())))?;
+        view.apply(tx, height);
+    }
+    let coinbase = coinbase.expect("the coinbase is checked");
+    let actual = check_output_value(coinbase)?;
+    let allowed = get_block_subsidy(height).checked_add(fees)
+        .ok_or_else(|| ValidationError::InvalidAmount(HEXLOWER.encode(coinbase.get_id())))?;
+    if actual > allowed {
+        return Err(ValidationError::ExcessiveCoinbase { allowed, actual });
+    }
+    // 批量验证块内的Schnorr签名, 失败时区块非法, 逐个验证只用于找出签名非法的交易
+    if !batch.verify() {
+        let mut view = CoinsViewCache::new(coins);
+        for tx in block.get_transactions() {
+            if !tx.verify(&view) {
+                return Err(ValidationError::InvalidTransaction(HEXLOWER.encode(tx.get_id())));
+            }
+            view.apply(tx, height);
+        }
+        return Err(ValidationError::InvalidSignatureBatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        RelativeLock,
+        Script,
+        SignatureScheme,
+        hash_pub_key,
+        test_utils::{self, mine_blocks, mine_to_maturity},
+        wallet::Wallet
+    };
+
+    fn new_blockchain() -> (Blockchain, String) {
+        let address = Wallet::new().get_address();
+        (test_utils::new_blockchain(address.as_str()), address)
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let bits = ProofOfWork::initial_bits();
+        let (blockchain, address) = new_blockchain();
+        let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO);
+        let block = Block::new(blockchain.get_tip_hash(), &[coinbase_tx], 1, bits);
+        assert_eq!(validate_block(&blockchain, &block), Ok(()));
+
+        // 篡改区块头中的nonce(区块头最后一个字段)后hash不再匹配
+        let mut bytes = block.serialize();
+        let header_len = bincode::serialized_size(block.get_header()).unwrap() as usize;
+        bytes[header_len - 1] ^= 1;
+        let tampered = Block::deserialize(bytes.as_slice()).unwrap();
+        assert_eq!(validate_block(&blockchain, &tampered), Err(ValidationError::InvalidProofOfWork));
+    }
+
+    #[test]
+    fn test_validate_block_timestamp() {
+        let (blockchain, address) = new_blockchain();
+        mine_blocks(&blockchain, address.as_str(), 3);
+        let tip = blockchain.get_block(blockchain.get_tip_hash().as_bytes()).unwrap();
+        let bits = blockchain.get_next_bits(tip.get_header(), tip.get_height());
+        let median = blockchain.get_median_time_past(tip.get_hash_bytes().as_slice());
+        let new_block = |timestamp| {
+            let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), 4, Amount::ZERO);
+            Block::new_with_timestamp(blockchain.get_tip_hash(), &[coinbase_tx], 4, bits, timestamp)
+        };
+        assert_eq!(validate_block(&blockchain, &new_block(median)), Ok(()));
+        assert_eq!(validate_block(&blockchain, &new_block(median - 1)), Err(ValidationError::InvalidTimestamp(median - 1)));
+
+        let future = current_timestamp() + MAX_FUTURE_BLOCK_TIME + 60_000;
+        assert_eq!(validate_block(&blockchain, &new_block(future)), Err(ValidationError::InvalidTimestamp(future)));
+    }
+
+    #[test]
+    fn test_validate_block_linkage() {
+        let bits = ProofOfWork::initial_bits();
+        let (blockchain, address) = new_blockchain();
+        let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO);
+
+        let block = Block::new(String::from("unknown"), std::slice::from_ref(&coinbase_tx), 1, bits);
+        assert_eq!(
+            validate_block(&blockchain, &block),
+            Err(ValidationError::MissingParent(String::from("unknown")))
+        );
+
+        let block = Block::new(blockchain.get_tip_hash(), std::slice::from_ref(&coinbase_tx), 2, bits);
+        assert_eq!(
+            validate_block(&blockchain, &block),
+            Err(ValidationError::InvalidHeight { expected: 1, actual: 2 })
+        );
+
+        // 使用比难度调整结果更低的难度
+        let easy_bits = 0x2010_0000;
+        let block = Block::new(blockchain.get_tip_hash(), &[coinbase_tx], 1, easy_bits);
+        assert_eq!(
+            validate_block(&blockchain, &block),
+            Err(ValidationError::InvalidBits { expected: bits, actual: easy_bits })
+        );
+    }
+
+    #[test]
+    fn test_validate_block_coinbase() {
+        let bits = ProofOfWork::initial_bits();
+        let (blockchain, address) = new_blockchain();
+
+        let block = Block::new(blockchain.get_tip_hash(), &[], 1, bits);
+        assert_eq!(validate_block(&blockchain, &block), Err(ValidationError::MissingCoinbase));
+
+        let txs = vec![
+            Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO),
+            Transaction::new_coinbase_tx(address.as_str(), 2, Amount::ZERO),
+        ];
+        let block = Block::new(blockchain.get_tip_hash(), &txs, 1, bits);
+        assert_eq!(validate_block(&blockchain, &block), Err(ValidationError::MultipleCoinbase));
+
+        // 重复末尾交易后Merkle根不变, 但区块被拒绝
+        let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), 1, Amount::ZERO);
+        let txid = HEXLOWER.encode(coinbase_tx.get_id());
+        let block = Block::new(blockchain.get_tip_hash(), &[coinbase_tx.clone(), coinbase_tx], 1, bits);
+        assert!(block.check_merkle_root());
+        assert_eq!(validate_block(&blockchain, &block), Err(ValidationError::DuplicateTransaction(txid)));
+
+        // 输出超过币的总量上限
+        let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), 1, crate::MAX_MONEY);
+        let txid = HEXLOWER.encode(coinbase_tx.get_id());
+        let block = Block::new(blockchain.get_tip_hash(), &[coinbase_tx], 1, bits);
+        assert_eq!(validate_block(&blockchain, &block), Err(ValidationError::InvalidAmount(txid)));
+    }
+
+    #[test]
+    fn test_validate_block_fees() {
+        let (blockchain, _) = new_blockchain();
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let utxo_set = crate::UTXOSet::new(blockchain.clone());
+        let height = mature_coinbase(&blockchain, address.as_str());
+        let (tip, tip_height) = blockchain.get_header(blockchain.get_tip_hash().as_bytes()).unwrap();
+        let bits = blockchain.get_next_bits(&tip, tip_height);
+
+        // 支付手续费3, coinbase最多可以领取 挖矿奖励 + 3
+        let fee = Amount::from_sat(3);
+        let tx = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(5), fee, &utxo_set);
+        assert_eq!(tx.get_fee(&blockchain), Some(fee));
+        let txs = vec![tx.clone(), Transaction::new_coinbase_tx(address.as_str(), height, fee)];
+        let block = Block::new(blockchain.get_tip_hash(), &txs, height, bits);
+        assert_eq!(validate_block(&blockchain, &block), Ok(()));
+
+        let subsidy = get_block_subsidy(height).as_sat();
+        let txs = vec![tx, Transaction::new_coinbase_tx(address.as_str(), height, Amount::from_sat(4))];
+        let block = Block::new(blockchain.get_tip_hash(), &txs, height, bits);
+        assert_eq!(
+            validate_block(&blockchain, &block),
+            Err(ValidationError::ExcessiveCoinbase {
+                allowed: Amount::from_sat(subsidy + 3),
+                actual: Amount::from_sat(subsidy + 4),
+            })
+        );
+
+        // coinbase必须记录区块高度
+        let txs = vec![Transaction::new_coinbase_tx(address.as_str(), height + 1, Amount::ZERO)];
+        let block = Block::new(blockchain.get_tip_hash(), &txs, height, bits);
+        assert_eq!(validate_block(&blockchain, &block), Err(ValidationError::InvalidCoinbaseHeight(height)));
+    }
+
+    #[test]
+    fn test_validate_block_spent_input() {
+        let (blockchain, _) = new_blockchain();
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let utxo_set = crate::UTXOSet::new(blockchain.clone());
+        mature_coinbase(&blockchain, address.as_str());
+        let tx = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(5), Amount::ZERO, &utxo_set);
+        blockchain.mine_block(std::slice::from_ref(&tx));
+
+        // 再次打包已确认的交易, 其输入引用的输出仍在链上但已不在utxo set中
+        let (tip, tip_height) = blockchain.get_header(blockchain.get_tip_hash().as_bytes()).unwrap();
+        let height = tip_height + 1;
+        let txs = vec![tx.clone(), Transaction::new_coinbase_tx(address.as_str(), height, Amount::ZERO)];
+        let block = Block::new(blockchain.get_tip_hash(), &txs, height, blockchain.get_next_bits(&tip, tip_height));
+        assert_eq!(
+            validate_block(&blockchain, &block),
+            Err(ValidationError::MissingInput {
+                txid: HEXLOWER.encode(tx.get_vin()[0].get_txid()),
+                outid: tx.get_vin()[0].get_outid(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate_transaction_maturity() {
+        let (blockchain, _) = new_blockchain();
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let utxo_set = crate::UTXOSet::new(blockchain.clone());
+        let height = mature_coinbase(&blockchain, address.as_str());
+
+        // 新挖出的coinbase输出计入未成熟余额
+        blockchain.mine_block(&[Transaction::new_coinbase_tx(address.as_str(), height, Amount::ZERO)]);
+        let time = blockchain.get_tip_median_time_past();
+        let pub_key_hash = hash_pub_key(wallet.get_public_key());
+        assert_eq!(
+            utxo_set.get_balance(&Script::new_p2pkh(pub_key_hash.as_slice())),
+            (get_block_subsidy(1), get_block_subsidy(height))
+        );
+
+        let tx = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(5), Amount::ZERO, &utxo_set);
+        assert_eq!(validate_transaction(&blockchain, &tx, height, time), Ok(Amount::ZERO));
+
+        // 在更早的高度花费该coinbase输出
+        let coinbase_txid = HEXLOWER.encode(tx.get_vin()[0].get_txid());
+        assert_eq!(
+            validate_transaction(&blockchain, &tx, height - 1, time),
+            Err(ValidationError::ImmatureCoinbase { txid: coinbase_txid, height: height - 1 })
+        );
+    }
+
+    #[test]
+    fn test_validate_transaction_script() {
+        let (blockchain, _) = new_blockchain();
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let utxo_set = crate::UTXOSet::new(blockchain.clone());
+        let height = mature_coinbase(&blockchain, address.as_str());
+        let time = blockchain.get_tip_median_time_past();
+
+        let mut tx = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(5), Amount::ZERO, &utxo_set);
+        assert!(tx.verify(&blockchain));
+
+        // 其他钱包的签名不能解锁P2PKH输出
+        tx.sign(&blockchain, &Wallet::new());
+        assert!(!tx.verify(&blockchain));
+        assert_eq!(
+            validate_transaction(&blockchain, &tx, height, time),
+            Err(ValidationError::InvalidTransaction(HEXLOWER.encode(tx.get_id())))
+        );
+    }
+
+    #[test]
+    fn test_validate_transaction_timelocks() {
+        let (blockchain, _) = new_blockchain();
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let utxo_set = crate::UTXOSet::new(blockchain.clone());
+        let height = mature_coinbase(&blockchain, address.as_str());
+        let time = blockchain.get_tip_median_time_past();
+        let from = Script::new_p2pkh(hash_pub_key(wallet.get_public_key()).as_slice());
+        let new_tx = || Transaction::new_unsigned_transaction(&from, address.as_str(), Amount::from_sat(5), Amount::ZERO, &utxo_set);
+        let non_final = |tx: &Transaction| Err(ValidationError::NonFinalTransaction(HEXLOWER.encode(tx.get_id())));
+        let sequence_locked = |tx: &Transaction| Err(ValidationError::SequenceLocked { txid: HEXLOWER.encode(tx.get_id()), input: 0 });
+
+        // 以高度计的绝对时间锁
+        let mut tx = new_tx();
+        tx.set_lock_time((height + 1) as u64);
+        tx.sign(&blockchain, &wallet);
+        assert_eq!(validate_transaction(&blockchain, &tx, height, time), non_final(&tx));
+        assert_eq!(validate_transaction(&blockchain, &tx, height + 1, time), Ok(Amount::ZERO));
+
+        // 以时间计的绝对时间锁
+        let mut tx = new_tx();
+        tx.set_lock_time(time + 1);
+        tx.sign(&blockchain, &wallet);
+        assert_eq!(validate_transaction(&blockchain, &tx, height, time), non_final(&tx));
+        assert_eq!(validate_transaction(&blockchain, &tx, height, time + 1), Ok(Amount::ZERO));
+
+        // lock_time和sequence都被签名覆盖
+        let mut forged = tx.clone();
+        forged.set_lock_time(0);
+        assert!(!forged.verify(&blockchain));
+
+        // 以区块数计的相对时间锁, 引用的coinbase在高度1
+        let mut tx = new_tx();
+        tx.set_sequence(0, RelativeLock::Blocks(height as u16).to_sequence());
+        tx.sign(&blockchain, &wallet);
+        assert_eq!(validate_transaction(&blockchain, &tx, height, time), sequence_locked(&tx));
+        assert_eq!(validate_transaction(&blockchain, &tx, height + 1, time), Ok(Amount::ZERO));
+
+        // 以时间计的相对时间锁, 从coinbase所在区块的前一个区块的中位时间开始计算
+        let prev_time = blockchain.get_median_time_past(blockchain.get_block_hash_by_height(0).unwrap().as_bytes());
+        let mut tx = new_tx();
+        tx.set_sequence(0, RelativeLock::Time(2).to_sequence());
+        tx.sign(&blockchain, &wallet);
+        let unlock_time = prev_time + 2 * crate::transaction::SEQUENCE_LOCKTIME_GRANULARITY;
+        assert_eq!(validate_transaction(&blockchain, &tx, height, unlock_time - 1), sequence_locked(&tx));
+        assert_eq!(validate_transaction(&blockchain, &tx, height, unlock_time), Ok(Amount::ZERO));
+    }
+
+    #[test]
+    fn test_timelocks_use_median_time_past() {
+        let (blockchain, _) = new_blockchain();
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let utxo_set = crate::UTXOSet::new(blockchain.clone());
+        mature_coinbase(&blockchain, address.as_str());
+
+        // 矿工把区块时间戳调快一小时, 中位时间不受影响
+        let (tip, tip_height) = blockchain.get_header(blockchain.get_tip_hash().as_bytes()).unwrap();
+        let timestamp = current_timestamp() + 60 * 60 * 1000;
+        let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), tip_height + 1, Amount::ZERO);
+        let block = Block::new_with_timestamp(blockchain.get_tip_hash(), &[coinbase_tx], tip_height + 1, blockchain.get_next_bits(&tip, tip_height), timestamp);
+        blockchain.add_block(&block).unwrap();
+        let median = blockchain.get_tip_median_time_past();
+        assert!(median < timestamp);
+
+        let height = blockchain.get_best_height() + 1;
+        let from = Script::new_p2pkh(hash_pub_key(wallet.get_public_key()).as_slice());
+        let new_tx = || Transaction::new_unsigned_transaction(&from, address.as_str(), Amount::from_sat(5), Amount::ZERO, &utxo_set);
+        let (tip, tip_height) = blockchain.get_header(blockchain.get_tip_hash().as_bytes()).unwrap();
+        let bits = blockchain.get_next_bits(&tip, tip_height);
+        let new_block = |tx: &Transaction| {
+            let coinbase_tx = Transaction::new_coinbase_tx(address.as_str(), height, Amount::ZERO);
+            Block::new(blockchain.get_tip_hash(), &[tx.clone(), coinbase_tx], height, bits)
+        };
+
+        // 以时间计的绝对时间锁按中位时间校验, 不按tip的时间戳
+        let mut tx = new_tx();
+        tx.set_lock_time(timestamp);
+        tx.sign(&blockchain, &wallet);
+        let non_final = ValidationError::NonFinalTransaction(HEXLOWER.encode(tx.get_id()));
+        assert_eq!(validate_transaction(&blockchain, &tx, height, timestamp), Ok(Amount::ZERO));
+        assert_eq!(validate_transaction(&blockchain, &tx, height, median), Err(non_final.clone()));
+        assert_eq!(validate_block(&blockchain, &new_block(&tx)), Err(non_final));
+
+        // 以时间计的相对时间锁同样按中位时间校验
+        let mut tx = new_tx();
+        tx.set_sequence(0, RelativeLock::Time(1).to_sequence());
+        tx.sign(&blockchain, &wallet);
+        let sequence_locked = ValidationError::SequenceLocked { txid: HEXLOWER.encode(tx.get_id()), input: 0 };
+        assert_eq!(validate_transaction(&blockchain, &tx, height, timestamp), Ok(Amount::ZERO));
+        assert_eq!(validate_transaction(&blockchain, &tx, height, median), Err(sequence_locked.clone()));
+        assert_eq!(validate_block(&blockchain, &new_block(&tx)), Err(sequence_locked));
+    }
+
+    #[test]
+    fn test_validate_data_output() {
+        let (blockchain, _) = new_blockchain();
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let utxo_set = crate::UTXOSet::new(blockchain.clone());
+        let height = mature_coinbase(&blockchain, address.as_str());
+        let time = blockchain.get_tip_median_time_past();
+
+        // 数据大小的上限是内存池策略, 不是共识规则
+        let data = vec![1; crate::GLOBAL_CONFIG.get_max_data_size() + 1];
+        let tx = Transaction::new_data_transaction(&wallet, data.as_slice(), Amount::ZERO, &utxo_set);
+        assert_eq!(validate_transaction(&blockchain, &tx, height, time), Ok(Amount::ZERO));
+    }
+
+    #[test]
+    fn test_validate_block_schnorr_batch() {
+        let (blockchain, _) = new_blockchain();
+        let wallet = Wallet::new_with_scheme(SignatureScheme::Schnorr);
+        let address = wallet.get_address();
+        let utxo_set = crate::UTXOSet::new(blockchain.clone());
+        mature_coinbase(&blockchain, address.as_str());
+        // 拆分为两个输出, 再合并为一笔两个输入的交易
+        let split = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), Amount::from_sat(5), Amount::ZERO, &utxo_set);
+        blockchain.mine_block(&[split]);
+        let balance = get_block_subsidy(1);
+        let merge = Transaction::new_utxo_transaction_from_wallet(&wallet, address.as_str(), balance, Amount::ZERO, &utxo_set);
+        assert_eq!(merge.get_vin().len(), 2);
+
+        let (tip, tip_height) = blockchain.get_header(blockchain.get_tip_hash().as_bytes()).unwrap();
+        let bits = blockchain.get_next_bits(&tip, tip_height);
+        let height = tip_height + 1;
+        let coinbase = Transaction::new_coinbase_tx(address.as_str(), height, Amount::ZERO);
+        let block = Block::new(blockchain.get_tip_hash(), &[merge.clone(), coinbase.clone()], height, bits);
+        assert_eq!(validate_block(&blockchain, &block), Ok(()));
+
+        // 第二个输入使用第一个输入的签名, 只在批量验证时发现
+        let mut forged = merge;
+        forged.set_script_sig(1, forged.get_vin()[0].get_script_sig().clone());
+        let block = Block::new(blockchain.get_tip_hash(), &[forged.clone(), coinbase], height, bits);
+        assert_eq!(
+            validate_block(&blockchain, &block),
+            Err(ValidationError::InvalidTransaction(HEXLOWER.encode(forged.get_id())))
+        );
+    }
+
+    /// 在高度1挖出奖励给address的coinbase, 再挖到该coinbase刚好成熟, 返回下一个区块的高度
+    fn mature_coinbase(blockchain: &Blockchain, address: &str) -> usize {
+        blockchain.mine_block(&[Transaction::new_coinbase_tx(address, 1, Amount::ZERO)]);
+        mine_to_maturity(blockchain, Wallet::new().get_address().as_str());
+        blockchain.get_best_height() + 1
+    }
+}
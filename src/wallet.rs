@@ -2,42 +2,68 @@
 //
 
 
-use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING, KeyPair};
 use serde::{Serialize, Deserialize};
 
+use crate::{Script, SignatureScheme};
+
 const VERSION: u8 = 0x00;
+/// 脚本hash(P2SH)地址的版本
+pub const SCRIPT_VERSION: u8 = 0x05;
 pub const ADDRESS_CHECKSUM_LEN: usize = 4;  //地址checksum长度
 
-/// 钱包
+/// 钱包, 签名算法由公钥的类型标签确定, 原有的P-256钱包格式不变
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Wallet {
-    pkcs8: Vec<u8>,             // 公私钥对
-    public_key: Vec<u8>,        // 公钥
+    secret_key: Vec<u8>,        // 私钥, P-256和Ed25519为pkcs8编码, secp256k1为32字节标量
+    public_key: Vec<u8>,        // 带类型标签的公钥
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Wallet {
 
-    /// 新建一个钱包
+    /// 新建一个P-256钱包
     pub fn new() -> Self { 
-        let pkcs8 = crate::new_key_pair();
-        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        Self::new_with_scheme(SignatureScheme::EcdsaP256)
+    }
 
-        Wallet{pkcs8, public_key:key_pair.public_key().as_ref().to_vec()}
+    /// 新建一个使用scheme签名的钱包
+    pub fn new_with_scheme(scheme: SignatureScheme) -> Self {
+        let (secret_key, public_key) = scheme.new_key_pair();
+        Wallet{secret_key, public_key}
     }
 
     pub fn get_public_key(&self) -> &[u8] {
         self.public_key.as_slice()
     }
 
-    pub fn get_pkcs8(&self) -> &[u8] {
-        self.pkcs8.as_slice()
+    pub fn get_secret_key(&self) -> &[u8] {
+        self.secret_key.as_slice()
+    }
+
+    /// 获取签名算法
+    pub fn get_scheme(&self) -> SignatureScheme {
+        SignatureScheme::from_public_key(&self.public_key).expect("ERROR: unknown public key type")
+    }
+
+    /// 使用私钥计算消息签名
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.get_scheme().sign(&self.secret_key, message)
     }
 
     /// 获取钱包地址
-    /// address = base58( version+ hash(pub_key) + checksum)
+    /// address = base58( version+ hash(pub_key) + checksum), version由签名算法确定
     pub fn get_address(&self) -> String {
-        let pub_key_hash = hash_pub_key(&self.public_key);
-        convert_address(pub_key_hash.as_slice())
+        convert_pub_key_address(&self.public_key).unwrap()
+    }
+
+    /// 锁定到钱包地址的P2PKH脚本
+    pub fn get_script_pubkey(&self) -> Script {
+        Script::new_p2pkh_with_scheme(self.get_scheme(), hash_pub_key(&self.public_key).as_slice())
     }
 }
 
@@ -70,13 +96,43 @@ pub fn validate_address(address: &str) -> bool {
     actual_checksum.eq(target_checksum.as_slice())
 }
 
-/// 通过公钥hash key计算address
+/// 通过公钥hash key计算P-256 address
 pub fn convert_address(pub_hash_key: &[u8]) -> String {
+    encode_address(VERSION, pub_hash_key)
+}
+
+/// 通过带类型标签的公钥计算address, 未知类型的公钥返回None
+pub fn convert_pub_key_address(pub_key: &[u8]) -> Option<String> {
+    let scheme = SignatureScheme::from_public_key(pub_key)?;
+    Some(convert_p2pkh_address(scheme, hash_pub_key(pub_key).as_slice()))
+}
+
+/// 通过签名算法和公钥hash计算P2PKH address
+pub fn convert_p2pkh_address(scheme: SignatureScheme, pub_hash_key: &[u8]) -> String {
+    encode_address(scheme.get_address_version(), pub_hash_key)
+}
+
+/// 通过脚本hash计算P2SH address
+pub fn convert_script_address(script_hash: &[u8]) -> String {
+    encode_address(SCRIPT_VERSION, script_hash)
+}
+
+/// 解析address, 返回(版本, hash), 地址非法时返回None
+pub fn decode_address(address: &str) -> Option<(u8, Vec<u8>)> {
+    let payload = bs58::decode(address).into_vec().ok()?;
+    if payload.len() <= ADDRESS_CHECKSUM_LEN || !validate_address(address) {
+        return None;
+    }
+    Some((payload[0], payload[1..payload.len()-ADDRESS_CHECKSUM_LEN].to_vec()))
+}
+
+/// address = base58(version + hash + checksum)
+fn encode_address(version: u8, hash: &[u8]) -> String {
     let mut payload: Vec<u8> = vec![];
-    payload.push(VERSION);
-    payload.extend(pub_hash_key);
+    payload.push(version);
+    payload.extend(hash);
     let checksum = checksum(payload.as_slice());
     payload.extend(checksum.as_slice());
 
-    crate::base58_encode(&payload.as_slice())
+    crate::base58_encode(payload.as_slice())
 }
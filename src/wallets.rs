@@ -9,7 +9,7 @@ use std::{
     collections::HashMap
 };
 
-use crate::wallet::Wallet;
+use crate::{wallet::Wallet, SignatureScheme};
 
 pub const WALLET_FILE: &str = "wallet.dat";
 
@@ -18,6 +18,12 @@ pub struct Wallets {
     wallets: HashMap<String, Wallet>,
 }
 
+impl Default for Wallets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Wallets {
 
     /// 新建wallets
@@ -30,14 +36,14 @@ impl Wallets {
         wallets
     }
 
-    /// 创建一个新钱包
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+    /// 创建一个使用scheme签名的新钱包
+    pub fn create_wallet(&mut self, scheme: SignatureScheme) -> String {
+        let wallet = Wallet::new_with_scheme(scheme);
         let address = wallet.get_address();
         self.wallets.insert(address.clone(), wallet);
         self.save_to_file();
 
-        return address;
+        address
     }
 
     pub fn get_addresses(&self) -> Vec<String> {
@@ -54,11 +60,12 @@ impl Wallets {
         let file = OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(&path)
             .expect("unable to open wallet.dat");
         let mut writer = BufWriter::new(file);
         let wallets_bytes = bincode::serialize(&self.wallets).expect("unable to serialize wallets");
-        writer.write(wallets_bytes.as_slice()).unwrap();
+        writer.write_all(wallets_bytes.as_slice()).unwrap();
         let _ = writer.flush();
     }
 
@@ -84,7 +91,7 @@ mod tests {
     #[test]
     fn test_new_wallets() {
         let mut wallets = Wallets::new();
-        let address = wallets.create_wallet();
+        let address = wallets.create_wallet(crate::SignatureScheme::EcdsaP256);
 
         println!("The new wallet address is {}", address);
     }
@@ -0,0 +1,161 @@
+// htlc_swap.rs
+//
+
+use std::{env, fs, path::Path, process::{self, Command}};
+
+use blockchain::{test_utils, utils, validate_transaction, Amount, Blockchain, Htlc, Script, Transaction, UTXOSet, Wallet};
+
+/// 新建一条创世区块奖励给address的链, 并挖到该奖励刚好可以花费
+fn new_chain(address: &str) -> Blockchain {
+    let blockchain = test_utils::new_blockchain(address);
+    test_utils::mine_to_maturity(&blockchain, Wallet::new().get_address().as_str());
+    blockchain
+}
+
+/// 挖一个打包txs的区块, 奖励给miner
+fn mine(blockchain: &Blockchain, mut txs: Vec<Transaction>, miner: &str) {
+    let height = blockchain.get_best_height() + 1;
+    txs.push(Transaction::new_coinbase_tx(miner, height, Amount::ZERO));
+    blockchain.mine_block(&txs);
+}
+
+/// 能否被下一个区块打包
+fn is_valid(blockchain: &Blockchain, tx: &Transaction) -> bool {
+    validate_transaction(blockchain, tx, blockchain.get_best_height() + 1, blockchain.get_tip_median_time_past()).is_ok()
+}
+
+fn balance(blockchain: &Blockchain, address: &str) -> Amount {
+    let script_pubkey = Script::from_address(address).unwrap();
+    UTXOSet::new(blockchain.clone()).get_balance(&script_pubkey).0
+}
+
+#[test]
+fn test_atomic_swap() {
+    let (alice, bob) = (Wallet::new(), Wallet::new());
+    let (alice_address, bob_address) = (alice.get_address(), bob.get_address());
+    let miner = Wallet::new().get_address();
+    let chain_a = new_chain(alice_address.as_str());
+    let chain_b = new_chain(bob_address.as_str());
+    let utxo_a = UTXOSet::new(chain_a.clone());
+    let utxo_b = UTXOSet::new(chain_b.clone());
+    let amount = Amount::from_sat(5);
+
+    // Alice选择秘密值, 在链A上锁定资金给Bob, 时间锁较长
+    let secret = utils::new_secret();
+    let hash = utils::sha256_digest(secret.as_slice());
+    let lock_a = (chain_a.get_best_height() + 20) as u64;
+    let htlc_a = Htlc::new(hash.as_slice(), bob_address.as_str(), alice_address.as_str(), lock_a);
+    let fund_a = Transaction::new_utxo_transaction_from_wallet(&alice, htlc_a.get_address().as_str(), amount, Amount::ZERO, &utxo_a);
+    mine(&chain_a, vec![fund_a], miner.as_str());
+
+    // Bob确认后使用相同的hash在链B上锁定资金给Alice, 时间锁较短
+    let lock_b = (chain_b.get_best_height() + 10) as u64;
+    let htlc_b = Htlc::new(hash.as_slice(), alice_address.as_str(), bob_address.as_str(), lock_b);
+    let fund_b = Transaction::new_utxo_transaction_from_wallet(&bob, htlc_b.get_address().as_str(), amount, Amount::ZERO, &utxo_b);
+    mine(&chain_b, vec![fund_b], miner.as_str());
+    assert_eq!(balance(&chain_b, htlc_b.get_address().as_str()), amount);
+
+    // 时间锁未到, Bob不能退款; 不知道原像的人不能取走资金
+    assert!(!is_valid(&chain_b, &htlc_b.refund(&bob, Amount::ZERO, &utxo_b)));
+    assert!(!is_valid(&chain_b, &htlc_b.claim(&alice, b"guess", Amount::ZERO, &utxo_b)));
+    assert!(!is_valid(&chain_b, &htlc_b.claim(&bob, secret.as_slice(), Amount::ZERO, &utxo_b)));
+
+    // Alice公布原像取走链B上的资金
+    let claim_b = htlc_b.claim(&alice, secret.as_slice(), Amount::ZERO, &utxo_b);
+    mine(&chain_b, vec![claim_b], miner.as_str());
+    assert_eq!(balance(&chain_b, alice_address.as_str()), amount);
+
+    // Bob从链B上得到原像, 取走链A上的资金
+    let preimage = htlc_b.find_preimage(&chain_b).unwrap();
+    assert_eq!(preimage, secret);
+    assert_eq!(htlc_a.find_preimage(&chain_a), None);
+    let claim_a = htlc_a.claim(&bob, preimage.as_slice(), Amount::ZERO, &utxo_a);
+    mine(&chain_a, vec![claim_a], miner.as_str());
+    assert_eq!(balance(&chain_a, bob_address.as_str()), amount);
+    assert_eq!(balance(&chain_a, htlc_a.get_address().as_str()), Amount::ZERO);
+}
+
+#[test]
+fn test_refund_after_lock_time() {
+    let alice = Wallet::new();
+    let alice_address = alice.get_address();
+    let bob_address = Wallet::new().get_address();
+    let miner = Wallet::new().get_address();
+    let chain = new_chain(alice_address.as_str());
+    let utxo_set = UTXOSet::new(chain.clone());
+    let before = balance(&chain, alice_address.as_str());
+
+    let lock_time = (chain.get_best_height() + 3) as u64;
+    let htlc = Htlc::new(&utils::sha256_digest(b"secret"), bob_address.as_str(), alice_address.as_str(), lock_time);
+    let fund = Transaction::new_utxo_transaction_from_wallet(&alice, htlc.get_address().as_str(), Amount::from_sat(5), Amount::from_sat(1), &utxo_set);
+    mine(&chain, vec![fund], miner.as_str());
+
+    // 下一个区块的高度到达时间锁后才能退款
+    let refund = htlc.refund(&alice, Amount::ZERO, &utxo_set);
+    while (chain.get_best_height() + 1) < lock_time as usize {
+        assert!(!is_valid(&chain, &refund));
+        mine(&chain, vec![], miner.as_str());
+    }
+    assert!(is_valid(&chain, &refund));
+    mine(&chain, vec![refund], miner.as_str());
+    assert_eq!(balance(&chain, alice_address.as_str()), before.checked_sub(Amount::from_sat(1)).unwrap());
+}
+
+/// 在dir中运行命令行, 返回标准输出
+fn cli(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_blockchain"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// 命令行输出中以label开头的一行去掉label后的值
+fn field(output: &str, label: &str) -> String {
+    output.lines()
+        .find_map(|line| line.strip_prefix(label))
+        .unwrap_or_else(|| panic!("{} not found in {}", label, output))
+        .trim()
+        .to_string()
+}
+
+#[test]
+fn test_htlc_cli() {
+    let dir = env::temp_dir().join(format!("htlc-cli-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let mut addresses = (0..3).map(|_| field(&cli(&dir, &["create-wallet"]), "Your new address:"));
+    let (alice, bob, miner) = (addresses.next().unwrap(), addresses.next().unwrap(), addresses.next().unwrap());
+    let balance = |address: &str| field(&cli(&dir, &["get-balance", address]), format!("Balance of {}:", address).as_str());
+
+    // 创世区块奖励给Bob, 挖到该奖励可以花费
+    let blockchain = Blockchain::create_blockchain_with_db(sled::open(dir.join("data")).unwrap(), bob.as_str());
+    test_utils::mine_to_maturity(&blockchain, miner.as_str());
+    blockchain.get_db().flush().unwrap();
+    drop(blockchain);
+
+    // Bob锁定资金给Alice, Alice公布秘密值取走资金
+    let secret_output = cli(&dir, &["htlc-secret"]);
+    let (secret, hash) = (field(&secret_output, "Secret:"), field(&secret_output, "Hash:"));
+    let created = cli(&dir, &["htlc-create", &hash, &alice, &bob, "1000"]);
+    let (htlc, redeem_script) = (field(&created, "HTLC address:"), field(&created, "Redeem script:"));
+    cli(&dir, &["send", &bob, &htlc, "1", "1"]);
+    assert_eq!(balance(&htlc), "1");
+    assert!(cli(&dir, &["htlc-secret-of", &redeem_script]).contains("Secret not revealed yet"));
+    cli(&dir, &["htlc-claim", &redeem_script, &alice, &secret, "--miner", &miner]);
+    assert_eq!(balance(&htlc), "0");
+    assert_eq!(balance(&alice), "1");
+    assert_eq!(field(&cli(&dir, &["htlc-secret-of", &redeem_script]), "Secret:"), secret);
+
+    // 时间锁已过, Bob取回锁定的资金
+    let created = cli(&dir, &["htlc-create", &hash, &alice, &bob, "1"]);
+    let (htlc, redeem_script) = (field(&created, "HTLC address:"), field(&created, "Redeem script:"));
+    cli(&dir, &["send", &bob, &htlc, "1", "1"]);
+    assert_eq!(balance(&htlc), "1");
+    cli(&dir, &["htlc-refund", &redeem_script, &bob, "--miner", &miner]);
+    assert_eq!(balance(&htlc), "0");
+
+    fs::remove_dir_all(&dir).unwrap();
+}